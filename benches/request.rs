@@ -0,0 +1,203 @@
+//! Benchmarks for parts of the request path beyond raw crypto: decoding and
+//! verifying JWTs end-to-end (base64, JSON, key lookup and signature check),
+//! looking up events in the `oc` claim, parsing request paths, resolving
+//! them to files and sending files.
+//!
+//! There is no verification cache yet, so `decode_and_verify` always takes the
+//! full path here. Once there is one, add cached variants next to these.
+//!
+//! This uses the same global allocator as the octoka binary, so different
+//! allocators can be compared by running with different features, e.g.:
+//!
+//!     cargo bench --bench request
+//!     cargo bench --bench request --no-default-features
+//!     cargo bench --bench request --no-default-features --features mimalloc
+
+use std::{hint::black_box, path::Path};
+
+use confique::Config as _;
+use divan::Bencher;
+use octoka::{
+    config::Config,
+    jwt::TokenInfo,
+    opencast::{self, PathParts, PathResolution},
+    util,
+};
+use tokio::runtime::Runtime;
+
+use self::common::JwksServer;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+#[cfg(all(feature = "jemalloc", not(feature = "mimalloc"), not(target_env = "msvc")))]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+
+fn main() {
+    divan::main();
+}
+
+/// Signed with `tests/jwks/ed25519.json`, payload:
+/// `{ "exp": 4012345678, "roles": ["ROLE_ADMIN"] }`
+const JWT_ADMIN: &str = "eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCJ9.\
+    eyJleHAiOjQwMTIzNDU2NzgsInJvbGVzIjpbIlJPTEVfQURNSU4iXX0.\
+    6Bs6wdvBdWbszV38Lj81OmtW5ibutzUfTc8_X6k3yiwOHNQm5xQrWiILXGRP7eHFiI4Ju1FHu_NxufSUPiELAw";
+
+/// Signed with `tests/jwks/ed25519.json`, payload:
+/// `{ "exp": 4012345678, "oc": { "e:ffff": ["read"], "e:abc123": ["read"] } }`
+const JWT_OC_CLAIM: &str = "eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCJ9.\
+    eyJleHAiOjQwMTIzNDU2NzgsIm9jIjp7ImU6ZmZmZiI6WyJyZWFkIl0sImU6YWJjMTIzIjpbInJlYWQiXX19.\
+    OKXJJJPztNijrkLQSJ67isUZo9ktGJkotMlidHe6Foo1yHtcEyA9967XljohpVZKPgtQf9Q7yJ-pbM8Eto2WAQ";
+
+const PATH: &str = "/static/mh_default_org/engage-player/\
+    eb4f3b14-3953-4c17-957d-6e4c5868206b/5bf5b0ea-0ddf-4ff4-a3e5-b4c1e7dd9e8f/video.mp4";
+const PATH_WITHOUT_ORG: &str = "/static/engage-player/\
+    eb4f3b14-3953-4c17-957d-6e4c5868206b/5bf5b0ea-0ddf-4ff4-a3e5-b4c1e7dd9e8f/video.mp4";
+
+
+/// Loads a basic config, with `extra_config` (TOML) having priority.
+fn config(extra_config: &str) -> Config {
+    let base_config = r#"
+        opencast.fallback = "none"
+        jwt.trusted_keys = ["http://127.0.0.1:1/unused.json"]
+    "#;
+    Config::builder()
+        .preloaded(toml::from_str(extra_config).unwrap())
+        .preloaded(toml::from_str(base_config).unwrap())
+        .load()
+        .unwrap()
+}
+
+
+mod decode_and_verify {
+    use super::*;
+
+    #[divan::bench(args = [JWT_ADMIN, JWT_OC_CLAIM])]
+    fn ed25519(bencher: Bencher, jwt: &str) {
+        let (rt, ctx) = setup();
+
+        // Just make sure it is a success before running benchmark
+        rt.block_on(ctx.decode_and_verify(jwt)).unwrap();
+
+        bencher.bench_local(|| rt.block_on(ctx.decode_and_verify(black_box(jwt))));
+    }
+
+    /// Creates a JWT context trusting a `JwksServer`, with all keys
+    /// already fetched.
+    fn setup() -> (Runtime, octoka::jwt::Context) {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let ctx = rt.block_on(async {
+            let jwks_host = JwksServer::start().url;
+            let config = config(&format!(r#"
+                jwt.trusted_keys = ["{jwks_host}/ed25519.json"]
+            "#));
+            octoka::jwt::Context::new(&config.jwt).await.unwrap()
+        });
+        (rt, ctx)
+    }
+}
+
+mod readable_events {
+    use super::*;
+
+    /// Looks up an event not contained in an `oc` claim with `len` entries,
+    /// i.e. the worst case.
+    #[divan::bench(args = [1, 100, 10_000])]
+    fn can_read(bencher: Bencher, len: usize) {
+        let info = TokenInfo {
+            is_admin: false,
+            readable_events: (0..len).map(|i| format!("event-{i}")).collect(),
+            roles: vec![],
+            kid: None,
+            alg: "EdDSA".into(),
+            cnf_jkt: None,
+            sub: None,
+            name: None,
+            email: None,
+        };
+        bencher.bench_local(|| info.can_read(black_box("eb4f3b14-3953-4c17-957d-6e4c5868206b")));
+    }
+}
+
+mod path_parts {
+    use super::*;
+
+    #[divan::bench]
+    fn parse(bencher: Bencher) {
+        let config = config("");
+        bencher.bench_local(|| PathParts::parse(black_box(PATH), &config.opencast));
+    }
+
+    #[divan::bench(args = [PATH, PATH_WITHOUT_ORG])]
+    fn parse_with_default_org(bencher: Bencher, path: &str) {
+        let config = config(r#"opencast.default_org = "mh_default_org""#);
+        bencher.bench_local(|| {
+            let full_path = opencast::insert_default_org(black_box(path), &config.opencast);
+            PathParts::parse(&full_path, &config.opencast).is_some()
+        });
+    }
+}
+
+mod path_resolution {
+    use super::*;
+
+    /// Resolves a file in `tests/downloads`. The difference between the modes
+    /// is much larger on network storage.
+    #[divan::bench(args = [PathResolution::Canonicalize, PathResolution::Lexical])]
+    fn resolve(bencher: Bencher, mode: PathResolution) {
+        let base = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/downloads"))
+            .canonicalize()
+            .unwrap();
+        let rel_path = util::normalize_lexically(Path::new("org/channel/abc123/hello.txt"))
+            .unwrap();
+        bencher.bench_local(|| util::resolve_path(&base, black_box(&rel_path), mode).unwrap());
+    }
+}
+
+mod serve_file {
+    use super::*;
+
+    const SIZE: usize = 64 * 1024 * 1024;
+
+    /// Downloads a large file over a local connection, i.e. the throughput of
+    /// reading the file and sending it through hyper.
+    #[divan::bench(sample_count = 20)]
+    fn download(bencher: Bencher) {
+        let downloads = std::env::temp_dir()
+            .join(format!("octoka-bench-serve-file-{}", std::process::id()));
+        let event_dir = downloads.join("org/channel/abc123");
+        std::fs::create_dir_all(&event_dir).unwrap();
+        std::fs::write(event_dir.join("video.mp4"), vec![0u8; SIZE]).unwrap();
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let url = rt.block_on(async {
+            let jwks_host = JwksServer::start().url;
+            let config = config(&format!(r#"
+                http.port = 0
+                http.on_allow = "file"
+                opencast.downloads_path = {:?}
+                jwt.trusted_keys = ["{jwks_host}/ed25519.json"]
+            "#, downloads.to_str().unwrap()));
+            let (addr, server) = octoka::test_http_server(config).await.unwrap();
+            tokio::spawn(server);
+            format!("http://{addr}/static/org/channel/abc123/video.mp4")
+        });
+        let client = reqwest::Client::new();
+        let download = || rt.block_on(async {
+            let resp = client.get(&url).bearer_auth(JWT_ADMIN).send().await.unwrap();
+            assert!(resp.status().is_success());
+            resp.bytes().await.unwrap().len()
+        });
+        assert_eq!(download(), SIZE);
+
+        bencher.counter(divan::counter::BytesCount::new(SIZE)).bench_local(download);
+        std::fs::remove_dir_all(&downloads).unwrap();
+    }
+}
@@ -1,45 +1,246 @@
-use std::time::Duration;
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
-use crate::{http::Context, opencast::PathParts, prelude::*};
+use hyper::{Request, body::Incoming};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+
+use crate::{
+    http::Context,
+    jwt::TokenInfo,
+    log,
+    opencast::PathParts,
+    prelude::*,
+    util::glob_match,
+};
 
 
 const JWT_VERIFY_TIMEOUT: Duration = Duration::from_millis(2500);
 
+
+#[derive(Debug, confique::Config)]
+pub struct AuthConfig {
+    /// Rules that grant read access based on the roles in the JWT's `roles`
+    /// claim and the requested path. Each rule is an object with a `role`
+    /// pattern and optional `org`, `channel` and `event` patterns. A rule
+    /// grants access if any role of the JWT matches `role` and all specified
+    /// path components of the request match their pattern. In all patterns,
+    /// `*` matches any sequence of characters. Example:
+    ///
+    ///    role_rules = [
+    ///        { role = "ROLE_STUDIO_*", channel = "internal" },
+    ///    ]
+    #[config(default = [])]
+    pub role_rules: Vec<RoleRule>,
+
+    /// Whether JWTs with `ROLE_ADMIN` in their `roles` claim grant access to
+    /// all files. If `false`, admin JWTs are treated like any other and need
+    /// an explicit grant (via the `oc` claim or `role_rules`).
+    #[config(default = true)]
+    pub allow_admin_override: bool,
+
+    /// IDs of events to which access is always denied, regardless of any JWT
+    /// or Opencast's opinion. Useful for incident response, e.g. if a JWT for
+    /// an event leaked. On Unix, this and `blocked_path_patterns` are
+    /// reloaded from the config file when octoka receives `SIGHUP` (all other
+    /// config changes require a restart).
+    #[config(default = [])]
+    pub blocked_events: Vec<String>,
+
+    /// Like `blocked_events`, but for patterns matched against the
+    /// (percent-decoded) request path, e.g. "/static/*/engage-download/*".
+    /// `*` matches any sequence of characters.
+    #[config(default = [])]
+    pub blocked_path_patterns: Vec<String>,
+}
+
+/// Events and paths to which access is always denied. See
+/// `auth.blocked_events` and `auth.blocked_path_patterns`.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    events: Vec<String>,
+    path_patterns: Vec<String>,
+}
+
+impl Blocklist {
+    pub fn new(config: &AuthConfig) -> Self {
+        Self {
+            events: config.blocked_events.clone(),
+            path_patterns: config.blocked_path_patterns.clone(),
+        }
+    }
+
+    pub fn is_blocked(&self, path: PathParts<'_>) -> bool {
+        if self.events.is_empty() && self.path_patterns.is_empty() {
+            return false;
+        }
+
+        // Decoded, as that's what the file system sees, too.
+        let event = percent_decode_str(path.event_id()).decode_utf8_lossy();
+        let full_path = percent_decode_str(path.full_path()).decode_utf8_lossy();
+        self.events.iter().any(|e| *e == event)
+            || self.path_patterns.iter().any(|pattern| glob_match(pattern, &full_path))
+    }
+}
+
+/// Reloads the blocklist from the config files at `config_paths` whenever
+/// `SIGHUP` is received. Nothing else is replaced: notably, the JWT context
+/// with its fetched keys is kept, so a reload never refetches JWKS.
+#[cfg(target_family = "unix")]
+pub fn reload_blocklist_on_sighup(
+    blocklist: Arc<RwLock<Blocklist>>,
+    config_paths: Vec<PathBuf>,
+) -> Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut signal = signal(SignalKind::hangup())
+        .context("failed to install SIGHUP signal handler")?;
+    tokio::spawn(async move {
+        while signal.recv().await.is_some() {
+            match crate::config::load_from(&config_paths) {
+                Ok(config) => {
+                    let new = Blocklist::new(&config.auth);
+                    info!(
+                        events = new.events.len(),
+                        path_patterns = new.path_patterns.len(),
+                        "SIGHUP received -> reloaded blocklist",
+                    );
+                    *blocklist.write().unwrap() = new;
+                }
+                Err(e) => error!("SIGHUP received, but failed to reload config \
+                    (keeping old blocklist): {e:#}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(target_family = "unix"))]
+pub fn reload_blocklist_on_sighup(_: Arc<RwLock<Blocklist>>, _: Vec<PathBuf>) -> Result<()> {
+    Ok(())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoleRule {
+    pub role: String,
+    pub org: Option<String>,
+    pub channel: Option<String>,
+    pub event: Option<String>,
+}
+
+impl RoleRule {
+    /// Returns whether this rule grants access to `path` for a JWT with the
+    /// given roles.
+    fn matches(&self, roles: &[String], path: PathParts<'_>) -> bool {
+        let component_matches = |pattern: &Option<String>, value: &str| {
+            pattern.as_ref().is_none_or(|p| glob_match(p, value))
+        };
+
+        component_matches(&self.org, path.org())
+            && component_matches(&self.channel, path.channel())
+            && component_matches(&self.event, path.event_id())
+            && roles.iter().any(|role| glob_match(&self.role, role))
+    }
+}
+
+/// Why access to a file was granted. Recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrantBasis {
+    /// The JWT has `ROLE_ADMIN`.
+    Admin,
+
+    /// The JWT grants read access to the event via the `oc` claim.
+    Event,
+
+    /// The JWT's roles match one of `auth.role_rules`.
+    RoleRule,
+
+    /// Opencast allowed access (see `opencast.fallback`).
+    Fallback,
+}
+
+impl GrantBasis {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Admin => "admin",
+            Self::Event => "event",
+            Self::RoleRule => "role_rule",
+            Self::Fallback => "fallback",
+        }
+    }
+}
+
+/// Access granted by a JWT.
+#[derive(Debug)]
+pub struct Grant {
+    pub basis: GrantBasis,
+    pub token: TokenInfo,
+}
+
+/// Checks whether `jwt` grants access to `path`. Returns `None` if not.
 pub async fn is_allowed(
     path: PathParts<'_>,
     jwt: Option<&str>,
+    req: &Request<Incoming>,
     ctx: &Context,
-) -> bool {
+) -> Option<Grant> {
+    let event = path.event_id();
     let Some(jwt) = jwt else {
-        trace!("no JWT found in request");
-        return false;
+        trace!(event, reason = "no JWT", "no JWT found in request");
+        return None;
     };
+    let token = log::Token(jwt);
 
     let res = tokio::select! {
         res = ctx.jwt.decode_and_verify(jwt) => res,
         _ = tokio::time::sleep(JWT_VERIFY_TIMEOUT) => {
-            warn!(?JWT_VERIFY_TIMEOUT, "could not verify JWT in time");
-            return false;
+            warn!(?JWT_VERIFY_TIMEOUT, event, jwt = %token, "could not verify JWT in time");
+            return None;
         }
     };
 
     let info = match res {
         Ok(info) => info,
         Err(e) => {
-            debug!("rejected JWT ({e:?})");
-            return false;
+            debug!(event, jwt = %token, reason = "invalid JWT", error = ?e, "rejected JWT");
+            return None;
         }
     };
 
-    if info.is_admin {
+    let dpop_proof = req.headers().get("dpop").and_then(|v| v.to_str().ok());
+    let (method, req_path) = (req.method(), req.uri().path());
+    let pop = ctx.jwt.verify_proof_of_possession(&info, jwt, dpop_proof, method, req_path);
+    if let Err(e) = pop.await {
+        debug!(event, jwt = %token, reason = "no proof of possession", error = %e, "rejected JWT");
+        return None;
+    }
+
+    if info.is_admin && ctx.config.auth.allow_admin_override {
         trace!("JWT grants ROLE_ADMIN -> allowing access");
-        return true;
+        return Some(Grant { basis: GrantBasis::Admin, token: info });
     }
-    if info.readable_events.iter().any(|e| e == path.event_id()) {
+    if info.can_read(path.event_id()) {
         trace!(event = path.event_id(), "JWT grants read access to event -> allowing access");
-        return true;
+        return Some(Grant { basis: GrantBasis::Event, token: info });
+    }
+
+    let rule = ctx.config.auth.role_rules.iter().find(|rule| rule.matches(&info.roles, path));
+    if let Some(rule) = rule {
+        trace!(event = path.event_id(), ?rule, "JWT roles match role rule -> allowing access");
+        return Some(Grant { basis: GrantBasis::RoleRule, token: info });
     }
 
-    debug!("JWT valid but does not grant access to event");
-    false
+    debug!(
+        event,
+        kid = info.kid.as_deref(),
+        alg = info.alg,
+        jwt = %token,
+        reason = "no grant for event",
+        "JWT valid but does not grant access to event",
+    );
+    None
 }
@@ -9,8 +9,36 @@ pub struct Cli {
 
     /// Specifies config file location. Default locations are: 'config.toml' and
     /// '/etc/octoka/config.toml'. Can also be set via env `OCTOKA_CONFIG_PATH`.
+    /// Can be given multiple times (e.g. a base config and an overlay for an
+    /// environment): files are merged in order, with values from later files
+    /// overriding earlier ones. Relative paths in the config are relative to
+    /// the first file.
     #[clap(long)]
-    pub config: Option<PathBuf>,
+    pub config: Vec<PathBuf>,
+
+    /// Overrides the log level for octoka (`log.filters.octoka`). Filters for
+    /// more specific targets from the config still apply.
+    #[clap(
+        long,
+        global = true,
+        value_parser = ["off", "error", "warn", "info", "debug", "trace"],
+    )]
+    pub log_level: Option<String>,
+
+    /// Shorthand for `--log-level`: `-v` is "debug", `-vv` is "trace".
+    #[clap(short, global = true, action = clap::ArgAction::Count, conflicts_with = "log_level")]
+    pub verbose: u8,
+}
+
+impl Cli {
+    /// Returns the log level set via `--log-level` or `-v`, if any.
+    pub fn log_level(&self) -> Option<&str> {
+        match self.verbose {
+            0 => self.log_level.as_deref(),
+            1 => Some("debug"),
+            _ => Some("trace"),
+        }
+    }
 }
 
 #[derive(Debug, clap::Parser)]
@@ -18,9 +46,21 @@ pub enum Command {
     /// Starts the HTTP server.
     Run,
 
-    /// Checks config, paths, URLs and other stuff. Useful to run before
-    /// restarting the main server after a config update.
-    Check,
+    /// Checks config, paths, URLs and other stuff and prints the effective
+    /// CORS policy. Useful to run before restarting the main server after a
+    /// config update.
+    Check {
+        /// A JWT to verify with the configured keys after fetching them. The
+        /// result and the access it grants are reported, which checks keys
+        /// and token format end-to-end.
+        #[clap(long, value_name = "JWT")]
+        validate_token: Option<String>,
+    },
+
+    /// Prints the effective configuration, i.e. after merging the config file,
+    /// `OCTOKA_*` env vars and default values. Useful to debug where a value
+    /// comes from. Values read from files via `OCTOKA_*_FILE` are redacted.
+    PrintEffectiveConfig,
 
     /// Outputs a template of the configuration, including all config options
     /// with descriptions, great as a starting point.
@@ -0,0 +1,252 @@
+//! Overriding config values via `OCTOKA_*` env vars, see `Config`.
+
+use confique::{
+    Config as _,
+    meta::{FieldKind, Meta},
+    serde::{self, de::{IntoDeserializer, Visitor, value::MapDeserializer}},
+};
+
+use super::Config;
+use crate::prelude::*;
+
+
+/// Prefix for env vars overriding config values.
+const PREFIX: &str = "OCTOKA_";
+
+/// Suffix for env vars whose value is a path to read the config value from.
+const FILE_SUFFIX: &str = "_FILE";
+
+
+/// Returns all env vars. Non-UTF-8 env vars cannot be meant for us, so they
+/// are just skipped.
+pub(super) fn vars() -> impl Iterator<Item = (String, String)> {
+    std::env::vars_os()
+        .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+}
+
+/// Returns the path segments of the config value the env var `key` refers
+/// to, and whether the env var's value is a file to read the value from.
+/// Returns `None` for env vars that do not override config values.
+///
+/// A key ending in `_FILE` only refers to a file if the key without that
+/// suffix is a config value and the key itself is not (like `OCTOKA_LOG__FILE`
+/// for `log.file`).
+fn target(key: &str) -> Option<(Vec<String>, bool)> {
+    let path = key.strip_prefix(PREFIX).filter(|p| p.contains("__"))?;
+    let segments = |path: &str| path.split("__").map(str::to_lowercase).collect::<Vec<_>>();
+
+    let direct = segments(path);
+    if !is_leaf(&Config::META, &direct) {
+        let without_suffix = path.strip_suffix(FILE_SUFFIX)
+            .map(segments)
+            .filter(|segments| is_leaf(&Config::META, segments));
+        if let Some(segments) = without_suffix {
+            return Some((segments, true));
+        }
+    }
+    Some((direct, false))
+}
+
+/// Returns whether `path` refers to a (non-nested) config value in `meta`.
+fn is_leaf(meta: &Meta, path: &[String]) -> bool {
+    let Some((first, rest)) = path.split_first() else {
+        return false;
+    };
+    meta.fields.iter()
+        .filter(|field| field.name == first)
+        .any(|field| match field.kind {
+            FieldKind::Leaf { .. } => rest.is_empty(),
+            FieldKind::Nested { meta } => is_leaf(meta, rest),
+        })
+}
+
+/// Reads a config value from the file at `path`, without trailing newlines.
+fn read_file(path: &str, key: &str) -> Result<String> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read file '{path}' given by ${key}"))?;
+    Ok(content.trim_end_matches(['\n', '\r']).to_owned())
+}
+
+/// Collects config overrides from env vars into a nested table with string
+/// values. `OCTOKA_HTTP__PORT=4051` sets `http.port`, i.e. `__` separates path
+/// segments. With the suffix `_FILE` (e.g. `OCTOKA_OPENCAST__HOST_FILE`), the
+/// value is read from the file at the given path instead, with trailing
+/// newlines removed. That is useful for secrets mounted into containers.
+pub(super) fn overrides(vars: impl Iterator<Item = (String, String)>) -> Result<toml::Table> {
+    let mut out = toml::Table::new();
+    for (key, value) in vars {
+        let Some((segments, from_file)) = target(&key) else {
+            continue;
+        };
+        let value = if from_file { read_file(&value, &key)? } else { value };
+
+        let (last, parents) = segments.split_last().unwrap();
+        let mut table = &mut out;
+        for segment in parents {
+            table = match table.entry(segment)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            {
+                toml::Value::Table(t) => t,
+                _ => bail!("conflicting env vars for config value '{segment}'"),
+            };
+        }
+        if table.insert(last.clone(), toml::Value::String(value)).is_some() {
+            bail!("config value set by multiple env vars (${key})");
+        }
+    }
+
+    Ok(out)
+}
+
+/// Returns the paths of the config values read from files via `*_FILE` env
+/// vars. These are usually secrets and must not be printed.
+pub(super) fn paths_from_files(
+    vars: impl Iterator<Item = (String, String)>,
+) -> Vec<Vec<String>> {
+    vars.filter_map(|(key, _)| target(&key))
+        .filter(|(_, from_file)| *from_file)
+        .map(|(segments, _)| segments)
+        .collect()
+}
+
+/// Deserializes the result of `overrides` into a config layer, see `Value`.
+pub(super) fn layer(
+    overrides: toml::Table,
+) -> Result<<Config as confique::Config>::Layer, toml::de::Error> {
+    serde::Deserialize::deserialize(Value(toml::Value::Table(overrides)))
+}
+
+
+/// Deserializer for env values, which are interpreted according to the type
+/// of the config value: strings are used as is (or unquoted if they are
+/// quoted TOML strings), all other types are parsed as TOML. That way,
+/// `OCTOKA_HTTP__PORT=4051` sets a number, while `1234` for a string value
+/// like `opencast.default_org` stays a string.
+struct Value(toml::Value);
+
+impl Value {
+    /// Parses `s` as TOML value, falling back to a string.
+    fn parse(s: String) -> toml::Value {
+        toml::from_str::<toml::Table>(&format!("v = {s}"))
+            .ok()
+            .and_then(|mut t| t.remove("v"))
+            .unwrap_or(toml::Value::String(s))
+    }
+
+    /// Returns the value for config values that are not strings.
+    fn parsed(self) -> toml::Value {
+        match self.0 {
+            toml::Value::String(s) => Self::parse(s),
+            other => other,
+        }
+    }
+
+    /// Returns the value for string config values.
+    fn string(self) -> toml::Value {
+        match self.0 {
+            toml::Value::String(s) => match Self::parse(s.clone()) {
+                unquoted @ toml::Value::String(_) => unquoted,
+                _ => toml::Value::String(s),
+            },
+            other => other,
+        }
+    }
+}
+
+impl<'de> IntoDeserializer<'de, toml::de::Error> for Value {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! deserialize_string {
+    ($($method:ident),*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                self.string().$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> serde::Deserializer<'de> for Value {
+    type Error = toml::de::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            toml::Value::Table(table) => {
+                let mut map = MapDeserializer::new(table.into_iter().map(|(k, v)| (k, Value(v))));
+                let out = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(out)
+            }
+            other => Value(other).parsed().deserialize_any(visitor),
+        }
+    }
+
+    deserialize_string!(deserialize_char, deserialize_str, deserialize_string);
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.parsed().deserialize_enum(name, variants, visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn overrides() {
+        let secret_path = std::env::temp_dir()
+            .join(format!("octoka-test-secret-{}", std::process::id()));
+        std::fs::write(&secret_path, "https://oc.example.com\n").unwrap();
+
+        let vars = [
+            ("OCTOKA_CONFIG_PATH", "config.toml"),
+            ("OCTOKA_HTTP__PORT", "4051"),
+            ("OCTOKA_HTTP__CORS_ALLOWED_ORIGINS", r#"["https://a.example"]"#),
+            ("OCTOKA_JWT__KEY_CACHE_DURATION", "1h30min"),
+            ("OCTOKA_OPENCAST__HOST_FILE", secret_path.to_str().unwrap()),
+            ("OCTOKA_LOG__FILE", "/var/log/octoka.log"),
+            ("PATH", "/usr/bin"),
+        ].map(|(k, v)| (k.to_owned(), v.to_owned()));
+        let table = super::overrides(vars.into_iter()).unwrap();
+        std::fs::remove_file(&secret_path).unwrap();
+
+        let expected: toml::Table = toml::from_str(r#"
+            http.port = "4051"
+            http.cors_allowed_origins = '["https://a.example"]'
+            jwt.key_cache_duration = "1h30min"
+            opencast.host = "https://oc.example.com"
+            log.file = "/var/log/octoka.log"
+        "#).unwrap();
+        assert_eq!(table, expected);
+
+        let missing_file = [("OCTOKA_OPENCAST__HOST_FILE", "/nonexistent/octoka-secret")]
+            .map(|(k, v)| (k.to_owned(), v.to_owned()));
+        assert!(super::overrides(missing_file.into_iter()).is_err());
+    }
+}
@@ -0,0 +1,549 @@
+use std::{fmt, path::{Path, PathBuf}, time::Duration};
+
+use anyhow::{ensure, Context as _, Error};
+use confique::{
+    Config as _,
+    serde::{self, Deserialize},
+};
+use http::{HeaderName, HeaderValue, Uri, uri::Scheme};
+
+use crate::{
+    auth::AuthConfig,
+    http::{HttpConfig, OnAllow},
+    jwt::JwtConfig,
+    log::LogConfig,
+    opencast::OpencastConfig,
+    prelude::*,
+};
+
+mod env;
+
+
+/// Paths that are checked for a config file.
+const DEFAULT_PATHS: &[&str] = &[
+    // For better DX, we include this special path here, but just in debug mode.
+    #[cfg(debug_assertions)]
+    "util/config.toml",
+
+    "config.toml",
+    "/etc/octoka/config.toml",
+];
+
+/// Env var that can be used to set the config path.
+const CONFIG_PATH_ENV: &str = "OCTOKA_CONFIG_PATH";
+
+
+
+/// Returns the path of the config file to use when none is given explicitly:
+/// the one from `OCTOKA_CONFIG_PATH` or the first existing default path.
+pub fn default_path() -> Result<PathBuf, Error> {
+    if let Some(path) = std::env::var_os(CONFIG_PATH_ENV) {
+        return Ok(PathBuf::from(path));
+    }
+
+    DEFAULT_PATHS.iter()
+        .map(PathBuf::from)
+        .find(|p| p.exists())
+        .ok_or(anyhow!(
+            "no configuration file found at any of the following locations: {}",
+            DEFAULT_PATHS.join(", "),
+        ))
+}
+
+pub fn load() -> Result<Config, Error> {
+    load_from(&[default_path()?])
+}
+
+/// Loads the config from the given files, which are merged in order, i.e.
+/// values from later files override those from earlier ones. Env vars
+/// override all files. Relative paths in the config are relative to the
+/// first file.
+pub fn load_from(paths: &[impl AsRef<Path>]) -> Result<Config, Error> {
+    load_with_env(paths, env::vars())
+}
+
+/// Like `load_from`, but with the given env vars.
+fn load_with_env(
+    paths: &[impl AsRef<Path>],
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<Config, Error> {
+    let Some(first) = paths.first() else {
+        bail!("no config file given");
+    };
+    let env_layer = env::layer(env::overrides(vars)?)
+        .context("invalid config value in `OCTOKA_*` env var")?;
+
+    // `confique` gives sources added earlier priority.
+    let mut builder = Config::builder().preloaded(env_layer);
+    for path in paths.iter().rev() {
+        builder = builder.file(path.as_ref());
+    }
+    let mut config = builder.load().with_context(|| {
+        let paths = paths.iter()
+            .map(|path| format!("'{}'", path.as_ref().display()))
+            .collect::<Vec<_>>();
+        format!("failed to load config file(s) {}", paths.join(", "))
+    })?;
+    config.fix_paths(first.as_ref())?;
+    config.opencast.apply_properties_file()?;
+    Ok(config)
+}
+
+/// Formats `config` like `{:#?}`, but with all values read from files via
+/// `*_FILE` env vars (see `Config`) replaced by `<redacted>`, as those are
+/// usually secrets.
+pub fn redacted_debug(config: &Config) -> String {
+    redact_debug(&format!("{config:#?}"), &env::paths_from_files(env::vars()))
+}
+
+/// Replaces the values of the fields with the given paths in the output of
+/// `{:#?}`. The paths are derived from the indentation, as each nested struct
+/// is indented by four more spaces.
+fn redact_debug(debug: &str, paths: &[Vec<String>]) -> String {
+    let mut out = String::new();
+    let mut path = Vec::new();
+    let mut lines = debug.lines();
+    while let Some(line) = lines.next() {
+        let content = line.trim_start();
+        let indent = &line[..line.len() - content.len()];
+        let field = content.split_once(": ")
+            .map(|(name, _)| name)
+            .filter(|name| name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+
+        // Lines that are not fields (e.g. list items) still take a path
+        // segment, so that nothing nested in them is matched.
+        path.truncate((indent.len() / 4).saturating_sub(1));
+        path.push(field.unwrap_or_default().to_owned());
+        if let Some(field) = field && paths.contains(&path) {
+            out.push_str(&format!("{indent}{field}: <redacted>,\n"));
+
+            // Multi-line values end with the first line with the same indentation.
+            if !content.ends_with(',') {
+                lines.by_ref()
+                    .find(|l| l.strip_prefix(indent).is_some_and(|rest| !rest.starts_with(' ')));
+            }
+            continue;
+        }
+
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+pub fn template() -> String {
+    let mut options = confique::toml::FormatOptions::default();
+    options.general.nested_field_gap = 2;
+    confique::toml::template::<Config>(options)
+}
+
+/// Configuration for octoka.
+///
+/// All relative paths are relative to the location of this configuration file.
+/// Values can be overridden by env vars like `OCTOKA_HTTP__PORT`, where `__`
+/// separates path segments. String values are used as is, all others are
+/// parsed as TOML, e.g. `OCTOKA_HTTP__CORS_ALLOWED_ORIGINS='["https://a.b"]'`.
+/// With the suffix `_FILE` (e.g. `OCTOKA_OPENCAST__HOST_FILE`), the value is
+/// read from the given file, with trailing newlines removed.
+/// Duration values are specified as string with a unit, e.g. "27s". Valid
+/// units: 'ms', 's', 'min', 'h' and 'd'. Values can be fractional ("1.5h") and
+/// units can be combined in descending order ("1h30min"). ISO 8601 durations
+/// ("PT1H30M") are also accepted.
+#[derive(Debug, confique::Config)]
+#[config(validate = Self::validate)]
+pub struct Config {
+    #[config(nested)]
+    pub opencast: OpencastConfig,
+
+    #[config(nested)]
+    pub jwt: JwtConfig,
+
+    #[config(nested)]
+    pub auth: AuthConfig,
+
+    #[config(nested)]
+    pub http: HttpConfig,
+
+    #[config(nested)]
+    pub log: LogConfig,
+}
+
+impl Config {
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.http.on_allow == OnAllow::File && self.opencast.downloads_path.is_none() {
+            return Err("`http.on_allow` is 'file', but `opencast.downloads_path` is not set");
+        }
+        if self.opencast.read_through_cache.is_some() && self.http.on_allow != OnAllow::File {
+            return Err("`opencast.read_through_cache` requires `http.on_allow` to be 'file'");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn fix_paths(&mut self, config_path: &Path) -> Result<()> {
+        let absolute_config_path = config_path.canonicalize()
+            .context("failed to canonicalize config path")?;
+        let base_path = absolute_config_path.parent()
+            .expect("config file path has no parent");
+
+        if let Some(path) = &mut self.opencast.downloads_path {
+            if path.is_relative() {
+                *path = base_path.join(&path);
+            }
+            *path = path.canonicalize()
+                .context("could not canonicalize `opencast.downloads_path`")?;
+            ensure!(path.is_dir(), "`opencast.downloads_psth` is not a directory");
+        }
+
+        for (channel, path) in &mut self.opencast.channel_downloads_paths {
+            if path.is_relative() {
+                *path = base_path.join(&path);
+            }
+            *path = path.canonicalize().with_context(|| format!(
+                "could not canonicalize `opencast.channel_downloads_paths.{channel}`",
+            ))?;
+            ensure!(path.is_dir(), "`opencast.channel_downloads_paths.{channel}` is not a directory");
+        }
+
+        if let Some(cache) = &mut self.opencast.read_through_cache
+            && cache.path.is_relative()
+        {
+            cache.path = base_path.join(&cache.path);
+        }
+
+        if let Some(path) = &mut self.opencast.properties_file
+            && path.is_relative()
+        {
+            *path = base_path.join(&path);
+        }
+
+        let static_files = self.http.unmatched_files.values_mut()
+            .chain(self.http.fallback_files.iter_mut().map(|rule| &mut rule.file));
+        for path in static_files {
+            if path.is_relative() {
+                *path = base_path.join(&path);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+
+/// Makes sure that the given string is a valid URL path.
+pub fn validate_url_path(value: &str) -> Result<(), &'static str> {
+    match hyper::http::uri::PathAndQuery::try_from(value) {
+        Ok(pq) if pq.query().is_none() => Ok(()),
+        _ => Err("not a valid URI path"),
+    }
+}
+
+pub fn is_unique<T: std::hash::Hash + Eq>(list: &[T]) -> bool {
+    <std::collections::HashSet<_>>::from_iter(list).len() == list.len()
+}
+
+pub fn validate_unique<T: std::hash::Hash + Eq>(list: &[T]) -> Result<(), &'static str> {
+    if !is_unique(list) {
+        return Err("duplicate entries");
+    }
+    Ok(())
+}
+
+pub fn validate_not_empty<T>(list: &[T]) -> Result<(), &'static str> {
+    if list.is_empty() {
+        return Err("must not be empty");
+    }
+    Ok(())
+}
+
+
+/// Custom format for durations. We allow a couple useful units and required
+/// a unit to increase readability of config files. Values can be fractional
+/// (`1.5h`) and multiple units can be combined in descending order
+/// (`1h30min`). ISO 8601 durations like `PT1H30M` are accepted as well.
+pub fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let s = String::deserialize(deserializer)?;
+    parse_duration(&s).map_err(|e| D::Error::custom(format!("invalid duration '{s}': {e}")))
+}
+
+/// Like `deserialize_duration`, but for optional fields with `#[serde(default)]`.
+pub fn deserialize_opt_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_duration(deserializer).map(Some)
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    const SEC: u64 = 1_000_000_000;
+
+    // Allow unit-less zeroes
+    if s == "0" {
+        return Ok(Duration::ZERO);
+    }
+
+    // ISO 8601 durations with days, hours, minutes and seconds. Years, months
+    // and weeks are not supported, as their length is ambiguous.
+    if let Some(iso) = s.strip_prefix('P') {
+        let (date, time) = iso.split_once('T').unwrap_or((iso, ""));
+        if (date.is_empty() && time.is_empty()) || iso.ends_with('T') {
+            return Err("empty ISO 8601 duration part".into());
+        }
+        let date = parse_units(date, &[("D", 24 * 60 * 60 * SEC)])?;
+        let time = parse_units(time, &[("H", 60 * 60 * SEC), ("M", 60 * SEC), ("S", SEC)])?;
+        return date.checked_add(time).ok_or_else(|| "duration too large".into());
+    }
+
+    if s.is_empty() {
+        return Err("empty string".into());
+    }
+    parse_units(s, &[
+        ("d", 24 * 60 * 60 * SEC),
+        ("h", 60 * 60 * SEC),
+        ("min", 60 * SEC),
+        ("s", SEC),
+        ("ms", SEC / 1000),
+    ])
+}
+
+/// Parses a sequence of `<number><unit>` pairs, e.g. `1h30.5min`. `units` maps
+/// unit names to nanoseconds and has to be in descending order, which is also
+/// the order required in `s`.
+fn parse_units(s: &str, units: &[(&str, u64)]) -> Result<Duration, String> {
+    let mut rest = s;
+    let mut nanos: u64 = 0;
+    let mut next_unit_idx = 0;
+    while !rest.is_empty() {
+        let num_end = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+        let (num, after_num) = rest.split_at(num_end);
+        let unit_end = after_num.find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(after_num.len());
+        let (unit, after_unit) = after_num.split_at(unit_end);
+
+        if unit.is_empty() {
+            return Err("no time unit for duration".into());
+        }
+        let idx = units.iter()
+            .position(|(name, _)| *name == unit)
+            .ok_or("invalid unit of time for duration")?;
+        if idx < next_unit_idx {
+            return Err("units must be unique and in descending order".into());
+        }
+        let unit_nanos = units[idx].1;
+
+        // The integer part is handled exactly, the fractional part via float.
+        let (int, frac) = num.split_once('.').unwrap_or((num, ""));
+        if int.is_empty() || (num.contains('.') && frac.is_empty()) {
+            return Err(format!("invalid number for duration: '{num}'"));
+        }
+        let int: u64 = int.parse()
+            .map_err(|e| format!("invalid number for duration: {e}"))?;
+        let frac_nanos = match frac {
+            "" => 0,
+            _ => {
+                let frac: f64 = format!("0.{frac}").parse()
+                    .map_err(|e| format!("invalid number for duration: {e}"))?;
+                (frac * unit_nanos as f64).round() as u64
+            }
+        };
+
+        nanos = int.checked_mul(unit_nanos)
+            .and_then(|v| v.checked_add(frac_nanos))
+            .and_then(|v| v.checked_add(nanos))
+            .ok_or("duration too large")?;
+        next_unit_idx = idx + 1;
+        rest = after_unit;
+    }
+
+    Ok(Duration::from_nanos(nanos))
+}
+
+
+/// Deserializes a string as HTTP header value.
+pub fn deserialize_header_value<'de, D>(deserializer: D) -> Result<HeaderValue, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let s = String::deserialize(deserializer)?;
+    HeaderValue::try_from(s).map_err(|_| D::Error::custom("invalid HTTP header value"))
+}
+
+pub fn deserialize_header_name<'de, D>(deserializer: D) -> Result<HeaderName, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let s = String::deserialize(deserializer)?;
+    HeaderName::try_from(s).map_err(|_| D::Error::custom("invalid HTTP header name"))
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct HttpHost {
+    pub scheme: hyper::http::uri::Scheme,
+    pub authority: hyper::http::uri::Authority,
+}
+
+impl HttpHost {
+    /// Returns a full URI by combining `self` with the given path+query. Panics
+    /// if `pq` is malformed!
+    pub fn with_path_and_query<T>(self, pq: T) -> Uri
+    where
+        T: TryInto<http::uri::PathAndQuery>,
+        <T as TryInto<http::uri::PathAndQuery>>::Error: Into<http::Error>,
+    {
+        self.builder()
+            .path_and_query(pq)
+            .build()
+            .expect("invalid URI path+query")
+    }
+
+    pub fn builder(self) -> http::uri::Builder {
+        Uri::builder()
+            .scheme(self.scheme)
+            .authority(self.authority)
+    }
+}
+
+impl fmt::Display for HttpHost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}://{}", self.scheme, self.authority)
+    }
+}
+
+impl fmt::Debug for HttpHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl TryFrom<String> for HttpHost {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let uri: Uri = value.parse()?;
+
+        let has_real_path = uri.path_and_query()
+            .map_or(false, |pq| !pq.as_str().is_empty() && pq.as_str() != "/");
+        anyhow::ensure!(!has_real_path, "must not contain path/query part");
+        anyhow::ensure!(!value.contains('#'), "must not contain fragment part");
+
+        let parts = uri.into_parts();
+        let Some(scheme) = parts.scheme else {
+            bail!("must contain scheme part");
+        };
+        anyhow::ensure!(scheme == Scheme::HTTP || scheme == Scheme::HTTPS,
+            "must be http(s) scheme");
+        let Some(authority) = parts.authority else {
+            bail!("must contain authority part");
+        };
+        anyhow::ensure!(!authority.as_str().contains('@'), "must not contain user part");
+
+        Ok(Self { scheme, authority })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::parse_duration;
+
+    #[test]
+    fn durations() {
+        let ok = |s: &str| parse_duration(s).unwrap();
+        assert_eq!(ok("0"), Duration::ZERO);
+        assert_eq!(ok("27s"), Duration::from_secs(27));
+        assert_eq!(ok("2500ms"), Duration::from_millis(2500));
+        assert_eq!(ok("10min"), Duration::from_secs(600));
+        assert_eq!(ok("1.5s"), Duration::from_millis(1500));
+        assert_eq!(ok("1.5h"), Duration::from_secs(90 * 60));
+        assert_eq!(ok("1h30min"), Duration::from_secs(90 * 60));
+        assert_eq!(ok("1d2h3min4s5ms"), Duration::from_millis(93_784_005));
+        assert_eq!(ok("PT1H30M"), Duration::from_secs(90 * 60));
+        assert_eq!(ok("P1DT0.5S"), Duration::from_millis(86_400_500));
+        assert_eq!(ok("P2D"), Duration::from_secs(2 * 86_400));
+    }
+
+    #[test]
+    #[allow(dead_code, reason = "fields are only read via `Debug`")]
+    fn redact_debug() {
+        #[derive(Debug)]
+        struct Root { inner: Inner, org: &'static str }
+        #[derive(Debug)]
+        struct Inner { org: Option<&'static str>, items: Vec<Item>, timeout: &'static str }
+        #[derive(Debug)]
+        struct Item { org: &'static str }
+
+        let root = Root {
+            inner: Inner { org: Some("org"), items: vec![Item { org: "org" }], timeout: "3s" },
+            org: "org",
+        };
+        let paths = [vec!["inner".into(), "org".into()], vec!["inner".into(), "timeout".into()]];
+        let out = super::redact_debug(&format!("{root:#?}"), &paths);
+        let expected = [
+            "Root {",
+            "    inner: Inner {",
+            "        org: <redacted>,",
+            "        items: [",
+            "            Item {",
+            "                org: \"org\",",
+            "            },",
+            "        ],",
+            "        timeout: <redacted>,",
+            "    },",
+            "    org: \"org\",",
+            "}",
+        ];
+        assert_eq!(out.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn env_beats_files() {
+        let dir = std::env::temp_dir();
+        let config_path = dir.join(format!("octoka-test-env-config-{}.toml", std::process::id()));
+        let secret_path = dir.join(format!("octoka-test-env-secret-{}", std::process::id()));
+        std::fs::write(&config_path, r#"
+            opencast.host = "http://localhost:8080"
+            opencast.default_org = "from-file"
+            http.on_allow = "empty"
+            http.port = 4051
+            jwt.trusted_keys = ["http://localhost/jwks.json"]
+        "#).unwrap();
+        std::fs::write(&secret_path, "https://oc.example.com\n").unwrap();
+
+        let vars = [
+            ("OCTOKA_HTTP__PORT", "4052"),
+            ("OCTOKA_HTTP__ADDRESS", r#""::1""#),
+            ("OCTOKA_OPENCAST__DEFAULT_ORG", "1234"),
+            ("OCTOKA_OPENCAST__HOST_FILE", secret_path.to_str().unwrap()),
+        ].map(|(k, v)| (k.to_owned(), v.to_owned()));
+        let res = super::load_with_env(&[&config_path], vars.into_iter());
+        std::fs::remove_file(&config_path).unwrap();
+        std::fs::remove_file(&secret_path).unwrap();
+
+        let config = res.unwrap();
+        assert_eq!(config.http.port, 4052);
+        assert_eq!(config.http.address.to_string(), "::1");
+        assert_eq!(config.opencast.default_org.as_deref(), Some("1234"));
+        assert_eq!(config.opencast.host.to_string(), "https://oc.example.com");
+    }
+
+    #[test]
+    fn invalid_durations() {
+        for s in [
+            "", "5", "s", "1.s", ".5s", "1..5s", "-1s", "1x", "1 s", "30min1h", "1s1s",
+            "1h30", "P", "PT", "P1H", "PT1D", "P1Y", "99999999999999d",
+        ] {
+            assert!(parse_duration(s).is_err(), "'{s}' should be invalid");
+        }
+    }
+}
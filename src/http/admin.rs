@@ -0,0 +1,99 @@
+//! Administrative endpoints under `/-/`, enabled by `http.admin_endpoints`.
+
+use hyper::{Request, StatusCode, body::Incoming, header};
+
+use crate::{jwt::LATENCY_BUCKETS_US, log, prelude::*};
+use super::{Body, ClientAddr, Context, Response, error_response, find_jwt};
+
+
+/// Path prefix of all admin endpoints.
+pub(super) const PREFIX: &str = "/-/";
+
+/// Handles a request whose path starts with `PREFIX`.
+pub(super) async fn handle(req: &Request<Incoming>, ctx: &Context) -> Response {
+    let is_loopback = req.extensions()
+        .get::<ClientAddr>()
+        .is_some_and(|ClientAddr(addr)| addr.ip().to_canonical().is_loopback());
+    if !is_loopback {
+        trace!(path = req.uri().path(), "admin endpoint requested by non-loopback client -> 403");
+        return error_response(StatusCode::FORBIDDEN);
+    }
+
+    match req.uri().path() {
+        "/-/verify-token" => verify_token(req, ctx).await,
+        "/-/keys" => keys(ctx),
+        "/-/verification-stats" => verification_stats(ctx),
+        _ => error_response(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Verifies the JWT of the request against the live key set and reports the
+/// result.
+async fn verify_token(req: &Request<Incoming>, ctx: &Context) -> Response {
+    let Some(jwt) = find_jwt(req, ctx).ok().and_then(|jwts| jwts.into_iter().next()) else {
+        return error_response(StatusCode::BAD_REQUEST);
+    };
+
+    let body = match ctx.jwt.decode_and_verify_detailed(&jwt).await {
+        Ok((info, matched)) => serde_json::json!({
+            "valid": true,
+            "source": matched.source.to_string(),
+            "kid": matched.kid,
+            "alg": info.alg,
+            "is_admin": info.is_admin,
+            "roles": info.roles,
+            "readable_events": info.readable_events,
+        }),
+        Err(e) => serde_json::json!({
+            "valid": false,
+            "error": e.to_string(),
+        }),
+    };
+    debug!(%body, "admin: verified token");
+    json_response(body)
+}
+
+/// Lists all currently loaded keys, without key material.
+fn keys(ctx: &Context) -> Response {
+    let keys = ctx.jwt.loaded_keys().into_iter()
+        .map(|key| serde_json::json!({
+            "kid": key.kid,
+            "kty": key.kty,
+            "alg": key.alg,
+            "crv": key.crv,
+            "source": key.source.to_string(),
+            "fetched_at": log::rfc3339(key.fetched_at),
+            "expires_at": log::rfc3339(key.expires_at),
+        }))
+        .collect::<Vec<_>>();
+    json_response(serde_json::json!({ "keys": keys }))
+}
+
+/// Reports per-algorithm statistics about signature checks.
+fn verification_stats(ctx: &Context) -> Response {
+    let algorithms = ctx.jwt.verification_stats().into_iter()
+        .map(|(alg, stats)| {
+            let bounds = LATENCY_BUCKETS_US.iter().map(|bound| bound.to_string())
+                .chain(["+Inf".to_owned()]);
+            let buckets = bounds.zip(stats.latency_buckets)
+                .map(|(bound, count)| (bound, serde_json::json!(count)))
+                .collect::<serde_json::Map<_, _>>();
+            let stats = serde_json::json!({
+                "count": stats.count,
+                "failures": stats.failures,
+                "total_ms": stats.total_time.as_secs_f64() * 1000.0,
+                "latency_buckets_us": buckets,
+            });
+            (alg, stats)
+        })
+        .collect::<serde_json::Map<_, _>>();
+    json_response(serde_json::json!({ "algorithms": algorithms }))
+}
+
+fn json_response(body: serde_json::Value) -> Response {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::tiny(body.to_string()))
+        .unwrap()
+}
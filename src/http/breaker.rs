@@ -0,0 +1,72 @@
+//! Circuit breaker for the Opencast fallback. See `opencast.circuit_breaker`.
+
+use std::sync::Mutex;
+
+use tokio::time::Instant;
+
+use crate::{opencast::CircuitBreakerConfig, prelude::*};
+
+
+pub(super) struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    consecutive_failures: u32,
+
+    /// If set, the breaker is open, i.e. Opencast is not asked until then.
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub(super) fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Returns whether Opencast may be asked. Once the cooldown has passed, a
+    /// single probe request is allowed. Further requests are only allowed
+    /// once the probe succeeded, or after another `super::FALLBACK_TIMEOUT`,
+    /// in case the probe never finished (e.g. because the client went away).
+    pub(super) fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            None => true,
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                debug!("circuit breaker cooldown over -> sending probe request to Opencast");
+                state.open_until = Some(Instant::now() + super::FALLBACK_TIMEOUT);
+                true
+            }
+        }
+    }
+
+    /// Records the outcome of a request to Opencast.
+    pub(super) fn record(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        if success {
+            if state.open_until.is_some() {
+                info!("Opencast reachable again -> closing circuit breaker");
+            }
+            *state = State::default();
+            return;
+        }
+
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        let was_open = state.open_until.is_some();
+        if was_open || state.consecutive_failures >= self.config.failures.get() {
+            if !was_open {
+                warn!(
+                    failures = state.consecutive_failures,
+                    cooldown = ?self.config.cooldown,
+                    "Opencast fallback failing repeatedly -> opening circuit breaker",
+                );
+            }
+            state.open_until = Some(Instant::now() + self.config.cooldown);
+        }
+    }
+}
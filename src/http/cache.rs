@@ -0,0 +1,476 @@
+//! Read-through cache for files that are not present in the downloads
+//! directory. See `opencast.read_through_cache`.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}},
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use futures::{StreamExt as _, TryStreamExt as _};
+use http_body_util::{BodyExt as _, BodyStream, StreamBody};
+use hyper::{Request, StatusCode, body::{Frame, Incoming}, header};
+use tokio::io::AsyncWriteExt as _;
+use tokio_util::io::ReaderStream;
+
+use super::{Context, Response, digest::DigestCheck, proxy};
+use crate::{opencast::ReadThroughCacheConfig, prelude::*, util::EmptyHttpBody};
+
+
+/// How long to wait for the next chunk of a file body from Opencast before
+/// aborting the download.
+const BODY_READ_TIMEOUT: Duration = proxy::TIMEOUT;
+
+
+pub(super) struct ReadThroughCache {
+    /// Cached files, with the same layout as the downloads directory.
+    files_dir: PathBuf,
+
+    /// Files are downloaded into this directory first and only moved into
+    /// `files_dir` once complete.
+    tmp_dir: PathBuf,
+
+    max_size: u64,
+    index: Mutex<Index>,
+
+    /// Files currently being downloaded, by path relative to `files_dir`.
+    /// Concurrent requests for the same file wait for the first download
+    /// instead of starting their own.
+    downloads: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+
+    /// Used to generate unique names for temporary files.
+    next_tmp_id: AtomicU64,
+}
+
+/// Tracks all cached files to decide which to evict. Paths are relative to
+/// `files_dir` and use `/` as separator.
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, Entry>,
+    total_size: u64,
+
+    /// Logical clock, incremented on each access.
+    clock: u64,
+}
+
+struct Entry {
+    size: u64,
+    last_used: u64,
+}
+
+/// Result of `ReadThroughCache::get_or_fetch`.
+pub(super) enum Lookup {
+    /// The file is cached at the given path.
+    Cached(PathBuf),
+
+    /// Opencast does not have the file either.
+    NotFound,
+
+    /// The file could not be cached, the response should be sent as is.
+    Response(Response),
+}
+
+impl ReadThroughCache {
+    /// Creates the cache directory if necessary and indexes all files already
+    /// in it. Files are ordered by modification time for the initial LRU order.
+    pub(super) async fn new(config: &ReadThroughCacheConfig) -> Result<Self> {
+        let files_dir = config.path.join("files");
+        let tmp_dir = config.path.join("tmp");
+
+        // Leftovers of interrupted downloads are useless.
+        match tokio::fs::remove_dir_all(&tmp_dir).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("failed to clear '{}'", tmp_dir.display()));
+            }
+            _ => {}
+        }
+        for dir in [&files_dir, &tmp_dir] {
+            tokio::fs::create_dir_all(dir).await
+                .with_context(|| format!("failed to create '{}'", dir.display()))?;
+        }
+
+        let scan = tokio::task::spawn_blocking({
+            let files_dir = files_dir.clone();
+            move || {
+                let mut files = Vec::new();
+                scan_dir(&files_dir, "", &mut files).map(|()| files)
+            }
+        });
+        let mut files = scan.await
+            .expect("panic while scanning read-through cache")
+            .with_context(|| format!("failed to read '{}'", files_dir.display()))?;
+        files.sort_by_key(|(_, _, mtime)| *mtime);
+
+        let mut index = Index::default();
+        for (path, size, _) in files {
+            index.insert(path, size);
+        }
+
+        info!(
+            files = index.entries.len(),
+            size = index.total_size,
+            "Indexed read-through cache",
+        );
+
+        let out = Self {
+            files_dir,
+            tmp_dir,
+            max_size: config.max_size,
+            index: Mutex::new(index),
+            downloads: Mutex::new(HashMap::new()),
+            next_tmp_id: AtomicU64::new(0),
+        };
+        out.evict().await;
+        Ok(out)
+    }
+
+    pub(super) fn files_dir(&self) -> &Path {
+        &self.files_dir
+    }
+
+    /// Returns the cached file for `rel_path` (relative to the downloads
+    /// directory, percent-decoded and lexically normalized), fetching it from
+    /// Opencast first if it's not cached yet.
+    pub(super) async fn get_or_fetch(
+        &self,
+        rel_path: &str,
+        req: &Request<Incoming>,
+        ctx: &Context,
+    ) -> Lookup {
+        // The path was not canonicalized, so we have to make sure it cannot
+        // escape the cache directory.
+        if !Path::new(rel_path).components().all(|c| matches!(c, Component::Normal(_))) {
+            debug!(rel_path, "path with special components -> not using read-through cache");
+            return Lookup::NotFound;
+        }
+
+        let fs_path = self.files_dir.join(rel_path);
+        if self.lookup(rel_path, &fs_path).await {
+            trace!(rel_path, "serving file from read-through cache");
+            return Lookup::Cached(fs_path);
+        }
+
+        // Only one request downloads a file, others wait for it. The file
+        // might have been downloaded while we were waiting.
+        let download = self.download_lock(rel_path);
+        let _guard = download.lock.lock().await;
+        if self.lookup(rel_path, &fs_path).await {
+            trace!(rel_path, "serving file downloaded by other request from read-through cache");
+            return Lookup::Cached(fs_path);
+        }
+
+        let lookup = self.fetch(rel_path, &fs_path, req, ctx).await;
+        if matches!(lookup, Lookup::Cached(_)) {
+            self.evict().await;
+        }
+        lookup
+    }
+
+    /// Returns whether the file is cached and marks it as most recently used.
+    async fn lookup(&self, rel_path: &str, fs_path: &Path) -> bool {
+        if !self.index.lock().unwrap().touch(rel_path) {
+            return false;
+        }
+        if tokio::fs::metadata(fs_path).await.is_ok() {
+            return true;
+        }
+
+        // Someone deleted the file from the cache directory.
+        self.index.lock().unwrap().remove(rel_path);
+        false
+    }
+
+    /// Returns the lock that has to be held while downloading `rel_path`.
+    fn download_lock<'a>(&'a self, rel_path: &'a str) -> DownloadLock<'a> {
+        let mut downloads = self.downloads.lock().unwrap();
+        let lock = Arc::clone(downloads.entry(rel_path.to_owned()).or_default());
+        DownloadLock { cache: self, rel_path, lock }
+    }
+
+    /// Downloads the file from Opencast into the cache. Files larger than
+    /// `max_size` are streamed through to the client instead, also ignoring
+    /// `Range` and conditional headers.
+    async fn fetch(
+        &self,
+        rel_path: &str,
+        fs_path: &Path,
+        req: &Request<Incoming>,
+        ctx: &Context,
+    ) -> Lookup {
+        let uri = ctx.config.opencast.host.clone()
+            .with_path_and_query(req.uri().path_and_query().unwrap().clone());
+        debug!(
+            uri = super::loggable_uri(&uri, &ctx.config.http),
+            "file not found locally -> fetching from OC into read-through cache",
+        );
+
+        let mut oc_req = Request::builder()
+            .uri(uri)
+            .body(EmptyHttpBody::new())
+            .expect("failed to build request for OC");
+        *oc_req.headers_mut() = req.headers().clone();
+        proxy::remove_hop_by_hop_headers(oc_req.headers_mut());
+        proxy::add_forwarded_headers(oc_req.headers_mut(), req);
+
+        // We always want the full, unencoded file.
+        for name in [
+            header::RANGE,
+            header::IF_RANGE,
+            header::IF_NONE_MATCH,
+            header::IF_MODIFIED_SINCE,
+            header::ACCEPT_ENCODING,
+        ] {
+            oc_req.headers_mut().remove(name);
+        }
+
+        let response = tokio::select! {
+            res = ctx.oc_client.request(oc_req) => match res {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("error fetching file from OC for read-through cache: {e}");
+                    return Lookup::Response(super::error_response(StatusCode::BAD_GATEWAY));
+                }
+            },
+            _ = tokio::time::sleep(proxy::TIMEOUT) => {
+                warn!("timeout waiting for OC to respond to read-through cache request");
+                return Lookup::Response(super::error_response(StatusCode::GATEWAY_TIMEOUT));
+            }
+        };
+
+        match response.status() {
+            StatusCode::OK => {}
+            StatusCode::NOT_FOUND => {
+                debug!(rel_path, "OC replied 404 to read-through cache request");
+                return Lookup::NotFound;
+            }
+            status => {
+                warn!(rel_path, ?status, "unexpected response from OC to read-through \
+                    cache request -> 502 Bad Gateway");
+                return Lookup::Response(super::error_response(StatusCode::BAD_GATEWAY));
+            }
+        }
+
+        let content_length = response.headers().get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if content_length.is_some_and(|len| len > self.max_size) {
+            debug!(
+                rel_path,
+                content_length,
+                "file too large for read-through cache -> streaming it through",
+            );
+            return Lookup::Response(proxy::forward_response(response, req, ctx));
+        }
+
+        let digest = ctx.config.http.verify_upstream_digests
+            .then(|| DigestCheck::from_headers(response.headers()))
+            .flatten();
+        let tmp_path = self.tmp_dir
+            .join(self.next_tmp_id.fetch_add(1, Ordering::Relaxed).to_string());
+        let (parts, mut body) = response.into_parts();
+        let size = match self.download(&mut body, &tmp_path, digest).await {
+            Ok(Download::Complete(size)) => size,
+            Ok(Download::TooLarge(chunk)) => {
+                debug!(rel_path, "file too large for read-through cache -> streaming it through");
+                let response = match stream_through(&tmp_path, chunk, body).await {
+                    Ok(body) => hyper::Response::from_parts(parts, body),
+                    Err(e) => {
+                        let _ = tokio::fs::remove_file(&tmp_path).await;
+                        error!(rel_path, "failed to read back partial download: {e}");
+                        return Lookup::Response(super::error_response(StatusCode::BAD_GATEWAY));
+                    }
+                };
+                return Lookup::Response(proxy::forward_response(response, req, ctx));
+            }
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+                error!(rel_path, "failed to download file into read-through cache: {e:#}");
+                return Lookup::Response(super::error_response(StatusCode::BAD_GATEWAY));
+            }
+        };
+
+        let moved = async {
+            if let Some(parent) = fs_path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::rename(&tmp_path, fs_path).await
+        };
+        if let Err(e) = moved.await {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            error!(rel_path, "failed to move file into read-through cache: {e}");
+            return Lookup::Response(super::error_response(StatusCode::INTERNAL_SERVER_ERROR));
+        }
+
+        debug!(rel_path, size, "stored file in read-through cache");
+        self.index.lock().unwrap().insert(rel_path.to_owned(), size);
+        Lookup::Cached(fs_path.to_owned())
+    }
+
+    /// Writes `body` to `path`. Stops once it's larger than `max_size`.
+    /// Returns an error if it does not match `digest`.
+    async fn download(
+        &self,
+        body: &mut Incoming,
+        path: &Path,
+        mut digest: Option<DigestCheck>,
+    ) -> Result<Download> {
+        let mut file = tokio::fs::File::create(path).await?;
+        let mut size = 0;
+        loop {
+            let frame = match tokio::time::timeout(BODY_READ_TIMEOUT, body.frame()).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(_) => bail!("timeout ({BODY_READ_TIMEOUT:?}) waiting for body data from OC"),
+            };
+            let Ok(data) = frame?.into_data() else {
+                continue;
+            };
+            size += data.len() as u64;
+            if size > self.max_size {
+                return Ok(Download::TooLarge(data));
+            }
+            if let Some(digest) = &mut digest {
+                digest.update(&data);
+            }
+            file.write_all(&data).await?;
+        }
+        if let Some(digest) = digest {
+            digest.finish()?;
+        }
+        file.flush().await?;
+        Ok(Download::Complete(size))
+    }
+
+    /// Deletes least recently used files until the cache is not larger than
+    /// `max_size` anymore.
+    async fn evict(&self) {
+        loop {
+            let evicted = {
+                let mut index = self.index.lock().unwrap();
+                if index.total_size <= self.max_size {
+                    return;
+                }
+                index.pop_least_recently_used()
+            };
+            let Some(rel_path) = evicted else {
+                return;
+            };
+
+            // Files that are currently being served stay readable on Unix.
+            trace!(rel_path, "evicting file from read-through cache");
+            if let Err(e) = tokio::fs::remove_file(self.files_dir.join(&rel_path)).await {
+                warn!(rel_path, "failed to delete file from read-through cache: {e}");
+            }
+        }
+    }
+}
+
+/// Result of `ReadThroughCache::download`.
+enum Download {
+    /// The whole body was written, with the given size.
+    Complete(u64),
+
+    /// The body is larger than `max_size`. Contains the chunk read last, which
+    /// was not written anymore.
+    TooLarge(Bytes),
+}
+
+/// Held while downloading a file, see `ReadThroughCache::downloads`.
+struct DownloadLock<'a> {
+    cache: &'a ReadThroughCache,
+    rel_path: &'a str,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Drop for DownloadLock<'_> {
+    fn drop(&mut self) {
+        // Only `downloads` and this hold the lock if no one else is waiting.
+        let mut downloads = self.cache.downloads.lock().unwrap();
+        if Arc::strong_count(&self.lock) == 2 {
+            downloads.remove(self.rel_path);
+        }
+    }
+}
+
+/// Returns the body of a download that turned out too large: the part already
+/// written to the temporary file at `path`, followed by `chunk` and the rest of
+/// `body`. The file is deleted right away, it stays readable while open.
+async fn stream_through(
+    path: &Path,
+    chunk: Bytes,
+    body: Incoming,
+) -> io::Result<StreamBody<impl futures::Stream<Item = io::Result<Frame<Bytes>>> + use<>>> {
+    let file = tokio::fs::File::open(path).await?;
+    tokio::fs::remove_file(path).await?;
+    let start = ReaderStream::new(file)
+        .chain(futures::stream::iter([Ok(chunk)]))
+        .map_ok(Frame::data);
+    let rest = BodyStream::new(body.map_err(io::Error::other));
+    Ok(StreamBody::new(start.chain(rest)))
+}
+
+impl Index {
+    /// Inserts or replaces an entry and marks it as most recently used.
+    fn insert(&mut self, path: String, size: u64) {
+        self.remove(&path);
+        self.clock += 1;
+        self.total_size += size;
+        self.entries.insert(path, Entry { size, last_used: self.clock });
+    }
+
+    /// Marks the entry as most recently used. Returns `false` if it does not
+    /// exist.
+    fn touch(&mut self, path: &str) -> bool {
+        self.clock += 1;
+        match self.entries.get_mut(path) {
+            Some(entry) => {
+                entry.last_used = self.clock;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, path: &str) {
+        if let Some(old) = self.entries.remove(path) {
+            self.total_size -= old.size;
+        }
+    }
+
+    /// Removes the least recently used entry and returns its path. This is
+    /// linear in the number of entries, which is fine as it's only called
+    /// after downloading a file, which is way more expensive.
+    fn pop_least_recently_used(&mut self) -> Option<String> {
+        let path = self.entries.iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(path, _)| path.clone())?;
+        self.remove(&path);
+        Some(path)
+    }
+}
+
+/// Recursively collects all files in `dir` as (relative path, size, mtime).
+fn scan_dir(
+    dir: &Path,
+    prefix: &str,
+    out: &mut Vec<(String, u64, SystemTime)>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(ToOwned::to_owned) else {
+            continue;
+        };
+        let rel_path = if prefix.is_empty() { name } else { format!("{prefix}/{name}") };
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            scan_dir(&entry.path(), &rel_path, out)?;
+        } else {
+            let mtime = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            out.push((rel_path, metadata.len(), mtime));
+        }
+    }
+    Ok(())
+}
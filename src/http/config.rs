@@ -1,6 +1,13 @@
-use std::{net::{IpAddr, SocketAddr}, time::Duration};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    path::PathBuf,
+    time::Duration,
+};
 
 use anyhow::anyhow;
+use hyper::header::{HeaderName, HeaderValue};
 use serde::Deserialize;
 
 
@@ -24,6 +31,37 @@ pub struct HttpConfig {
     #[config(default = "empty")]
     pub on_deny: OnDeny,
 
+    /// If `true` and `on_deny = "empty"`, requests without any JWT are
+    /// answered with "401 Unauthorized" and a `WWW-Authenticate: Bearer`
+    /// challenge instead of 403. Requests with a JWT that does not grant
+    /// access are still answered with 403.
+    #[config(default = false)]
+    pub challenge_on_missing: bool,
+
+    /// Specifies how to respond to requests whose path does not start with
+    /// any of `opencast.path_prefixes` or is otherwise not understood.
+    /// - "bad-request": status 400.
+    /// - "not-found": status 404.
+    /// - "proxy": the request is forwarded to `opencast.host` and Opencast's
+    ///   response is passed through.
+    ///
+    /// Paths listed in `unmatched_files` are handled first.
+    #[config(default = "bad-request")]
+    pub passthrough_unmatched: UnmatchedMode,
+
+    /// Local files that are served for specific request paths not handled
+    /// otherwise, e.g. `{ "/robots.txt" = "/etc/octoka/robots.txt" }`. Keys
+    /// are the exact request path, values are file paths. These files are
+    /// served to everyone without any authorization check!
+    #[config(
+        default = {},
+        validate(
+            unmatched_files.keys().all(|k| k.starts_with('/')),
+            "request paths must start with '/'",
+        ),
+    )]
+    pub unmatched_files: HashMap<String, PathBuf>,
+
     /// Origins from which CORS requests are allowed. Web apps that load assets
     /// with the 'Authorization' header must be listed here. If empty, no CORS
     /// requests are allowed.
@@ -33,16 +71,175 @@ pub struct HttpConfig {
     )]
     pub cors_allowed_origins: Vec<CorsOrigin>,
 
+    /// How to respond to CORS preflight requests (`OPTIONS` with `Origin`
+    /// header) that are denied, e.g. because the origin is not listed in
+    /// `cors_allowed_origins`.
+    /// - "allow-no-cors": status 204, but without any CORS headers. The
+    ///   browser will then block the actual request.
+    /// - "forbidden": status 403.
+    #[config(default = "allow-no-cors")]
+    pub cors_preflight_on_deny: CorsPreflightOnDeny,
+
+    /// Origins (e.g. "https://tobira.example.com") from which embedding files
+    /// is allowed, as a protection against hotlinking from other sites. If
+    /// not empty, requests for files with a `Referer` header whose origin is
+    /// not listed here are denied with 403. Requests without `Referer` are
+    /// not affected, as browsers omit it in many legitimate situations.
+    /// Unlike `cors_allowed_origins`, this also affects plain `<video>` or
+    /// `<img>` embeds. Empty means disabled.
+    #[config(
+        default = [],
+        validate(crate::config::is_unique(allowed_referers), "has duplicates"),
+    )]
+    pub allowed_referers: Vec<CorsOrigin>,
+
+    /// If `true`, the `Timing-Allow-Origin` header is set for CORS requests
+    /// from origins allowed by `cors_allowed_origins`. This allows frontends
+    /// to measure detailed load timings via the Resource Timing API.
+    #[config(default = false)]
+    pub timing_allow_origin: bool,
+
+    /// Rules to set the `Cache-Control` header of file responses (only
+    /// relevant for `on_allow = "file"`). Each rule is an object with
+    /// `pattern` and `value`. The `pattern` is matched against the file path
+    /// inside the event directory, where `*` matches any sequence of
+    /// characters (including `/`). The first matching rule determines the
+    /// header value. If no rule matches, no `Cache-Control` header is set.
+    ///
+    /// Example:
+    ///
+    ///    cache_rules = [
+    ///        { pattern = "*.m3u8", value = "max-age=0, must-revalidate" },
+    ///        { pattern = "*", value = "max-age=31536000, immutable" },
+    ///    ]
+    #[config(default = [])]
+    pub cache_rules: Vec<CacheRule>,
+
+    /// Placeholder files served when a requested file does not exist (only
+    /// relevant for `on_allow = "file"`), e.g. a default poster image for
+    /// missing thumbnails. Each rule is an object with `pattern` and `file`.
+    /// The `pattern` is matched like in `cache_rules`, the first matching rule
+    /// is used. Placeholders are only served to authorized requests and with
+    /// status 200. Example:
+    ///
+    ///    fallback_files = [
+    ///        { pattern = "*.jpg", file = "/etc/octoka/placeholder.jpg" },
+    ///    ]
+    #[config(default = [])]
+    pub fallback_files: Vec<FallbackFile>,
+
+    /// If `true`, HLS manifests (`.m3u8`) served with `on_allow = "file"` are
+    /// preceded by a `103 Early Hints` response with `Link: <...>; rel=preload`
+    /// headers for the first few segments they reference, so that players can
+    /// fetch them earlier. The same headers are also added to the final
+    /// response. Only sent to HTTP/1.1 clients and after the request was
+    /// authorized. Note that preload requests do not carry a JWT passed in the
+    /// query.
+    #[config(default = false)]
+    pub early_hints: bool,
+
+    /// If set, files served with `on_allow = "file"` that are at most this
+    /// large (in bytes) get a `Repr-Digest` header (RFC 9530) with the SHA-256
+    /// of the whole file, so that clients can verify what they downloaded.
+    /// The file has to be read completely for that, so keep this small.
+    /// Not sent for `304 Not Modified` responses.
+    pub repr_digest_max_size: Option<u64>,
+
+    /// Content types of served files to which `; charset=utf-8` is appended
+    /// (only relevant for `on_allow = "file"`). `*` matches any sequence of
+    /// characters. Opencast text files like WebVTT captions are UTF-8, but
+    /// without charset parameter, some browsers decode them incorrectly.
+    #[config(default = ["text/*"])]
+    pub charset_types: Vec<String>,
+
+    /// Additional headers for served files depending on their content type
+    /// (only relevant for `on_allow = "file"`), e.g. for media players that
+    /// expect certain headers. Each rule is an object with `content_type`,
+    /// `name` and `value`. `content_type` is matched like in `charset_types`
+    /// (without the charset parameter). All matching rules are applied.
+    /// Responses proxied from Opencast pass through Opencast's headers (like
+    /// `X-Content-Duration`) anyway. These headers are not added to `304 Not
+    /// Modified` responses, which are sent before the content type is
+    /// determined. Example:
+    ///
+    ///    type_headers = [
+    ///        { content_type = "video/*", name = "X-Content-Type-Options", value = "nosniff" },
+    ///    ]
+    #[config(default = [])]
+    pub type_headers: Vec<TypeHeader>,
+
+    /// If `true`, responses to allowed requests with `on_allow = "empty"` or
+    /// `"x-accel-redirect:..."` include the headers `X-Authenticated-User`,
+    /// `X-Authenticated-Name` and `X-Authenticated-Email` with the `sub`,
+    /// `name` and `email` claims of the JWT granting access (if present).
+    /// Your reverse proxy can then log them or forward them to Opencast (e.g.
+    /// via `$upstream_http_x_authenticated_user` in nginx). Control and
+    /// non-ASCII characters are percent-encoded.
+    #[config(default = false)]
+    pub user_headers: bool,
+
+    /// If `true`, responses to allowed requests with `on_allow = "empty"` or
+    /// `"x-accel-redirect:..."` include the headers `X-Octoka-Org`,
+    /// `X-Octoka-Channel` and `X-Octoka-Event` with the respective segments
+    /// of the request path (as they appear in the path, i.e. not
+    /// percent-decoded). Useful for logging or access control in your
+    /// reverse proxy, e.g. via `$upstream_http_x_octoka_event` in nginx.
+    #[config(default = false)]
+    pub path_headers: bool,
+
+    /// Additional headers for responses to allowed requests with `on_allow =
+    /// "empty"` or `"x-accel-redirect:..."`, e.g. for your reverse proxy to
+    /// pick up. Each entry is an object with `name` and `value`. Example:
+    ///
+    ///    allow_headers = [{ name = "X-Octoka-Result", value = "allow" }]
+    #[config(default = [])]
+    pub allow_headers: Vec<ExtraHeader>,
+
+    /// Like `allow_headers`, but for responses to denied requests (see
+    /// `on_deny`).
+    #[config(default = [])]
+    pub deny_headers: Vec<ExtraHeader>,
+
+    /// If not zero, responses with status 401, 403 or 404 are delayed by a
+    /// random duration between half of this value and this value. This makes
+    /// probing for event IDs slower and less attractive. The delay does not
+    /// block other requests, but keeps the connection busy, so only use small
+    /// values like "200ms".
+    #[config(default = "0s", deserialize_with = crate::config::deserialize_duration)]
+    pub deny_delay: Duration,
+
+    /// Client hints to request via the `Accept-CH` header, e.g. `["Downlink",
+    /// "ECT", "RTT", "Save-Data"]`. If not empty, `Accept-CH` is sent with
+    /// all responses to allowed requests (files and empty responses), so that
+    /// clients include these hints in subsequent requests. Useful if your
+    /// edge does adaptive delivery based on them. octoka itself ignores the
+    /// hints, so no `Vary` header is added.
+    #[config(
+        default = [],
+        validate(
+            client_hints.iter()
+                .all(|h| hyper::header::HeaderName::try_from(h.as_str()).is_ok()),
+            "invalid client hint (has to be a valid header name)",
+        ),
+    )]
+    pub client_hints: Vec<String>,
+
     /// Where to look for a JWT in the HTTP request. First source has highest
     /// priority. Each array element is an object. Possible sources:
     ///
     /// - `{ source = "query", name = "jwt" }`: from URL query parameter "jwt".
     ///   `name` can be chosen arbitrarily. The first parameter with that name
-    ///   is used.
+    ///   is used. The value is percent-decoded once, with `+` meaning space,
+    ///   as usual for query strings. JWTs are base64url encoded and thus only
+    ///   contain URL-safe characters, so they can be passed as is. Tokens
+    ///   using standard base64 (`+` and `/`) or being encoded twice are
+    ///   rejected.
     /// - `{ source = "header", name = "Authorization", prefix = "Bearer " }`:
     ///   from first HTTP header with the given name. The optional `prefix` is
     ///   stripped from the header value. If the header value does not start
-    ///   with the given prefix, it is ignored.
+    ///   with the given prefix, it is ignored. The prefix is matched case
+    ///   insensitively, as authentication schemes like `Bearer` or `DPoP`
+    ///   are case insensitive (RFC 7235).
     #[config(
         default = [
             { "source": "header", "name": "Authorization", "prefix": "Bearer " },
@@ -52,6 +249,99 @@ pub struct HttpConfig {
     )]
     pub jwt_sources: Vec<JwtSource>,
 
+    /// What to do if a request contains multiple headers for a `header`
+    /// source of `jwt_sources`, e.g. two `Authorization` headers. Only header
+    /// values starting with the source's `prefix` count. Possible values:
+    ///
+    /// - "first": only the first header is considered. If it does not start
+    ///   with the `prefix`, the source does not provide a JWT.
+    /// - "reject": if more than one header value starts with the `prefix`,
+    ///   the request is answered with 400 Bad Request.
+    /// - "try-each": every header value starting with the `prefix` is checked
+    ///   and access is granted if any of them grants access.
+    #[config(default = "first")]
+    pub duplicate_jwt_headers: DuplicateJwtHeaders,
+
+    /// Headers to remove from responses proxied from Opencast (only relevant
+    /// for `passthrough_unmatched = "proxy"`), e.g. `["X-Powered-By"]`. Case
+    /// insensitive.
+    #[config(
+        default = [],
+        validate(
+            strip_response_headers.iter()
+                .all(|h| hyper::header::HeaderName::try_from(h.as_str()).is_ok()),
+            "invalid header name",
+        ),
+    )]
+    pub strip_response_headers: Vec<String>,
+
+    /// Maximum time to wait for the next chunk of a response body proxied
+    /// from Opencast. If Opencast stalls for longer, the response is aborted
+    /// and the connection to the client is closed.
+    #[config(default = "30s", deserialize_with = crate::config::deserialize_duration)]
+    pub proxy_read_timeout: Duration,
+
+    /// Maximum size (in bytes) of response bodies proxied from Opencast. If
+    /// Opencast announces a larger `Content-Length`, octoka replies with "502
+    /// Bad Gateway". Otherwise, the response is aborted once the limit is
+    /// exceeded. Not set by default, i.e. no limit.
+    pub proxy_max_body_size: Option<u64>,
+
+    /// Whether to check bodies received from Opencast against the `Digest`
+    /// (`sha-256` or `md5`) or `Content-MD5` header Opencast sent along. This
+    /// guards against truncated or corrupt responses. Responses without
+    /// these headers are not checked. On mismatch, files are not stored in
+    /// `opencast.read_through_cache` (responding with 502 Bad Gateway).
+    /// Proxied responses are streamed, so there, the mismatch is only noticed
+    /// at the end. The last chunk is held back until then and the response is
+    /// aborted instead of sending it.
+    #[config(default = false)]
+    pub verify_upstream_digests: bool,
+
+    /// Value of the `Server` header set on all responses, or "none" to omit
+    /// the header. With "none", a `Server` header from a proxied Opencast
+    /// response is removed as well.
+    #[config(default = "none")]
+    pub server_header: ServerHeader,
+
+    /// If `true`, octoka starts in maintenance mode: all requests for
+    /// Opencast files are answered with "503 Service Unavailable" and a
+    /// `Retry-After` header. Other requests (e.g. admin endpoints and
+    /// unmatched paths) are handled as usual. On Unix, maintenance mode can
+    /// be toggled at runtime by sending `SIGUSR2` to the octoka process.
+    #[config(default = false)]
+    pub maintenance_mode: bool,
+
+    /// If `true`, enables administrative endpoints under `/-/`. These are
+    /// only available to clients connecting from a loopback address. Make
+    /// sure your reverse proxy does not forward `/-/` to octoka! Endpoints:
+    ///
+    /// - `GET /-/verify-token`: verifies the JWT from the request (found via
+    ///   `jwt_sources`) with the currently loaded keys and replies with a JSON
+    ///   object describing the result, including which key source and `kid`
+    ///   matched.
+    /// - `GET /-/keys`: lists all currently loaded keys with their `kid`,
+    ///   `kty`, `alg`, `crv`, source URL, and fetch and expiry times (key
+    ///   material is not included).
+    /// - `GET /-/verification-stats`: number, failures and latency histogram
+    ///   (in microseconds) of signature checks since startup, by algorithm.
+    ///   Useful to understand the crypto load, e.g. when planning to migrate
+    ///   to a different key algorithm.
+    #[config(default = false)]
+    pub admin_endpoints: bool,
+
+    /// If `true`, every source in `jwt_sources` has to provide a JWT and each
+    /// of those has to grant access on its own. A request missing a JWT from
+    /// any source is denied, without asking Opencast (`opencast.fallback` is
+    /// ignored). This allows requiring two independent tokens
+    /// (e.g. one from Tobira and one from an edge gateway) for defense in
+    /// depth. Tradeoffs: every request needs multiple signature verifications
+    /// and clients must be able to send all tokens, which is hard or
+    /// impossible for some players (e.g. those only supporting query
+    /// parameters).
+    #[config(default = false)]
+    pub require_all_sources: bool,
+
     /// The TCP port the HTTP server should listen on.
     #[config(default = 4050)]
     pub port: u16,
@@ -60,6 +350,43 @@ pub struct HttpConfig {
     #[config(default = "127.0.0.1")]
     pub address: IpAddr,
 
+    /// Additional addresses to listen on, besides `address` and `port`. Each
+    /// entry is an object with `address` and `port` fields. All listeners
+    /// serve the same content with the same configuration. octoka only
+    /// speaks plain HTTP on all of them. Example:
+    ///
+    ///    listeners = [{ address = "::1", port = 4051 }]
+    #[config(default = [])]
+    pub listeners: Vec<Listener>,
+
+    /// Only relevant for IPv6 addresses in `address` or `listeners`: whether
+    /// the socket only accepts IPv6 connections (`IPV6_V6ONLY`). If `false`,
+    /// binding to `::` accepts both, IPv4 and IPv6 connections, regardless of
+    /// the operating system's default.
+    #[config(default = false)]
+    pub ipv6_only: bool,
+
+    /// Deadline for sending a file response (only relevant for `on_allow =
+    /// "file"`). To not penalize large downloads, one second per 64 KiB of
+    /// response body is added to this value. If the response is not fully
+    /// sent by then, the connection is closed. This protects against very
+    /// slow clients occupying resources for a long time.
+    #[config(default = "10min", deserialize_with = crate::config::deserialize_duration)]
+    pub response_timeout: Duration,
+
+    /// Maximum number of files that are open at the same time to serve them
+    /// (only relevant for `on_allow = "file"`). A file stays open until its
+    /// response is completely sent. If the limit is reached, requests wait
+    /// for up to `open_file_wait` and are answered with 503 Service
+    /// Unavailable after that. Set this below the process' file descriptor
+    /// limit (`ulimit -n`), leaving room for sockets. Unlimited by default.
+    pub max_open_files: Option<NonZeroUsize>,
+
+    /// How long a request waits for a free slot if `max_open_files` is
+    /// reached. "0s" responds with 503 right away.
+    #[config(default = "5s", deserialize_with = crate::config::deserialize_duration)]
+    pub open_file_wait: Duration,
+
     /// How long to wait for active connections to terminate when shutting down.
     #[config(default = "3s", deserialize_with = crate::config::deserialize_duration)]
     pub shutdown_timeout: Duration,
@@ -69,6 +396,20 @@ impl HttpConfig {
     pub fn socket_addr(&self) -> SocketAddr {
         SocketAddr::from((self.address, self.port))
     }
+
+    /// All addresses to listen on: `address`/`port` first, then `listeners`.
+    pub fn socket_addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        std::iter::once(self.socket_addr())
+            .chain(self.listeners.iter().map(|l| SocketAddr::from((l.address, l.port))))
+    }
+}
+
+/// An additional address to listen on. See `http.listeners`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Listener {
+    pub address: IpAddr,
+    pub port: u16,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
@@ -118,6 +459,80 @@ impl TryFrom<String> for OnDeny {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ServerHeader {
+    None,
+    Value(HeaderValue),
+}
+
+impl TryFrom<String> for ServerHeader {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        if value == "none" {
+            Ok(Self::None)
+        } else {
+            HeaderValue::try_from(value)
+                .map(Self::Value)
+                .map_err(|_| anyhow!("invalid header value"))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnmatchedMode {
+    BadRequest,
+    NotFound,
+    Proxy,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheRule {
+    pub pattern: String,
+    #[serde(deserialize_with = "crate::config::deserialize_header_value")]
+    pub value: HeaderValue,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TypeHeader {
+    pub content_type: String,
+    #[serde(deserialize_with = "crate::config::deserialize_header_name")]
+    pub name: HeaderName,
+    #[serde(deserialize_with = "crate::config::deserialize_header_value")]
+    pub value: HeaderValue,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExtraHeader {
+    #[serde(deserialize_with = "crate::config::deserialize_header_name")]
+    pub name: HeaderName,
+    #[serde(deserialize_with = "crate::config::deserialize_header_value")]
+    pub value: HeaderValue,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FallbackFile {
+    pub pattern: String,
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CorsPreflightOnDeny {
+    AllowNoCors,
+    Forbidden,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateJwtHeaders {
+    First,
+    Reject,
+    TryEach,
+}
+
 #[derive(Debug, Clone, serde::Deserialize)]
 #[serde(tag = "source", rename_all = "snake_case")]
 pub enum JwtSource {
@@ -0,0 +1,116 @@
+//! Checking response bodies from Opencast against the digest Opencast sent
+//! along. See `http.verify_upstream_digests`.
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use hyper::{HeaderMap, header::HeaderName};
+use sha2::{Sha256, digest::DynDigest};
+
+use crate::prelude::*;
+
+
+const CONTENT_MD5: HeaderName = HeaderName::from_static("content-md5");
+const DIGEST: HeaderName = HeaderName::from_static("digest");
+
+
+/// Incrementally hashes a body and compares it to the expected digest.
+pub(super) struct DigestCheck {
+    hasher: Box<dyn DynDigest + Send + Sync>,
+    expected: Vec<u8>,
+    algorithm: &'static str,
+}
+
+impl DigestCheck {
+    /// Reads the expected digest from the `Digest` header (RFC 3230, with
+    /// `sha-256` or `md5`) or `Content-MD5` (RFC 1864), in that order of
+    /// preference. Returns `None` if there is no usable digest.
+    pub(super) fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let digests = headers.get_all(DIGEST).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|digest| digest.trim().split_once('='))
+            .collect::<Vec<_>>();
+        let find = |alg: &str| digests.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(alg))
+            .map(|(_, value)| *value);
+
+        let sha256 = find("sha-256")
+            .and_then(|value| Self::new(Box::new(Sha256::default()), value, "sha-256"));
+        if sha256.is_some() {
+            return sha256;
+        }
+        let md5 = find("md5")
+            .or_else(|| headers.get(CONTENT_MD5).and_then(|value| value.to_str().ok()))?;
+        Self::new(Box::new(md5::Md5::default()), md5, "md5")
+    }
+
+    fn new(
+        hasher: Box<dyn DynDigest + Send + Sync>,
+        base64_value: &str,
+        algorithm: &'static str,
+    ) -> Option<Self> {
+        let expected = STANDARD.decode(base64_value.trim()).ok()
+            .filter(|expected| expected.len() == hasher.output_size());
+        if expected.is_none() {
+            debug!(algorithm, base64_value, "ignoring invalid digest from OC");
+        }
+        Some(Self { hasher, expected: expected?, algorithm })
+    }
+
+    pub(super) fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// Returns an error if the hashed data does not match the expected digest.
+    pub(super) fn finish(self) -> Result<()> {
+        if *self.hasher.finalize() != *self.expected {
+            bail!("{} digest of body from OC does not match header", self.algorithm);
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hyper::HeaderMap;
+
+    use super::DigestCheck;
+
+    fn check(headers: &[(&str, &str)], body: &[u8]) -> Option<bool> {
+        let headers = headers.iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect::<HeaderMap>();
+        let mut check = DigestCheck::from_headers(&headers)?;
+        check.update(body);
+        Some(check.finish().is_ok())
+    }
+
+    #[test]
+    fn digest_headers() {
+        // Digests of "hello"
+        const MD5: &str = "XUFAKrxLKna5cZ2REBfFkg==";
+        const SHA256: &str = "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+
+        let content_md5 = [("content-md5", MD5)];
+        assert_eq!(check(&content_md5, b"hello"), Some(true));
+        assert_eq!(check(&content_md5, b"hell"), Some(false));
+        let value = format!("md5={MD5}");
+        let digest_md5 = [("digest", value.as_str())];
+        assert_eq!(check(&digest_md5, b"hello"), Some(true));
+        let value = format!("SHA-256={SHA256}");
+        let digest_sha256 = [("digest", value.as_str())];
+        assert_eq!(check(&digest_sha256, b"hello"), Some(true));
+        assert_eq!(check(&digest_sha256, b"hello!"), Some(false));
+
+        // SHA-256 is preferred, invalid digests are skipped.
+        let value = format!("md5=AAAAAAAAAAAAAAAAAAAAAA==, sha-256={SHA256}");
+        let both = [("digest", value.as_str())];
+        assert_eq!(check(&both, b"hello"), Some(true));
+        let invalid_sha256 = [("digest", "sha-256=AAAA"), ("content-md5", MD5)];
+        assert_eq!(check(&invalid_sha256, b"hello"), Some(true));
+
+        // Unknown algorithms or no digest at all.
+        assert_eq!(check(&[("digest", "sha-512=AAAA")], b"hello"), None);
+        assert_eq!(check(&[], b"hello"), None);
+    }
+}
@@ -1,15 +1,59 @@
-use std::time::SystemTime;
-
+use std::{
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::{Duration, SystemTime},
+};
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
 use bytes::Bytes;
 use futures::TryStreamExt as _;
-use http::Request;
-use http_body_util::combinators::BoxBody;
+use http::{Request, Version};
+use http_body_util::{Full, combinators::BoxBody};
 use http_range::{HttpRange, HttpRangeParseError};
-use hyper::{HeaderMap, StatusCode, body::Incoming, header};
-use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncSeekExt as _};
+use hyper::{
+    HeaderMap, StatusCode,
+    body::{Frame, Incoming, SizeHint},
+    header::{self, HeaderName, HeaderValue},
+};
+use percent_encoding::percent_decode_str;
+use sha2::{Digest as _, Sha256};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncSeekExt as _},
+    sync::OwnedSemaphorePermit,
+    time::Sleep,
+};
+
+use super::{Body, Context, Response, cache::Lookup, hints::EarlyHints};
+use crate::{
+    http::{add_client_hints, add_cors_headers},
+    opencast::PathParts,
+    prelude::*,
+    util,
+};
+
+
+/// Minimum transfer rate in bytes per second that is accounted for in the
+/// response deadline, in addition to `http.response_timeout`.
+const MIN_TRANSFER_RATE: u64 = 64 * 1024;
+
+/// Size of the chunks in which files are read and sent. Every read of a
+/// `tokio::fs::File` is a roundtrip to the blocking thread pool, so the 4 KiB
+/// default of `ReaderStream` costs a lot of CPU for large video files. Larger
+/// chunks barely help further, but cost memory per open file.
+///
+/// Sending files without copying them through userspace (`sendfile`/`splice`)
+/// is not possible, as hyper only writes body data from buffers.
+const FILE_CHUNK_SIZE: usize = 128 * 1024;
+
+/// How many segments of an HLS manifest are announced with `http.early_hints`.
+const PRELOAD_SEGMENTS: usize = 3;
 
-use super::{Body, Context, Response};
-use crate::{http::add_cors_headers, opencast::PathParts, prelude::*};
+/// Larger HLS manifests are not inspected for `http.early_hints`.
+const PRELOAD_MAX_MANIFEST_SIZE: u64 = 64 * 1024;
+
+const REPR_DIGEST: HeaderName = HeaderName::from_static("repr-digest");
 
 
 /// Serves the file referred to by `path` directly from the file system.
@@ -18,25 +62,101 @@ pub async fn serve_file(
     req: &Request<Incoming>,
     ctx: &Context,
 ) -> Response {
+    // This is checked by `Config::validate`
+    let downloads_path = ctx.config.opencast.downloads_path_for(path.channel())
+        .expect("no downloads_path in serve_files");
+
     macro_rules! handle_io_err {
         ($e:expr, $action:literal $(,)?) => {
             match $e {
                 Ok(v) => v,
-                Err(e) => return handle_io_error(&e, $action),
+                Err(e) => {
+                    // If the downloads directory itself is gone (e.g. the
+                    // storage got unmounted), that's not the client's fault.
+                    if !tokio::fs::metadata(downloads_path).await.is_ok_and(|m| m.is_dir()) {
+                        error!(
+                            ?downloads_path,
+                            "Downloads directory unavailable (unmounted?) while {} -> \
+                                503 Service Unavailable. Error: {e}",
+                            $action,
+                        );
+                        return super::error_response(StatusCode::SERVICE_UNAVAILABLE);
+                    }
+                    return handle_io_error(&e, $action);
+                }
             }
         };
     }
 
-    // This is checked by `Config::validate`
-    let downloads_path = ctx.config.opencast.downloads_path.as_ref()
-        .expect("no downloads_path in serve_files");
+    // The request path is percent-encoded, but the file system path is not.
+    let Ok(rel_path) = percent_decode_str(path.without_prefix()).decode_utf8() else {
+        debug!(path = path.full_path(), "path is not valid UTF-8 after decoding");
+        return super::error_response(StatusCode::BAD_REQUEST);
+    };
+    if rel_path.contains('\0') {
+        debug!(path = path.full_path(), "path contains null byte");
+        return super::error_response(StatusCode::BAD_REQUEST);
+    }
+
+    // Resolve `.` and `..` lexically and check for path traversal before
+    // touching the file system. That way, `..` works even if preceding
+    // directories do not exist, does not depend on symlinks, and traversal
+    // attempts are rejected even if the target does not exist. With
+    // `opencast.path_resolution = "canonicalize"`, the check below
+    // additionally catches symlinks.
+    let event_dir = percent_decode_str(path.rel_event_dir()).decode_utf8_lossy();
+    let normalized = util::normalize_lexically(Path::new(&*rel_path))
+        .filter(|normalized| normalized.starts_with(&*event_dir));
+    let Some(normalized) = normalized else {
+        warn!(
+            path = path.full_path(),
+            "Directory traversal attack detected, responding 400 Bad Request",
+        );
+        return super::error_response(StatusCode::BAD_REQUEST);
+    };
+
+    // Join and resolve path (see `opencast.path_resolution`). Check for path
+    // traversal attacks. If the file does not exist, it might be fetched into
+    // the read-through cache or a configured placeholder might be served.
+    let mut base_path = downloads_path;
+    let resolution = ctx.config.opencast.path_resolution;
+    let resolved = util::resolve_path(downloads_path, &normalized, resolution);
+    let downloads_available = match &resolved {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => tokio::fs::metadata(downloads_path)
+            .await
+            .is_ok_and(|metadata| metadata.is_dir()),
+        _ => false,
+    };
+    let fs_path = match resolved {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && downloads_available => 'found: {
+            if let Some(cache) = &ctx.read_through_cache {
+                // `normalized` was built from a `str`, so this is lossless.
+                let cache_path = normalized.to_string_lossy();
+                match cache.get_or_fetch(&cache_path, req, ctx).await {
+                    Lookup::Cached(cached_path) => {
+                        base_path = cache.files_dir();
+                        break 'found cached_path;
+                    }
+                    Lookup::Response(response) => return response,
+                    Lookup::NotFound => {}
+                }
+            }
 
-    // Join, resolve and canonicalize path. Check for path traversal attacks.
-    let fs_path = handle_io_err!(
-        downloads_path.join(path.without_prefix()).canonicalize(),
-        "canonicalizing path",
-    );
-    let event_dir = downloads_path.join(path.rel_event_dir());
+            let fallback = ctx.config.http.fallback_files.iter()
+                .find(|rule| util::glob_match(&rule.pattern, path.suffix()));
+            let Some(fallback) = fallback else {
+                return handle_io_error(&e, "resolving path");
+            };
+
+            trace!(path = path.full_path(), file = ?fallback.file,
+                "file does not exist -> serving fallback file");
+            let mut response = Response::builder();
+            add_cors_headers(req, &mut response, &ctx.config.http);
+            return serve_static_file(&fallback.file, response).await;
+        }
+        res => handle_io_err!(res, "resolving path"),
+    };
+    let event_dir = base_path.join(path.rel_event_dir());
     if !fs_path.starts_with(&event_dir) {
         warn!(
             path = path.full_path(),
@@ -45,11 +165,37 @@ pub async fn serve_file(
         return super::error_response(StatusCode::BAD_REQUEST);
     }
 
+    // Wait for a free slot if `http.max_open_files` is set. The permit is
+    // moved into the response body and released once that is dropped.
+    let permit = match &ctx.open_files {
+        None => None,
+        Some(open_files) => {
+            let acquire = Arc::clone(open_files).acquire_owned();
+            match tokio::time::timeout(ctx.config.http.open_file_wait, acquire).await {
+                Ok(permit) => Some(permit.expect("open files semaphore closed")),
+                Err(_) => {
+                    warn!(
+                        path = path.full_path(),
+                        "`http.max_open_files` reached -> 503 Service Unavailable",
+                    );
+                    return super::error_response(StatusCode::SERVICE_UNAVAILABLE);
+                }
+            }
+        }
+    };
+
     // Open file and check size
     let mut file = handle_io_err!(tokio::fs::File::open(&fs_path).await, "opening file");
     let metadata = handle_io_err!(file.metadata().await, "reading file metadata");
     let file_size = metadata.len();
 
+    // Opening directories succeeds on Unix, only reading them fails. Treat
+    // them like missing files, for `GET` and `HEAD` alike.
+    if metadata.is_dir() {
+        trace!(path = path.full_path(), "path is a directory -> 404 Not Found");
+        return super::error_response(StatusCode::NOT_FOUND);
+    }
+
     // We `unwrap` here as this will always return `Ok` on most platforms.
     // Specifically, Unix and Windows always return `Ok` to the best of my
     // knowledge.
@@ -57,15 +203,58 @@ pub async fn serve_file(
         .expect("platform does not support 'modified' timestamp");
     let etag = etag(mtime, file_size, &metadata);
 
-
+    // HTTP dates only have a resolution of one second. All date comparisons
+    // are done with the truncated mtime, matching the `Last-Modified` header.
+    let last_modified = truncate_to_secs(mtime);
 
     let mut response = Response::builder()
         .header(header::ACCEPT_RANGES, "bytes")
-        .header(header::LAST_MODIFIED, httpdate::fmt_http_date(mtime))
+        .header(header::LAST_MODIFIED, httpdate::fmt_http_date(last_modified))
         .header(header::ETAG, &etag);
     add_cors_headers(req, &mut response, &ctx.config.http);
+    add_client_hints(&mut response, &ctx.config.http);
+    let cache_rule = ctx.config.http.cache_rules.iter()
+        .find(|rule| util::glob_match(&rule.pattern, path.suffix()));
+    if let Some(rule) = cache_rule {
+        response = response.header(header::CACHE_CONTROL, &rule.value);
+    }
+
+    // Reply with 304 as early as possible. The file is opened, but never
+    // read or seeked. `Content-Type` and the other headers below (including
+    // `http.type_headers`) are not needed for 304 (RFC 9110, section 15.4.5).
+    if is_unmodified(req.headers(), &etag, last_modified) {
+        return response.status(StatusCode::NOT_MODIFIED).body(Body::Empty).unwrap();
+    }
+
     if let Some(mime) = mime_guess::from_path(&fs_path).first() {
-        response = response.header(header::CONTENT_TYPE, mime.to_string());
+        let mut value = mime.to_string();
+        if ctx.config.http.charset_types.iter().any(|pattern| util::glob_match(pattern, &value)) {
+            value.push_str("; charset=utf-8");
+        }
+        response = response.header(header::CONTENT_TYPE, value);
+        let type_headers = ctx.config.http.type_headers.iter()
+            .filter(|rule| util::glob_match(&rule.content_type, mime.essence_str()));
+        for rule in type_headers {
+            response = response.header(&rule.name, &rule.value);
+        }
+
+        let is_manifest = mime.essence_str().ends_with("mpegurl");
+        let small_enough = file_size <= PRELOAD_MAX_MANIFEST_SIZE;
+        if ctx.config.http.early_hints && is_manifest && small_enough {
+            match tokio::fs::read_to_string(&fs_path).await {
+                Ok(manifest) => {
+                    let links = preload_links(&manifest).collect::<Vec<_>>();
+                    let hints = req.extensions().get::<EarlyHints>();
+                    if let Some(hints) = hints && req.version() == Version::HTTP_11 {
+                        hints.send(&links);
+                    }
+                    for link in links {
+                        response = response.header(header::LINK, link);
+                    }
+                }
+                Err(e) => debug!(?fs_path, "could not read HLS manifest for early hints: {e}"),
+            }
+        }
     }
 
     // If the `download=1` parameter is set, we add a header to make browsers
@@ -76,13 +265,33 @@ pub async fn serve_file(
         response = response.header(header::CONTENT_DISPOSITION, "attachment");
     }
 
-    if is_unmodified(req.headers(), &etag, mtime) {
-        return response.status(StatusCode::NOT_MODIFIED).body(Body::Empty).unwrap();
+    // The digest is always over the whole file, also for range requests.
+    if ctx.config.http.repr_digest_max_size.is_some_and(|max| file_size <= max) {
+        match tokio::fs::read(&fs_path).await {
+            Ok(content) => {
+                let digest = STANDARD.encode(Sha256::digest(&content));
+                response = response.header(REPR_DIGEST, format!("sha-256=:{digest}:"));
+            }
+            Err(e) => debug!(?fs_path, "could not read file for `Repr-Digest`: {e}"),
+        }
     }
 
-    // Check if this is a `Range` request.
-    let body = if let Some(range_header) = req.headers().get(header::RANGE) {
-        let range = match HttpRange::parse_bytes(range_header.as_bytes(), file_size) {
+    // Check if this is a `Range` request. If `If-Range` does not match the
+    // current file or the range unit is not `bytes`, the range is ignored and
+    // the full file is sent. Ranges always refer to the bytes of the file as
+    // stored: files are never sent with `Content-Encoding`, so compressed
+    // files (e.g. `.gz`) are served as they are, with ranges over the
+    // compressed bytes.
+    let range_header = req.headers().get(header::RANGE)
+        .filter(|_| if_range_matches(req.headers(), &etag, last_modified));
+    let byte_ranges = range_header.and_then(byte_range_set);
+    if let (Some(range_header), None) = (range_header, byte_ranges) {
+        debug!(?range_header, path = path.full_path(), "ignoring range with unknown unit");
+    }
+    let body = if let (Some(range_header), Some(byte_ranges)) = (range_header, byte_ranges) {
+        // `http_range` only accepts the unit in lowercase.
+        let normalized = [b"bytes=".as_slice(), byte_ranges].concat();
+        let range = match HttpRange::parse_bytes(&normalized, file_size) {
             Ok(ranges) if ranges.len() == 1 => ranges[0],
             Ok(_) => {
                 debug!(?range_header, path= path.full_path(), "received multi range request");
@@ -98,10 +307,17 @@ pub async fn serve_file(
             Err(HttpRangeParseError::NoOverlap) => {
                 debug!(?range_header, file_size, path = path.full_path(),
                     "unsatisfiable `Range` header");
-                return super::error_response(StatusCode::RANGE_NOT_SATISFIABLE);
+                // RFC 9110, section 14.4: the current length has to be sent.
+                let mut response = super::error_response(StatusCode::RANGE_NOT_SATISFIABLE);
+                let content_range = format!("bytes */{file_size}").try_into()
+                    .expect("bug: invalid header value");
+                response.headers_mut().insert(header::CONTENT_RANGE, content_range);
+                return response;
             }
         };
 
+        // For `HEAD` requests, this results in the same `206` headers as for
+        // `GET`. The body is stripped in `handle_wrapper`.
         handle_io_err!(
             file.seek(std::io::SeekFrom::Start(range.start)).await,
             "seeking in file"
@@ -116,23 +332,110 @@ pub async fn serve_file(
                 file_size,
             ));
 
-        make_file_body(file.take(range.length))
+        let deadline = response_deadline(range.length, &ctx.config.http);
+        make_file_body(file.take(range.length), deadline, permit)
     } else {
+        // The file might still be written to (e.g. for near-live playback),
+        // so we only send as many bytes as announced in `Content-Length`.
+        // Ranges are limited the same way above.
         response = response.header(header::CONTENT_LENGTH, file_size);
-        make_file_body(file)
+        let deadline = response_deadline(file_size, &ctx.config.http);
+        make_file_body(file.take(file_size), deadline, permit)
     };
 
     response.body(Body::File(body)).expect("invalid response")
 }
 
+/// Returns `Link` header values preloading the first segments referenced in
+/// the given HLS manifest. URIs that are not valid in a header are skipped.
+fn preload_links(manifest: &str) -> impl Iterator<Item = HeaderValue> + '_ {
+    manifest.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .take(PRELOAD_SEGMENTS)
+        .map(|uri| format!("<{uri}>; rel=preload; as=fetch; crossorigin"))
+        .filter_map(|link| HeaderValue::try_from(link).ok())
+}
+
+/// Serves a small local file that is not part of the Opencast downloads, e.g.
+/// `robots.txt`. The file is read into memory completely. `response` can be
+/// used to pass additional headers.
+pub async fn serve_static_file(fs_path: &Path, response: http::response::Builder) -> Response {
+    let content = match tokio::fs::read(fs_path).await {
+        Ok(v) => v,
+        Err(e) => return handle_io_error(&e, "reading static file"),
+    };
+
+    let mut response = response.header(header::CONTENT_LENGTH, content.len());
+    if let Some(mime) = mime_guess::from_path(fs_path).first() {
+        response = response.header(header::CONTENT_TYPE, mime.to_string());
+    }
+    response.body(Body::Tiny(Full::new(content.into()))).expect("invalid response")
+}
+
 /// `Body` to stream a file as HTTP response.
 pub(super) type FileBody = BoxBody<Bytes, std::io::Error>;
 
-fn make_file_body<R: AsyncRead + Send + Sync + 'static>(reader: R) -> FileBody {
-    let reader = tokio_util::io::ReaderStream::new(reader)
+fn make_file_body<R>(
+    reader: R,
+    deadline: Duration,
+    permit: Option<OwnedSemaphorePermit>,
+) -> FileBody
+where
+    R: AsyncRead + Send + Sync + 'static,
+{
+    let reader = tokio_util::io::ReaderStream::with_capacity(reader, FILE_CHUNK_SIZE)
         .map_ok(hyper::body::Frame::data);
     let body = http_body_util::StreamBody::new(reader);
-    BoxBody::new(body)
+    BoxBody::new(DeadlineBody {
+        inner: BoxBody::new(body),
+        sleep: Box::pin(tokio::time::sleep(deadline)),
+        deadline,
+        _permit: permit,
+    })
+}
+
+/// Returns the deadline for sending a response body of the given length.
+fn response_deadline(length: u64, config: &super::HttpConfig) -> Duration {
+    config.response_timeout + Duration::from_secs(length / MIN_TRANSFER_RATE)
+}
+
+/// Body wrapper that fails with `TimedOut` once the deadline has passed,
+/// which makes hyper close the connection.
+struct DeadlineBody {
+    inner: FileBody,
+    sleep: Pin<Box<Sleep>>,
+    deadline: Duration,
+
+    /// Held until the body is dropped, see `http.max_open_files`.
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl hyper::body::Body for DeadlineBody {
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.sleep.as_mut().poll(cx).is_ready() {
+            debug!(deadline = ?self.deadline, "response deadline exceeded -> aborting response");
+            return Poll::Ready(Some(Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "response deadline exceeded",
+            ))));
+        }
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
 }
 
 fn handle_io_error(e: &std::io::Error, action: &str) -> Response {
@@ -181,6 +484,9 @@ fn handle_io_error(e: &std::io::Error, action: &str) -> Response {
 ///
 /// Of course, hashing the actual file content would be best, but is not viable
 /// for the huge files we are dealing with.
+///
+/// The value only contains digits, `:` and `-` inside the quotes, so reverse
+/// proxies pass it through unchanged (or only weaken it when compressing).
 fn etag(mtime: SystemTime, size: u64, metadata: &std::fs::Metadata) -> String {
     // On unix, we also add the inode number to the ETag. It can protect against
     // "content change, same etag" situations when a new file is moved to the
@@ -209,12 +515,50 @@ fn etag(mtime: SystemTime, size: u64, metadata: &std::fs::Metadata) -> String {
     out
 }
 
+/// Truncates the given time to whole seconds.
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => SystemTime::UNIX_EPOCH + Duration::from_secs(duration.as_secs()),
+        Err(_) => time,
+    }
+}
+
+/// Returns the range set of a `Range` header (everything after `=`) if its
+/// unit is `bytes`. Range units are case-insensitive and unknown units have to
+/// be ignored (RFC 9110, section 14.2).
+fn byte_range_set(range_header: &HeaderValue) -> Option<&[u8]> {
+    let value = range_header.as_bytes();
+    let (unit, ranges) = value.split_at(value.iter().position(|b| *b == b'=')?);
+    unit.trim_ascii().eq_ignore_ascii_case(b"bytes").then_some(&ranges[1..])
+}
+
+/// Checks the `If-Range` header and returns whether a `Range` header should be
+/// honored. `last_modified` has to be truncated to whole seconds.
+fn if_range_matches(headers: &HeaderMap, etag: &str, last_modified: SystemTime) -> bool {
+    let Some(if_range) = headers.get(header::IF_RANGE) else {
+        return true;
+    };
+
+    // Entity tags require a strong comparison, so weak tags never match.
+    // Dates have to match `Last-Modified` exactly. Invalid values never match,
+    // resulting in the full file being sent, which is always correct.
+    let if_range = if_range.as_bytes().trim_ascii();
+    if if_range.starts_with(b"\"") || if_range.starts_with(b"W/") {
+        if_range == etag.as_bytes()
+    } else {
+        str::from_utf8(if_range).ok()
+            .and_then(|s| httpdate::parse_http_date(s).ok())
+            .is_some_and(|date| date == last_modified)
+    }
+}
+
 /// Checks `If-None-Match` and `If-Modified-Since` headers and returns whether
-/// we should reply with "304 Unmodified".
+/// we should reply with "304 Unmodified". `last_modified` has to be truncated
+/// to whole seconds.
 fn is_unmodified(
     headers: &HeaderMap,
     etag: &str,
-    mtime: SystemTime,
+    last_modified: SystemTime,
 ) -> bool {
     // The `If-None-Match` header has priority over `If-Unmodified-Since`.
     if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
@@ -239,9 +583,37 @@ fn is_unmodified(
         .and_then(|v| v.to_str().ok())
         .and_then(|s| httpdate::parse_http_date(s.trim()).ok());
     if let Some(if_modified_since) = if_modified_since {
-        return mtime < if_modified_since;
+        return last_modified <= if_modified_since;
     }
 
     // If no header is present, we reply with normal 200.
     false
 }
+
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt as _;
+    use tokio::io::AsyncWriteExt as _;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn slow_body_is_aborted_after_deadline() {
+        // Reader that sends one chunk and then stalls.
+        let (mut writer, reader) = tokio::io::duplex(64);
+        writer.write_all(b"first chunk").await.unwrap();
+        let body = make_file_body(reader, Duration::from_millis(100), None);
+
+        let err = body.collect().await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        drop(writer);
+    }
+
+    #[tokio::test]
+    async fn fast_body_is_not_aborted() {
+        let body = make_file_body(&b"all at once"[..], Duration::from_millis(100), None);
+        let bytes = body.collect().await.unwrap().to_bytes();
+        assert_eq!(bytes, "all at once");
+    }
+}
@@ -0,0 +1,210 @@
+//! Sending `103 Early Hints` (RFC 8297), see `http.early_hints`.
+//!
+//! hyper cannot send informational responses from the server side, so they
+//! are written to the connection directly by `Io`, right before the final
+//! response. That is only safe if none of the previous responses on that
+//! connection is still (partly) buffered in hyper. Otherwise, the hints
+//! would end up in the middle of another response. To know that, each
+//! response body is wrapped in `TrackedBody`, which counts finished responses,
+//! and `Io` remembers how many of those were completely flushed.
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, ready},
+};
+
+use hyper::{
+    body::{Body, Frame, SizeHint},
+    header::HeaderValue,
+    rt::{Read, ReadBufCursor, Write},
+};
+
+use crate::prelude::*;
+
+
+/// State shared by everything belonging to one connection.
+#[derive(Default)]
+pub(super) struct Connection {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Number of requests received on this connection.
+    requests: u64,
+
+    /// Number of responses that hyper is done with, i.e. that are completely
+    /// in its write buffer or already written.
+    done: u64,
+
+    /// Number of responses that were completely written and flushed.
+    flushed: u64,
+
+    /// Raw informational responses that still have to be written.
+    pending: Vec<u8>,
+}
+
+impl Connection {
+    /// Returns the handle for the next request, to be stored in its
+    /// extensions.
+    pub(super) fn next_request(self: &Arc<Self>) -> EarlyHints {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.requests;
+        state.requests += 1;
+        EarlyHints { conn: Arc::clone(self), seq }
+    }
+
+    /// Wraps the body of a response on this connection.
+    pub(super) fn track<B>(self: &Arc<Self>, body: B) -> TrackedBody<B> {
+        TrackedBody { inner: body, conn: Arc::clone(self) }
+    }
+}
+
+/// Handle to send `103 Early Hints` before the response to one request,
+/// stored in the request extensions.
+#[derive(Clone)]
+pub(super) struct EarlyHints {
+    conn: Arc<Connection>,
+
+    /// Index of the request on its connection.
+    seq: u64,
+}
+
+impl EarlyHints {
+    /// Queues a `103 Early Hints` response with the given `Link` header
+    /// values, to be sent right before the final response. If responses to
+    /// previous (pipelined) requests are not completely written yet, nothing
+    /// is sent: the hints are just an optimization.
+    pub(super) fn send(&self, links: &[HeaderValue]) {
+        let mut state = self.conn.state.lock().unwrap();
+        if state.flushed != self.seq {
+            trace!("previous response still in flight -> not sending 103 Early Hints");
+            return;
+        }
+
+        state.pending.extend_from_slice(b"HTTP/1.1 103 Early Hints\r\n");
+        for link in links {
+            state.pending.extend_from_slice(b"link: ");
+            state.pending.extend_from_slice(link.as_bytes());
+            state.pending.extend_from_slice(b"\r\n");
+        }
+        state.pending.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Response body that counts as done for its connection once it is dropped
+/// by hyper. hyper always encodes the remaining message into its write buffer
+/// before or right after dropping the body.
+pub(super) struct TrackedBody<B> {
+    inner: B,
+    conn: Arc<Connection>,
+}
+
+impl<B> Drop for TrackedBody<B> {
+    fn drop(&mut self) {
+        self.conn.state.lock().unwrap().done += 1;
+    }
+}
+
+impl<B: Body + Unpin> Body for TrackedBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Connection IO that writes queued informational responses before any
+/// other data.
+pub(super) struct Io<T> {
+    inner: T,
+    conn: Arc<Connection>,
+
+    /// Informational responses taken from `State::pending`, but not yet
+    /// completely written.
+    buf: Vec<u8>,
+}
+
+impl<T: Write + Unpin> Io<T> {
+    pub(super) fn new(inner: T, conn: Arc<Connection>) -> Self {
+        Self { inner, conn, buf: Vec::new() }
+    }
+
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if self.buf.is_empty() {
+                std::mem::swap(&mut self.buf, &mut self.conn.state.lock().unwrap().pending);
+                if self.buf.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.buf.drain(..n);
+        }
+    }
+}
+
+impl<T: Read + Unpin> Read for Io<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: Write + Unpin> Write for Io<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_write_pending(cx))?;
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_write_pending(cx))?;
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+        let mut state = self.conn.state.lock().unwrap();
+        if self.buf.is_empty() && state.pending.is_empty() {
+            state.flushed = state.done;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
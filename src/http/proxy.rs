@@ -0,0 +1,212 @@
+//! Simple reverse proxy forwarding requests to Opencast.
+
+use std::{io, time::Duration};
+
+use http_body_util::{BodyExt as _, StreamBody, combinators::BoxBody};
+use hyper::{
+    HeaderMap, Method, Request, StatusCode,
+    body::{Bytes, Frame, Incoming},
+    header::{self, HeaderName, HeaderValue},
+};
+
+use super::{Body, ClientAddr, Context, Response, digest::DigestCheck};
+use crate::{prelude::*, util::EmptyHttpBody};
+
+
+/// How long to wait for Opencast's response headers.
+pub(super) const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Headers that are only meaningful for a single connection and must not be
+/// forwarded by proxies. See RFC 9110, section 7.6.1.
+const HOP_BY_HOP_HEADERS: [HeaderName; 8] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+
+/// Forwards the request to Opencast (same method, path, query and headers) and
+/// returns Opencast's response. The response body is streamed through.
+pub(super) async fn handle(req: &Request<Incoming>, ctx: &Context) -> Response {
+    let uri = ctx.config.opencast.host.clone()
+        .with_path_and_query(req.uri().path_and_query().unwrap().clone());
+    trace!(uri = super::loggable_uri(&uri, &ctx.config.http), "proxying request to OC");
+
+    let mut oc_req = Request::builder()
+        .uri(uri)
+        .method(req.method().clone())
+        .body(EmptyHttpBody::new())
+        // There should be no reason building this request can fail.
+        .expect("failed to build request for OC");
+    *oc_req.headers_mut() = req.headers().clone();
+    remove_hop_by_hop_headers(oc_req.headers_mut());
+    add_forwarded_headers(oc_req.headers_mut(), req);
+
+    let response = tokio::select! {
+        res = ctx.oc_client.request(oc_req) => match res {
+            Ok(r) => r,
+            Err(e) => {
+                debug!("error sending proxied request to OC: {e}");
+                return super::error_response(StatusCode::BAD_GATEWAY);
+            }
+        },
+        _ = tokio::time::sleep(TIMEOUT) => {
+            debug!("timeout waiting for OC to respond to proxied request");
+            return super::error_response(StatusCode::GATEWAY_TIMEOUT);
+        }
+    };
+
+    trace!(status = ?response.status(), "OC replied to proxied request");
+    forward_response(response, req, ctx)
+}
+
+/// Turns Opencast's `response` to a request forwarded for `req` into the
+/// response for the client. The body is streamed through.
+pub(super) fn forward_response<B>(
+    response: hyper::Response<B>,
+    req: &Request<Incoming>,
+    ctx: &Context,
+) -> Response
+where
+    B: hyper::body::Body<Data = Bytes> + Unpin + Send + Sync + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let max_size = ctx.config.http.proxy_max_body_size;
+    let content_length = response.headers().get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let (Some(max_size), Some(len)) = (max_size, content_length) && len > max_size {
+        warn!(len, max_size, "proxied response from OC too large -> 502 Bad Gateway");
+        return super::error_response(StatusCode::BAD_GATEWAY);
+    }
+
+    // All end-to-end headers not listed in `strip_response_headers` are passed
+    // through unmodified. This notably includes cache-related headers like
+    // `Age`, `Cache-Control`, `Expires` and `ETag`, so that downstream caches
+    // behave as intended by Opencast.
+    let (mut parts, body) = response.into_parts();
+    remove_hop_by_hop_headers(&mut parts.headers);
+    for name in &ctx.config.http.strip_response_headers {
+        parts.headers.remove(name.as_str());
+    }
+    // Only full bodies can be checked, see `http.verify_upstream_digests`.
+    let check_digest = ctx.config.http.verify_upstream_digests
+        && req.method() != Method::HEAD
+        && parts.status == StatusCode::OK;
+    let digest = check_digest.then(|| DigestCheck::from_headers(&parts.headers)).flatten();
+    let body = limit_body(body, ctx.config.http.proxy_read_timeout, max_size, digest);
+    Response::from_parts(parts, Body::Stream(body))
+}
+
+/// Wraps `body` such that it fails if receiving a frame takes longer than
+/// `read_timeout`, if it is larger than `max_size` or if it does not match
+/// `digest`. hyper then aborts the response to the client.
+fn limit_body<B>(
+    body: B,
+    read_timeout: Duration,
+    max_size: Option<u64>,
+    digest: Option<DigestCheck>,
+) -> BoxBody<Bytes, io::Error>
+where
+    B: hyper::body::Body<Data = Bytes> + Unpin + Send + Sync + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let init = Some((body, 0, digest, None::<Frame<Bytes>>));
+    let frames = futures::stream::unfold(init, move |state| async move {
+        let (mut body, mut size, mut digest, mut held_back) = state?;
+        loop {
+            let frame = match tokio::time::timeout(read_timeout, body.frame()).await {
+                Ok(None) => {
+                    if let Some(Err(e)) = digest.map(DigestCheck::finish) {
+                        warn!("{e} -> aborting proxied response");
+                        return Some((Err(io::Error::other(e)), None));
+                    }
+                    return held_back.map(|frame| (Ok(frame), None));
+                }
+                Ok(Some(Ok(frame))) => frame,
+                Ok(Some(Err(e))) => return Some((Err(io::Error::other(e)), None)),
+                Err(_) => {
+                    warn!(
+                        ?read_timeout,
+                        "timeout reading proxied response body from OC -> aborting",
+                    );
+                    let e = io::Error::new(io::ErrorKind::TimedOut, "timeout reading OC response");
+                    return Some((Err(e), None));
+                }
+            };
+
+            if let Some(data) = frame.data_ref() {
+                size += data.len() as u64;
+                if let Some(digest) = &mut digest {
+                    digest.update(data);
+                }
+            }
+            if max_size.is_some_and(|max| size > max) {
+                warn!(max_size, "proxied response body from OC too large -> aborting");
+                return Some((Err(io::Error::other("OC response too large")), None));
+            }
+
+            // When checking the digest, the latest frame is held back until
+            // the next one arrives. That way, the client never receives the
+            // complete body if the digest does not match.
+            if digest.is_none() {
+                return Some((Ok(frame), Some((body, size, digest, None))));
+            }
+            if let Some(previous) = held_back.replace(frame) {
+                return Some((Ok(previous), Some((body, size, digest, held_back))));
+            }
+        }
+    });
+    StreamBody::new(frames).boxed()
+}
+
+pub(super) fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    // Headers listed in `Connection` are hop-by-hop as well.
+    let listed: Vec<HeaderName> = headers.get_all(header::CONNECTION)
+        .iter()
+        .flat_map(|v| v.as_bytes().split(|b| *b == b','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim_ascii()).ok())
+        .collect();
+
+    for name in HOP_BY_HOP_HEADERS.iter().chain(&listed) {
+        headers.remove(name);
+    }
+}
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// Adds `X-Forwarded-*` headers describing `req` to `headers` of a request to
+/// Opencast, so that Opencast sees the original client. The client address is
+/// appended to an existing `X-Forwarded-For` list. `X-Forwarded-Proto` and
+/// `X-Forwarded-Host` are only set if `req` did not already have them (e.g.
+/// set by a reverse proxy in front of octoka, which knows better).
+pub(super) fn add_forwarded_headers(headers: &mut HeaderMap, req: &Request<Incoming>) {
+    if let Some(ClientAddr(addr)) = req.extensions().get::<ClientAddr>() {
+        let ip = addr.ip().to_canonical().to_string();
+        let mut list = req.headers().get_all(&X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>();
+        list.push(&ip);
+        let value = HeaderValue::try_from(list.join(", "))
+            .expect("joined header values are not a valid header value");
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+
+    if !headers.contains_key(&X_FORWARDED_PROTO) {
+        // octoka itself only speaks plain HTTP.
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("http"));
+    }
+    if !headers.contains_key(&X_FORWARDED_HOST)
+        && let Some(host) = req.headers().get(header::HOST)
+    {
+        headers.insert(X_FORWARDED_HOST, host.clone());
+    }
+}
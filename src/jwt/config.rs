@@ -1,21 +1,55 @@
-use std::{fmt, net::IpAddr, time::Duration};
+use std::{collections::HashMap, fmt, net::IpAddr, num::NonZeroUsize, time::Duration};
 
 use hyper::Uri;
 
 use super::keys::BACKGROUND_REFRESH_LEAD_TIME;
 
 
+/// `key_cache_duration` values above this cause a warning on startup.
+const KEY_CACHE_DURATION_WARN_THRESHOLD: Duration = Duration::from_secs(24 * 60 * 60);
+
+
 #[derive(Debug, Clone, confique::Config)]
 #[config(validate = Self::validate)]
 pub struct JwtConfig {
     /// List of URLs to a JWKS containing public keys used for verifying JWT
     /// signatures. IMPORTANT: this is where the trust of the whole operation
     /// stems from! Only specify URLs to services that you fully trust to give
-    /// access to Opencast resources.
+    /// access to Opencast resources. Keys whose `key_ops` member does not
+    /// include "verify" are ignored.
     ///
-    /// Example: ["https://tobira.example.com/.well-known/jwks.json"]
+    /// Instead of a URL, an entry can also be a table with the `url` and
+    /// optionally `key_cache_duration` and `background_key_refresh`, which
+    /// override the global settings for that source.
+    ///
+    /// Example: [
+    ///     "https://tobira.example.com/.well-known/jwks.json",
+    ///     { url = "https://idp.example.com/jwks.json", key_cache_duration = "1h" },
+    /// ]
     #[config(validate = validate_trusted_keys)]
-    pub trusted_keys: Vec<JwksUrl>,
+    pub trusted_keys: Vec<TrustedKeySource>,
+
+    /// By default, `trusted_keys` URLs have to use HTTPS, except for
+    /// `localhost` and loopback addresses. If `true`, HTTPS is required for
+    /// those as well, e.g. for hardened setups where local traffic is not
+    /// trusted either.
+    #[config(default = false)]
+    pub require_https_jwks: bool,
+
+    /// Maps `kid` prefixes to one of the `trusted_keys` URLs. When a JWT
+    /// cannot be verified with the known keys, octoka refetches JWKS URLs in
+    /// case a key was rotated. If the JWT's `kid` starts with a prefix listed
+    /// here, only the mapped URL is refetched in that case, avoiding needless
+    /// requests to unrelated sources. The longest matching prefix wins.
+    ///
+    /// Example: { "tobira-" = "https://tobira.example.com/.well-known/jwks.json" }
+    #[config(default = {})]
+    pub kid_source_hints: HashMap<String, JwksUrl>,
+
+    /// Maximum number of JWKS URLs that are fetched at the same time. Only
+    /// relevant with many `trusted_keys`, to avoid bursts of requests.
+    #[config(default = 8)]
+    pub max_concurrent_fetches: NonZeroUsize,
 
     /// Whether to regularly refetch `trusted_keys`. If `false`, they are
     /// refetched on-the-fly if stale when handling an incoming request, slowing
@@ -23,11 +57,98 @@ pub struct JwtConfig {
     #[config(default = true)]
     pub background_key_refresh: bool,
 
+    /// If `true`, all `trusted_keys` are fetched on startup before accepting
+    /// requests, and startup fails if any of them does not yield a single
+    /// usable key (e.g. because the JWKS is empty, only contains unsupported
+    /// keys or could not be fetched). This catches misconfigurations early.
+    /// If `false`, such sources only cause a warning.
+    #[config(default = false)]
+    pub fail_on_empty_jwks: bool,
+
     /// For how long keys fetched from JWKS URLs are considered valid. After
     /// this time, they are considered stale and won't be used anymore.
+    ///
+    /// Security tradeoff: this is also the time it can take until a key that
+    /// was removed from a JWKS (e.g. because it was compromised) stops being
+    /// trusted by octoka. Longer durations reduce the number of requests to
+    /// the JWKS URLs, but revoked keys stay trusted longer. A warning is
+    /// emitted for values longer than one day.
+    ///
+    /// Keys with a (nonstandard) `exp` member containing a Unix timestamp
+    /// expire individually at that time, even within this duration.
     #[config(default = "10min", deserialize_with = crate::config::deserialize_duration)]
     pub key_cache_duration: Duration,
 
+    /// Upper limit for `key_cache_duration`. Configs exceeding it are
+    /// rejected.
+    #[config(default = "7d", deserialize_with = crate::config::deserialize_duration)]
+    pub max_key_cache_duration: Duration,
+
+    /// For how long keys that were removed from their JWKS (e.g. due to key
+    /// rotation) are still used to verify JWTs, as a last resort. Each such
+    /// use is logged as warning. This avoids failures for JWTs that were
+    /// signed shortly before the rotation.
+    ///
+    /// Security tradeoff: a key removed because it was compromised also stays
+    /// trusted for this long. `0s` disables this.
+    #[config(default = "0s", deserialize_with = crate::config::deserialize_duration)]
+    pub removed_key_grace: Duration,
+
+    /// If set, only JWTs signed with one of these algorithms (`alg` header)
+    /// are accepted, even if a key for another algorithm exists. Example:
+    /// `["EdDSA"]`. If unset, all supported algorithms are accepted.
+    #[config(validate = validate_allowed_algorithms)]
+    pub allowed_algorithms: Option<Vec<String>>,
+
+    /// If set, the `typ` header of JWTs must be present and equal this value
+    /// (case-insensitive). This prevents tokens intended for other purposes
+    /// (e.g. `at+jwt`) from being accepted. If unset, any `typ` is accepted.
+    /// Recommended value: "JWT".
+    pub expected_typ: Option<String>,
+
+    /// Enables a stricter validation mode, turning several lenient behaviors
+    /// into hard rejections. If `true`:
+    ///
+    /// - The `typ` header of JWTs must be present and equal "JWT" (or
+    ///   `expected_typ`, if set).
+    /// - The `oc` claim must only contain well-formed items of known kinds
+    ///   (`e:`, `s:` and `p:`).
+    /// - If a JWT has a `kid` header, it must match a known key. Otherwise,
+    ///   keys without ID are tried as well.
+    /// - Non-UTF8 values of headers listed in `http.jwt_sources` lead to the
+    ///   request being denied instead of the header being ignored.
+    #[config(default = false)]
+    pub strict: bool,
+
+    /// If `true`, JWTs must be sender-constrained via DPoP (RFC 9449): they
+    /// need a `cnf` claim containing a `jkt` (key thumbprint), and requests
+    /// must carry a `DPoP` header with a proof signed by that key. The proof's
+    /// `htm` must match the request method and the path of its `htu` must
+    /// match the request path. Its `ath` must be the hash of the JWT. Scheme
+    /// and host of `htu` are not checked, as octoka usually runs behind a
+    /// reverse proxy. Proofs older than 5 minutes are rejected, but `jti`
+    /// values are not tracked, so proofs can be replayed within that window.
+    ///
+    /// If `false`, JWTs without `cnf` claim are accepted as well. JWTs with a
+    /// `cnf.jkt` still need a valid proof, as the issuer bound them to a key.
+    /// Certificate-bound tokens (`cnf` with `x5t#S256`) are not supported, as
+    /// octoka does not terminate TLS.
+    #[config(default = false)]
+    pub require_cnf: bool,
+
+    /// If `true`, JWTs with an unencoded payload (RFC 7797) are accepted,
+    /// i.e. with the headers `"b64": false` and `"crit": ["b64"]`. The
+    /// signature of those is computed over the raw payload instead of its
+    /// base64url encoding. Only enable this if an issuer requires it.
+    #[config(default = false)]
+    pub allow_unencoded_payload: bool,
+
+    /// Maximum number of entries in the `oc` claim of a JWT. Tokens with more
+    /// entries are rejected. This limits the work and memory a single
+    /// (possibly malicious) token can cause per request.
+    #[config(default = 10_000)]
+    pub max_oc_entries: usize,
+
     /// When checking `exp` and `nbf`, allow this amount of leeway to account
     /// for possible clock skew.
     #[config(default = "3s", deserialize_with = crate::config::deserialize_duration)]
@@ -44,8 +165,154 @@ impl JwtConfig {
             ));
         }
 
+        if self.key_cache_duration > self.max_key_cache_duration {
+            return Err(format!(
+                "`key_cache_duration` ({:?}) exceeds `max_key_cache_duration` ({:?})",
+                self.key_cache_duration,
+                self.max_key_cache_duration,
+            ));
+        }
+
+        for src in self.trusted_keys.iter()
+            .filter(|src| src.key_cache_duration.is_some() || src.background_key_refresh.is_some())
+        {
+            let duration = self.key_cache_duration_for(&src.url);
+            if self.background_key_refresh_for(&src.url) && duration < min_duration {
+                return Err(format!(
+                    "`key_cache_duration` of {} too short for `background_key_refresh`; \
+                        should be at least {min_duration:.2?}",
+                    src.url,
+                ));
+            }
+            if duration > self.max_key_cache_duration {
+                return Err(format!(
+                    "`key_cache_duration` of {} ({duration:?}) exceeds \
+                        `max_key_cache_duration` ({:?})",
+                    src.url,
+                    self.max_key_cache_duration,
+                ));
+            }
+        }
+
+        if self.require_https_jwks
+            && let Some(src) = self.trusted_keys.iter()
+                .find(|src| src.url.0.scheme() != Some(&hyper::http::uri::Scheme::HTTPS))
+        {
+            return Err(format!(
+                "{} does not use HTTPS, but `require_https_jwks` is enabled",
+                src.url,
+            ));
+        }
+
+        if let Some(url) = self.kid_source_hints.values()
+            .find(|url| !self.trusted_keys.iter().any(|src| src.url == **url))
+        {
+            return Err(format!(
+                "`kid_source_hints` refers to {url}, which is not in `trusted_keys`",
+            ));
+        }
+
         Ok(())
     }
+
+    /// Returns the source hinted for `kid` via `kid_source_hints`, if any.
+    pub(super) fn hinted_source(&self, kid: &str) -> Option<&JwksUrl> {
+        self.kid_source_hints.iter()
+            .filter(|(prefix, _)| kid.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, url)| url)
+    }
+
+    /// Returns the source configured in `trusted_keys` for `url`, if any.
+    fn source(&self, url: &JwksUrl) -> Option<&TrustedKeySource> {
+        self.trusted_keys.iter().find(|src| src.url == *url)
+    }
+
+    /// Returns the `key_cache_duration` for the given source, taking the
+    /// per-source override into account.
+    pub(super) fn key_cache_duration_for(&self, url: &JwksUrl) -> Duration {
+        self.source(url)
+            .and_then(|src| src.key_cache_duration)
+            .unwrap_or(self.key_cache_duration)
+    }
+
+    /// Returns whether the given source is refreshed in the background,
+    /// taking the per-source override into account.
+    pub(super) fn background_key_refresh_for(&self, url: &JwksUrl) -> bool {
+        self.source(url)
+            .and_then(|src| src.background_key_refresh)
+            .unwrap_or(self.background_key_refresh)
+    }
+
+    /// Returns a warning message if `key_cache_duration` (global or of any
+    /// source) is suspiciously long.
+    pub(super) fn key_cache_duration_warning(&self) -> Option<String> {
+        let longest = self.trusted_keys.iter()
+            .filter_map(|src| src.key_cache_duration)
+            .fold(self.key_cache_duration, Duration::max);
+        (longest > KEY_CACHE_DURATION_WARN_THRESHOLD).then(|| format!(
+            "`jwt.key_cache_duration` is very long ({longest:?}): keys removed from a JWKS \
+                will be trusted for up to that long",
+        ))
+    }
+}
+
+/// An entry of `trusted_keys`: a JWKS URL with optional per-source overrides.
+/// Deserialized from either a string (just the URL) or a table.
+#[derive(Debug, Clone)]
+pub struct TrustedKeySource {
+    pub url: JwksUrl,
+
+    /// Overrides `jwt.key_cache_duration` for this source.
+    pub key_cache_duration: Option<Duration>,
+
+    /// Overrides `jwt.background_key_refresh` for this source.
+    pub background_key_refresh: Option<bool>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TrustedKeySourceTable {
+    url: JwksUrl,
+    #[serde(default, deserialize_with = "crate::config::deserialize_opt_duration")]
+    key_cache_duration: Option<Duration>,
+    background_key_refresh: Option<bool>,
+}
+
+impl<'de> serde::Deserialize<'de> for TrustedKeySource {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::{self, value::MapAccessDeserializer};
+
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = TrustedKeySource;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a JWKS URL or a table with `url` field")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(TrustedKeySource {
+                    url: JwksUrl::try_from(v.to_owned()).map_err(E::custom)?,
+                    key_cache_duration: None,
+                    background_key_refresh: None,
+                })
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                let table: TrustedKeySourceTable =
+                    de::Deserialize::deserialize(MapAccessDeserializer::new(map))?;
+                Ok(TrustedKeySource {
+                    url: table.url,
+                    key_cache_duration: table.key_cache_duration,
+                    background_key_refresh: table.background_key_refresh,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Hash, serde::Deserialize)]
@@ -78,8 +345,21 @@ impl TryFrom<String> for JwksUrl {
             _ => {}
         }
 
+        // Normalize the host, so that equivalent URLs are equal: DNS names
+        // are case-insensitive and a trailing dot does not change the host.
+        let host = uri.host().unwrap().trim_end_matches('.').to_ascii_lowercase();
+        if host.is_empty() {
+            return Err("must have a host".into());
+        }
+        let authority = match uri.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.clone(),
+        };
+        let mut parts = uri.into_parts();
+        parts.authority = Some(authority.parse().map_err(|e| format!("invalid host: {e}"))?);
+        let uri = Uri::from_parts(parts).map_err(|e| format!("invalid URI: {e}"))?;
+
         // Non-local URLs must use HTTPS
-        let host = uri.host().unwrap();
         let is_local = host == "localhost"
             || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback());
         if !is_local && uri.scheme() != Some(&hyper::http::uri::Scheme::HTTPS) {
@@ -95,8 +375,129 @@ impl TryFrom<String> for JwksUrl {
     }
 }
 
-fn validate_trusted_keys(keys: &Vec<JwksUrl>) -> Result<(), &'static str> {
+#[allow(clippy::ptr_arg, reason = "confique passes the field by reference")]
+fn validate_allowed_algorithms(algs: &Vec<String>) -> Result<(), &'static str> {
+    const KNOWN: &[&str] = &[
+        "EdDSA", "ES256", "ES384", "ES512", "RS256", "RS384", "RS512", "PS256", "PS384", "PS512",
+    ];
+
+    crate::config::validate_not_empty(algs)?;
+    crate::config::validate_unique(algs)?;
+    if !algs.iter().all(|alg| KNOWN.contains(&alg.as_str())) {
+        return Err("unknown algorithm");
+    }
+    Ok(())
+}
+
+#[allow(clippy::ptr_arg, reason = "confique passes the field by reference")]
+fn validate_trusted_keys(keys: &Vec<TrustedKeySource>) -> Result<(), &'static str> {
     crate::config::validate_not_empty(keys)?;
-    crate::config::validate_unique(keys)?;
+    crate::config::validate_unique(&keys.iter().map(|src| &src.url).collect::<Vec<_>>())?;
     Ok(())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, time::Duration};
+
+    use super::{JwksUrl, JwtConfig, TrustedKeySource};
+
+    fn config(key_cache_duration: Duration) -> JwtConfig {
+        JwtConfig {
+            trusted_keys: vec![],
+            require_https_jwks: false,
+            kid_source_hints: Default::default(),
+            max_concurrent_fetches: NonZeroUsize::new(8).unwrap(),
+            background_key_refresh: true,
+            fail_on_empty_jwks: false,
+            key_cache_duration,
+            max_key_cache_duration: Duration::from_secs(7 * 24 * 60 * 60),
+            removed_key_grace: Duration::ZERO,
+            strict: false,
+            require_cnf: false,
+            allow_unencoded_payload: false,
+            max_oc_entries: 10_000,
+            expected_typ: None,
+            allowed_algorithms: None,
+            allowed_clock_skew: Duration::from_secs(3),
+        }
+    }
+
+    #[test]
+    fn warns_for_long_key_cache_duration() {
+        let warns = |secs| config(Duration::from_secs(secs)).key_cache_duration_warning().is_some();
+        assert!(!warns(600));
+        assert!(!warns(24 * 60 * 60));
+        assert!(warns(3 * 24 * 60 * 60));
+    }
+
+    #[test]
+    fn rejects_key_cache_duration_above_max() {
+        assert!(config(Duration::from_secs(7 * 24 * 60 * 60)).validate().is_ok());
+        assert!(config(Duration::from_secs(8 * 24 * 60 * 60)).validate().is_err());
+    }
+
+    #[test]
+    fn jwks_url_host_is_normalized() {
+        let url = |s: &str| JwksUrl::try_from(s.to_owned()).unwrap();
+        assert_eq!(url("https://Example.com./jwks"), url("https://example.com/jwks"));
+        assert_eq!(url("https://EXAMPLE.com:8443/jwks"), url("https://example.com:8443/jwks"));
+        assert_eq!(url("http://LocalHost./jwks").to_string(), "http://localhost/jwks");
+
+        // Paths stay case-sensitive.
+        assert_ne!(url("https://example.com/JWKS"), url("https://example.com/jwks"));
+        assert!(JwksUrl::try_from("https://./jwks".to_owned()).is_err());
+    }
+
+    #[test]
+    fn per_source_overrides() {
+        #[derive(serde::Deserialize)]
+        struct Wrapper {
+            trusted_keys: Vec<TrustedKeySource>,
+        }
+
+        let parsed: Wrapper = toml::from_str(r#"
+            trusted_keys = [
+                "http://localhost/a.json",
+                { url = "http://localhost/b.json", key_cache_duration = "2s" },
+                { url = "http://localhost/c.json", background_key_refresh = false },
+            ]
+        "#).unwrap();
+        let mut config = config(Duration::from_secs(600));
+        config.trusted_keys = parsed.trusted_keys;
+
+        let [a, b, c] = [0, 1, 2].map(|i| config.trusted_keys[i].url.clone());
+        assert_eq!(config.key_cache_duration_for(&a), Duration::from_secs(600));
+        assert_eq!(config.key_cache_duration_for(&b), Duration::from_secs(2));
+        assert!(config.background_key_refresh_for(&b));
+        assert!(!config.background_key_refresh_for(&c));
+
+        // Too short for background refresh.
+        assert!(config.validate().is_err());
+        config.trusted_keys[1].background_key_refresh = Some(false);
+        assert!(config.validate().is_ok());
+
+        // Same URL validation as for plain strings.
+        let invalid = r#"trusted_keys = [{ url = "http://example.com" }]"#;
+        assert!(toml::from_str::<Wrapper>(invalid).is_err());
+    }
+
+    #[test]
+    fn require_https_jwks() {
+        let mut config = config(Duration::from_secs(600));
+        config.trusted_keys = ["https://example.com/jwks", "http://localhost/jwks"]
+            .map(|url| TrustedKeySource {
+                url: JwksUrl::try_from(url.to_owned()).unwrap(),
+                key_cache_duration: None,
+                background_key_refresh: None,
+            })
+            .into();
+        assert!(config.validate().is_ok());
+
+        config.require_https_jwks = true;
+        assert!(config.validate().is_err());
+        config.trusted_keys.pop();
+        assert!(config.validate().is_ok());
+    }
+}
@@ -0,0 +1,138 @@
+//! Verification of DPoP proofs (RFC 9449), which bind a JWT to a key the
+//! client has to prove possession of. See `JwtConfig::require_cnf`.
+
+use std::{collections::BTreeMap, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use hyper::{Method, Uri};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest as _, Sha256};
+
+use super::TokenError;
+
+
+/// Proofs with an `iat` older than this are rejected.
+const MAX_PROOF_AGE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Deserialize)]
+struct ProofHeader {
+    typ: Option<String>,
+    jwk: Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct ProofClaims {
+    htm: String,
+    htu: String,
+    iat: u64,
+
+    /// Hash of the access token, see `access_token_hash`.
+    ath: Option<String>,
+}
+
+/// Accepts every JWT. Used for proofs, whose claims are checked separately.
+struct NoValidation;
+
+impl<H, P> jwtea::Validator<H, P> for NoValidation {
+    fn validate(
+        &self,
+        _: &jwtea::Header<H>,
+        _: &jwtea::Payload<P>,
+    ) -> Result<(), jwtea::Error> {
+        Ok(())
+    }
+}
+
+/// Checks that `proof` is a valid DPoP proof for a request with the given
+/// method and path, signed by the key with the thumbprint `jkt` and bound to
+/// `access_token` via its `ath` claim.
+///
+/// As octoka usually runs behind a reverse proxy, it cannot reliably know the
+/// scheme and host the client used. So only the path of the `htu` claim is
+/// compared. `jti` values are not tracked, so proofs can be replayed within
+/// `MAX_PROOF_AGE`.
+pub(super) async fn verify(
+    proof: &str,
+    jkt: &str,
+    access_token: &str,
+    method: &Method,
+    path: &str,
+    allowed_clock_skew: Duration,
+) -> Result<(), TokenError> {
+    let err = TokenError::Cnf;
+    let mut parts = proof.split('.');
+    let (Some(header), Some(claims)) = (parts.next(), parts.next()) else {
+        return Err(err("malformed DPoP proof"));
+    };
+    let decode = |part: &str| URL_SAFE_NO_PAD.decode(part).ok();
+    let header: ProofHeader = decode(header)
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or(err("invalid DPoP proof header"))?;
+    let claims: ProofClaims = decode(claims)
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or(err("invalid DPoP proof claims"))?;
+
+    if !header.typ.is_some_and(|typ| typ.eq_ignore_ascii_case("dpop+jwt")) {
+        return Err(err("DPoP proof has wrong `typ` header"));
+    }
+    if header.jwk.contains_key("d") {
+        return Err(err("DPoP proof `jwk` header contains private key"));
+    }
+    if thumbprint(&header.jwk).as_deref() != Some(jkt) {
+        return Err(err("DPoP proof key does not match `cnf.jkt` claim"));
+    }
+
+    // Verify the signature with the key from the header.
+    let jwks = serde_json::json!({ "keys": [header.jwk] }).to_string();
+    let key = jwtea::Jwks::from_str(&jwks)
+        .ok()
+        .and_then(|jwks| jwks.to_verifying_keys().next())
+        .and_then(Result::ok)
+        .ok_or(err("unsupported key in DPoP proof"))?;
+    jwtea::decode::<(), (), ()>(proof, &vec![key], &NoValidation, |_, _| ())
+        .await
+        .map_err(|_| err("invalid DPoP proof signature"))?;
+
+    if claims.htm != method.as_str() {
+        return Err(err("DPoP proof `htm` does not match request method"));
+    }
+    if claims.htu.parse::<Uri>().ok().is_none_or(|htu| htu.path() != path) {
+        return Err(err("DPoP proof `htu` does not match request path"));
+    }
+    if claims.ath.as_deref() != Some(&*access_token_hash(access_token)) {
+        return Err(err("DPoP proof `ath` does not match access token"));
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let iat = Duration::from_secs(claims.iat);
+    if iat > now + allowed_clock_skew || iat + MAX_PROOF_AGE + allowed_clock_skew < now {
+        return Err(err("DPoP proof `iat` too far from current time"));
+    }
+
+    Ok(())
+}
+
+/// Computes the `ath` value for the given access token (RFC 9449, section
+/// 4.2): its SHA-256 hash, base64url encoded.
+fn access_token_hash(access_token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(access_token))
+}
+
+/// Computes the JWK thumbprint (RFC 7638) with SHA-256, base64url encoded.
+fn thumbprint(jwk: &Map<String, Value>) -> Option<String> {
+    let members: &[&str] = match jwk.get("kty")?.as_str()? {
+        "EC" => &["crv", "kty", "x", "y"],
+        "OKP" => &["crv", "kty", "x"],
+        "RSA" => &["e", "kty", "n"],
+        _ => return None,
+    };
+
+    // `BTreeMap` serializes with lexicographically ordered keys and
+    // `serde_json` without whitespace, as required.
+    let canonical = members.iter()
+        .map(|&name| Some((name, jwk.get(name)?.as_str()?)))
+        .collect::<Option<BTreeMap<_, _>>>()?;
+    let json = serde_json::to_string(&canonical).ok()?;
+    Some(URL_SAFE_NO_PAD.encode(Sha256::digest(json)))
+}
@@ -1,10 +1,15 @@
-use std::time::Duration;
+use std::{
+    hash::{DefaultHasher, Hash as _, Hasher as _},
+    time::{Duration, SystemTime},
+};
 
 use bytes::Bytes;
-use jwtea::{Jwks, VerifyingKey};
+use jwtea::{Jwk, VerifyingKey, jwk::{KeyData, KeyOp}};
+use serde::Deserialize;
+use tracing::{debug_span, field::Empty};
 
 use super::Kid;
-use crate::{jwt::JwksUrl, prelude::*, util::SimpleHttpClient};
+use crate::{jwt::JwksUrl, log, prelude::*, util::SimpleHttpClient};
 
 
 const FETCH_TIMEOUT: Duration = Duration::from_secs(20);
@@ -13,15 +18,104 @@ const FETCH_TIMEOUT: Duration = Duration::from_secs(20);
 pub(super) struct FetchedKey {
     pub(super) key: VerifyingKey,
     pub(super) kid: Option<Kid>,
+    pub(super) meta: JwkMeta,
+
+    /// Hash of the public parts of the JWK, used to detect whether a key was
+    /// removed from its JWKS.
+    pub(super) fingerprint: u64,
 }
 
 pub(super) struct FetchedData {
     pub(super) keys: Vec<FetchedKey>,
-    // TODO: expiration
+
+    /// The earliest expiration time of all `keys`, if any has one.
+    pub(super) key_expiry: Option<SystemTime>,
+}
+
+/// Like `jwtea::Jwks`, but additionally reads the nonstandard `exp` member of
+/// each key.
+#[derive(Deserialize)]
+struct JwksWithExp<'a> {
+    keys: Vec<JwkWithExp<'a>>,
+}
+
+#[derive(Deserialize)]
+struct JwkWithExp<'a> {
+    #[serde(flatten)]
+    jwk: Jwk<'a>,
+
+    /// Nonstandard: expiration time of this key as Unix timestamp (seconds).
+    /// Expired keys are not used, even if the JWKS still contains them.
+    exp: Option<u64>,
+}
+
+/// Information about a JWK besides its key material.
+#[derive(Debug, Clone)]
+pub(super) struct JwkMeta {
+    pub(super) kty: String,
+    pub(super) alg: Option<String>,
+    pub(super) crv: Option<String>,
+
+    /// See `JwkWithExp::exp`.
+    exp: Option<u64>,
+}
+
+impl JwkMeta {
+    fn new(jwk: &Jwk<'_>, exp: Option<u64>) -> Self {
+        let (kty, crv) = kty_and_crv(jwk);
+        Self {
+            kty: kty.to_owned(),
+            alg: jwk.alg.as_ref().map(|alg| alg.to_string()),
+            crv: crv.map(str::to_owned),
+            exp,
+        }
+    }
+
+    /// Returns the expiration time from the `exp` member, if set. Values too
+    /// large to be represented are treated as no expiration.
+    pub(super) fn expiry(&self) -> Option<SystemTime> {
+        self.exp.and_then(|exp| SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(exp)))
+    }
+
+    pub(super) fn is_expired(&self) -> bool {
+        self.expiry().is_some_and(|expiry| expiry <= SystemTime::now())
+    }
+}
+
+fn kty_and_crv<'a>(jwk: &'a Jwk<'_>) -> (&'static str, Option<&'a str>) {
+    match &jwk.key_data {
+        KeyData::Ec { crv, .. } => ("EC", Some(crv.as_str())),
+        KeyData::Rsa { .. } => ("RSA", None),
+        KeyData::Oct { .. } => ("oct", None),
+        KeyData::Okp { crv, .. } => ("OKP", Some(crv.as_str())),
+    }
+}
+
+/// Hashes `kid`, `alg` and the public key parameters of `jwk`.
+fn fingerprint(jwk: &Jwk<'_>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    jwk.kid.hash(&mut hasher);
+    jwk.alg.as_ref().map(|alg| alg.as_str()).hash(&mut hasher);
+    kty_and_crv(jwk).hash(&mut hasher);
+    match &jwk.key_data {
+        KeyData::Ec { x, y, .. } => (x, y).hash(&mut hasher),
+        KeyData::Rsa { n, e, .. } => (n, e).hash(&mut hasher),
+        KeyData::Oct { k } => k.hash(&mut hasher),
+        KeyData::Okp { x, .. } => x.hash(&mut hasher),
+    }
+    hasher.finish()
 }
 
 /// Fetches the given JWKS URL and returns valid keys that were found.
 pub async fn fetch(uri: &JwksUrl, http_client: &SimpleHttpClient) -> Result<FetchedData> {
+    let span = debug_span!("jwks_fetch", %uri, elapsed_ms = Empty, outcome = Empty);
+    log::timed!(span, fetch_impl(uri, http_client), |out| match out {
+        Ok(_) => "ok",
+        Err(_) => "error",
+    })
+}
+
+async fn fetch_impl(uri: &JwksUrl, http_client: &SimpleHttpClient) -> Result<FetchedData> {
     use http_body_util::BodyExt;
 
     trace!(?uri, "fetching JWKS");
@@ -39,22 +133,82 @@ pub async fn fetch(uri: &JwksUrl, http_client: &SimpleHttpClient) -> Result<Fetc
     let body: Bytes = response.into_body().collect().await
         .context("failed to download HTTP body of JWKS")?
         .to_bytes();
-    let jwks: Jwks = serde_json::from_slice(&body)
+    let jwks: JwksWithExp = serde_json::from_slice(&body)
         .context("could not deserialize JWKS response as valid JWKS")?;
 
     // Read as crypto keys
     let mut keys = Vec::new();
-    for jwk in jwks.keys {
+    for JwkWithExp { jwk, exp } in jwks.keys {
+        let meta = JwkMeta::new(&jwk, exp);
+        if !jwk.key_ops.as_ref().is_none_or(|ops| ops.contains(&KeyOp::Verify)) {
+            debug!(key_ops = ?jwk.key_ops, "key from JWKS not usable to verify signatures \
+                according to `key_ops` -> ignoring");
+            continue;
+        }
+        if meta.is_expired() {
+            debug!(exp, "key from JWKS expired according to `exp` -> ignoring");
+            continue;
+        }
+
         match VerifyingKey::from_jwk(&jwk) {
             Err(e) => debug!("key from JWKS invalid: {e}"),
             Ok(key) => {
                 keys.push(FetchedKey {
                     key,
+                    fingerprint: fingerprint(&jwk),
                     kid: jwk.kid.map(|c| Kid(c.into_owned())),
+                    meta,
                 });
             }
         }
     }
 
-    Ok(FetchedData { keys })
+    let key_expiry = keys.iter().filter_map(|key| key.meta.expiry()).min();
+    Ok(FetchedData { keys, key_expiry })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tracing::Instrument as _;
+
+    use super::*;
+    use crate::log::Capture;
+
+    #[test]
+    fn huge_exp_means_no_expiry() {
+        let meta = |exp| JwkMeta { kty: "OKP".into(), alg: None, crv: None, exp: Some(exp) };
+        assert!(meta(0).is_expired());
+        assert!(!meta(4_012_345_678).is_expired());
+        assert_eq!(meta(u64::MAX).expiry(), None);
+        assert!(!meta(u64::MAX).is_expired());
+    }
+
+    #[tokio::test]
+    async fn fetch_emits_timed_span() {
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        // Nothing listens on port 1, so this fails quickly.
+        let uri = JwksUrl("http://127.0.0.1:1/jwks.json".parse().unwrap());
+        let client = crate::util::http_client().unwrap();
+        let outer = tracing::info_span!("req", id = 7);
+        let res = fetch(&uri, &client).instrument(outer).await;
+        assert!(res.is_err());
+
+        let logs = capture.output();
+        let line = logs.lines()
+            .find(|l| l.contains("finished"))
+            .unwrap_or_else(|| panic!("no span finish event in logs:\n{logs}"));
+        assert!(line.contains("req{id=7}:jwks_fetch{"), "{line}");
+        assert!(line.contains("uri=http://127.0.0.1:1/jwks.json"), "{line}");
+        assert!(line.contains("elapsed_ms="), "{line}");
+        assert!(line.contains("outcome=\"error\""), "{line}");
+    }
 }
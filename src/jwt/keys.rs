@@ -9,9 +9,11 @@ use tokio::{
     sync::{RwLock, Semaphore, TryAcquireError},
     time::Instant,
 };
+use tracing::{Instrument as _, debug_span, field::Empty};
 
 use super::{Context, JwksUrl, JwtConfig, Kid, jwks};
 use crate::{
+    log,
     prelude::*,
     util::{self, SimpleHttpClient},
 };
@@ -24,12 +26,27 @@ pub(super) const BACKGROUND_REFRESH_LEAD_TIME: Duration = Duration::from_secs(3)
 /// but only if the last backup refresh was more than this duration ago.
 const BACKUP_REFRESH_RATE_LIMIT: Duration = Duration::from_secs(15);
 
+/// Expiring keys (see `JwkMeta::exp`) do not make their source stale earlier
+/// than this after fetching it. Otherwise, a JWKS still containing keys about
+/// to expire would be refetched in a loop. Expired keys are never used anyway.
+const MIN_KEY_EXPIRY_REFRESH_INTERVAL: Duration = Duration::from_secs(15);
+
 
 /// A single cryptographic key fetched from a JWKS, with some metadata.
 #[derive(Debug, Clone)]
 pub(super) struct Key {
     pub(super) key: jwtea::VerifyingKey,
     pub(super) source: Arc<KeySource>,
+    pub(super) meta: jwks::JwkMeta,
+    fingerprint: u64,
+}
+
+/// A key that was removed from its JWKS, see `jwt.removed_key_grace`.
+#[derive(Debug, Clone)]
+struct RemovedKey {
+    kid: Option<Kid>,
+    key: Key,
+    removed_at: Instant,
 }
 
 /// A URL to a JWKS, with some metadata.
@@ -37,26 +54,33 @@ pub(super) struct Key {
 pub(super) struct KeySource {
     pub(super) last_fetch: Instant,
     pub(super) url: JwksUrl,
-    // TODO: expiration
+
+    /// The earliest expiration time of the keys of this source, if any has
+    /// one. The source is considered stale from then on.
+    pub(super) key_expiry: Option<Instant>,
 }
 
 /// All cryptographic keys we know of.
 #[derive(Clone)]
 pub(super) struct Keys {
-    with_id: HashMap<Kid, Key>,
+    /// Usually one key per `kid`, but a source might publish multiple keys
+    /// with the same `kid` for different algorithms, e.g. during rotation.
+    with_id: HashMap<Kid, Vec<Key>>,
     without_id: Vec<Key>,
     sources: Vec<Arc<KeySource>>,
+
+    /// Keys removed from their JWKS less than `jwt.removed_key_grace` ago.
+    removed: Vec<RemovedKey>,
 }
 
 impl KeySource {
     pub(super) fn is_stale(&self, config: &JwtConfig) -> bool {
-        // TODO: expiration
-        self.last_fetch.elapsed() > config.key_cache_duration
+        Instant::now() > self.expiry(config)
     }
 
     pub(super) fn expiry(&self, config: &JwtConfig) -> Instant {
-        // TODO: expiration
-        self.last_fetch + config.key_cache_duration
+        let cache_expiry = self.last_fetch + config.key_cache_duration_for(&self.url);
+        self.key_expiry.map_or(cache_expiry, |key_expiry| key_expiry.min(cache_expiry))
     }
 }
 
@@ -67,24 +91,25 @@ impl Keys {
             with_id: HashMap::new(),
             without_id: Vec::new(),
             sources: Vec::new(),
+            removed: Vec::new(),
         }
     }
 
     /// Returns the number of keys.
     fn len(&self) -> usize {
-        self.with_id.len() + self.without_id.len()
+        self.with_id.values().map(Vec::len).sum::<usize>() + self.without_id.len()
     }
 
     /// Returns keys that could be used to verify a JWT with the given `kid` and
     /// `alg`.
     ///
     /// - If `kid` is `Some` and there is...
-    ///     - ... a key with that kid: only that key is returned.
+    ///     - ... a key with that kid: only the keys with that kid are returned.
     ///     - ... NOT a key with that kid: all `without_id` are returned.
     /// - Else: All keys are returned
     ///
-    /// If the is a key with the given `kid`, but the algo mismatches, `Err` is
-    /// returned. All other keys with mismatched algo are filtered out. Does
+    /// If there are keys with the given `kid`, but none supports `alg`, `Err`
+    /// is returned. All other keys with mismatched algo are filtered out. Does
     /// not filter stale keys, so stale keys can be returned!
     ///
     /// The Iterator returns a pair of `Key` and a bool that described if the
@@ -93,26 +118,65 @@ impl Keys {
         &self,
         kid: Option<&str>,
         alg: &jwtea::Alg<'_>,
+        strict: bool,
     ) -> Result<impl Iterator<Item = (&Key, bool)>, jwtea::Error> {
-        let perfect_match = kid.and_then(|kid| self.with_id.get(kid));
+        let with_kid = kid.and_then(|kid| self.with_id.get(kid));
 
-        if let Some(key) = perfect_match && !key.key.supports_alg(alg) {
+        if let Some(keys) = with_kid && !keys.iter().any(|key| key.key.supports_alg(alg)) {
             return Err(jwtea::Error::AlgoMismatch);
         }
+        let perfect_matches = with_kid.into_iter().flatten()
+            .filter(move |key| key.key.supports_alg(alg))
+            .map(|key| (key, true));
 
-        let without_ids = perfect_match.is_none().then(|| self.without_id.iter());
-        let with_ids = kid.is_none().then(|| self.with_id.values());
+        // Normally, if no key matches the `kid`, all keys without ID are
+        // tried. In strict mode, the `kid` has to match.
+        let try_without_ids = with_kid.is_none() && !(strict && kid.is_some());
+        let without_ids = try_without_ids.then(|| self.without_id.iter());
+        let with_ids = kid.is_none().then(|| self.with_id.values().flatten());
         let rest = without_ids.into_iter().flatten()
             .chain(with_ids.into_iter().flatten())
             .filter(move |key| key.key.supports_alg(alg))
             .map(|key| (key, false));
 
-        Ok(perfect_match.into_iter().map(|key| (key, true)).chain(rest))
+        Ok(perfect_matches.chain(rest))
+    }
+
+    /// Returns keys that were removed from their JWKS less than `grace` ago
+    /// and could be used to verify a JWT with the given `kid` and `alg`. The
+    /// `kid` is matched like in `keys_for`, except that keys without ID are
+    /// always tried in non-strict mode. Keys past their `exp` are skipped.
+    pub(super) fn removed_keys_for(
+        &self,
+        kid: Option<&str>,
+        alg: &jwtea::Alg<'_>,
+        strict: bool,
+        grace: Duration,
+    ) -> impl Iterator<Item = &Key> {
+        self.removed.iter()
+            .filter(move |removed| removed.removed_at.elapsed() < grace)
+            .filter(move |removed| kid.is_none_or(|kid| match &removed.kid {
+                Some(removed_kid) => removed_kid.0 == kid,
+                None => !strict,
+            }))
+            .map(|removed| &removed.key)
+            .filter(|key| !key.meta.is_expired())
+            .filter(move |key| key.key.supports_alg(alg))
+    }
+
+    /// Returns all keys with their `kid`, if any.
+    pub(super) fn iter(&self) -> impl Iterator<Item = (Option<&Kid>, &Key)> {
+        self.with_id.iter()
+            .flat_map(|(kid, keys)| keys.iter().map(move |key| (Some(kid), key)))
+            .chain(self.without_id.iter().map(|key| (None, key)))
     }
 
     /// Removes all keys of the given source.
     fn prune_keys_of(&mut self, source: &JwksUrl) {
-        self.with_id.retain(|_kid, key| &key.source.url != source);
+        self.with_id.retain(|_kid, keys| {
+            keys.retain(|key| &key.source.url != source);
+            !keys.is_empty()
+        });
         self.without_id.retain(|key| &key.source.url != source);
     }
 
@@ -125,22 +189,50 @@ impl Keys {
     }
 
     /// Removes all old keys of the given source, then inserts all freshly
-    /// fetched keys.
-    fn update_source(&mut self, source: &JwksUrl, data: &jwks::FetchedData) {
+    /// fetched keys. Old keys not contained in `data` anymore are remembered
+    /// for `grace`.
+    fn update_source(&mut self, source: &JwksUrl, data: &jwks::FetchedData, grace: Duration) {
+        let now = Instant::now();
+        let fresh = data.keys.iter().map(|key| key.fingerprint).collect::<HashSet<_>>();
+        self.removed.retain(|removed| {
+            removed.removed_at.elapsed() < grace && !fresh.contains(&removed.key.fingerprint)
+        });
+        if !grace.is_zero() {
+            let removed = self.iter()
+                .filter(|(_, key)| &key.source.url == source && !fresh.contains(&key.fingerprint))
+                .map(|(kid, key)| RemovedKey {
+                    kid: kid.cloned(),
+                    key: key.clone(),
+                    removed_at: now,
+                })
+                .collect::<Vec<_>>();
+            for removed in &removed {
+                debug!(%source, kid = ?removed.kid, "key was removed from JWKS");
+            }
+            self.removed.extend(removed);
+        }
         self.prune_keys_of(source);
+
+        // Expiry times too far in the future to represent are treated as none.
+        let key_expiry = data.key_expiry
+            .and_then(util::to_instant)
+            .map(|expiry| expiry.max(now + MIN_KEY_EXPIRY_REFRESH_INTERVAL));
         let source = Arc::new(KeySource {
             url: source.clone(),
-            last_fetch: Instant::now(),
+            last_fetch: now,
+            key_expiry,
         });
 
         for key in &data.keys {
             let v = Key {
                 key: key.key.clone(),
                 source: source.clone(),
+                meta: key.meta.clone(),
+                fingerprint: key.fingerprint,
             };
             match &key.kid {
                 Some(kid) => {
-                    self.with_id.insert(kid.clone(), v);
+                    self.with_id.entry(kid.clone()).or_default().push(v);
                 }
                 None => self.without_id.push(v),
             }
@@ -176,6 +268,13 @@ pub(super) struct KeyManager {
     /// entry per JWKS source in the config and never changes size.
     fetch_guards: HashMap<JwksUrl, Semaphore>,
 
+    /// Limits how many fetches run concurrently across all sources. See
+    /// `jwt.max_concurrent_fetches`.
+    fetch_limit: Semaphore,
+
+    /// See `jwt.removed_key_grace`.
+    removed_key_grace: Duration,
+
     /// Backup refreshes are those that happen in response to an incoming
     /// request, when no key is found to verify the JWT with. This is used to
     /// throttle backup refreshes, to avoid a DOS attack where an attacker would
@@ -188,12 +287,14 @@ impl KeyManager {
     pub(super) async fn new(config: &JwtConfig) -> Result<Arc<Self>> {
         let http_client = util::http_client()?;
         let fetch_guards = config.trusted_keys.iter()
-            .map(|url| (url.clone(), Semaphore::new(1)))
+            .map(|src| (src.url.clone(), Semaphore::new(1)))
             .collect();
 
         let this = Arc::new(Self {
             keys: ArcSwap::from_pointee(Keys::empty()),
             fetch_guards,
+            fetch_limit: Semaphore::new(config.max_concurrent_fetches.get()),
+            removed_key_grace: config.removed_key_grace,
             http_client,
             last_backup_refresh: RwLock::new(
                 // We want to allow a single a backup refresh at the very start.
@@ -204,17 +305,35 @@ impl KeyManager {
             ),
         });
 
-        // Fetching all sources once & setting up background refresh
+        // Fetching all sources once & setting up background refresh. The
+        // initial fetch usually happens in the background, except if we have
+        // to check its result.
+        if config.fail_on_empty_jwks {
+            this.initial_fetch(config).await;
+            let keys = this.keys.load();
+            let empty_sources = config.trusted_keys.iter()
+                .filter(|src| !keys.iter().any(|(_, key)| key.source.url == src.url))
+                .map(|src| src.url.to_string())
+                .collect::<Vec<_>>();
+            if !empty_sources.is_empty() {
+                bail!(
+                    "no usable keys found in JWKS (and `jwt.fail_on_empty_jwks` is enabled): {}",
+                    empty_sources.join(", "),
+                );
+            }
+        }
         {
             let this = this.clone();
             let config = config.clone();
             tokio::spawn(async move {
-                info!("Fetching trusted keys for initialization");
-                this.refresh(&config.trusted_keys).await;
-                info!("Fetched {} trusted keys", this.keys.load().len());
+                if !config.fail_on_empty_jwks {
+                    this.initial_fetch(&config).await;
+                }
 
-                // Start background refresh task, if configured.
-                if config.background_key_refresh {
+                // Start background refresh task, if configured for any source.
+                let any_background = config.trusted_keys.iter()
+                    .any(|src| config.background_key_refresh_for(&src.url));
+                if any_background {
                     this.background_refresh(&config).await;
                 }
             });
@@ -223,6 +342,12 @@ impl KeyManager {
         Ok(this)
     }
 
+    async fn initial_fetch(self: &Arc<Self>, config: &JwtConfig) {
+        info!("Fetching trusted keys for initialization");
+        self.refresh(config.trusted_keys.iter().map(|src| &src.url)).await;
+        info!("Fetched {} trusted keys", self.keys.load().len());
+    }
+
     pub fn keys(&self) -> &ArcSwap<Keys> {
         &self.keys
     }
@@ -233,11 +358,19 @@ impl KeyManager {
     /// started, but the ongoing fetch is awaited. After this function returns,
     /// the fetch results are visible in `keys`.
     async fn refresh_single(&self, source: &JwksUrl) {
+        let span = debug_span!("refresh_keys", %source, elapsed_ms = Empty, outcome = Empty);
+        log::timed!(span, self.refresh_single_impl(source), |outcome| outcome);
+    }
+
+    /// Implementation of `refresh_single`, returning the outcome for the span.
+    async fn refresh_single_impl(&self, source: &JwksUrl) -> &'static str {
         trace!(%source, "Refreshing keys");
         let semaphore = self.fetch_guards.get(source).expect("no semaphore for JWKS url");
         match semaphore.try_acquire() {
             // We could acquire a permit -> no other task is fetching for this URL.
             Ok(_permit) => {
+                let _limit = self.fetch_limit.acquire().await
+                    .expect("fetch limit semaphore closed");
                 let res = jwks::fetch(source, &self.http_client).await;
                 self.keys.rcu(|keys| {
                     let mut out = Keys::clone(keys);
@@ -247,7 +380,7 @@ impl KeyManager {
                             if data.keys.is_empty() {
                                 warn!(%source, "JWKS URL had no valid keys");
                             }
-                            out.update_source(source, data);
+                            out.update_source(source, data, self.removed_key_grace);
                         }
                         Err(e) => {
                             error!(?source, "Error fetching JWKS: {e}");
@@ -255,11 +388,13 @@ impl KeyManager {
                             out.update_source_metadata(Arc::new(KeySource {
                                 last_fetch: Instant::now(),
                                 url: source.clone(),
+                                key_expiry: None,
                             }));
                         }
                     }
                     out
                 });
+                if res.is_ok() { "fetched" } else { "failed" }
             }
 
             // If there are currently no permits, that means another task is
@@ -270,6 +405,7 @@ impl KeyManager {
             Err(TryAcquireError::NoPermits) => {
                 trace!(%source, "waiting for already running refresh task");
                 let _ = semaphore.acquire().await;
+                "awaited"
             }
 
             Err(TryAcquireError::Closed) => unreachable!("semaphore is closed for: {source}"),
@@ -288,7 +424,7 @@ impl KeyManager {
             let source = source.clone();
             tokio::spawn(async move {
                 this.refresh_single(&source).await;
-            })
+            }.in_current_span())
         });
 
         join_all(fetch_tasks).await;
@@ -334,11 +470,13 @@ impl KeyManager {
     }
 
     /// Runs in a background thread and refreshes all sources just in time.
+    /// Sources with `background_key_refresh` disabled are skipped.
     pub(super) async fn background_refresh(self: &Arc<Self>, config: &JwtConfig) {
         loop {
             // Find source that is expiring next.
             let keys = self.keys.load();
             let next_expiry = keys.sources.iter()
+                .filter(|src| config.background_key_refresh_for(&src.url))
                 .map(|src| src.expiry(config))
                 .min()
                 .expect("no key sources in BG refresh");
@@ -357,6 +495,7 @@ impl KeyManager {
                 - BACKGROUND_REFRESH_LEAD_TIME
                 - Duration::from_millis(500);
             let to_be_refreshed = keys.sources.iter()
+                .filter(|src| config.background_key_refresh_for(&src.url))
                 .filter(|src| src.expiry(config) > threshold)
                 .map(|src| &src.url);
 
@@ -367,8 +506,14 @@ impl KeyManager {
 }
 
 
-// This implements the logic to verify a JWT signature, by potentially fetching
-// keys on the fly.
+/// Information about which key verified a signature.
+pub(super) struct VerifiedBy {
+    pub(super) source: Arc<KeySource>,
+
+    /// Whether the key was found via the `kid` of the JWT.
+    pub(super) kid_match: bool,
+}
+
 impl<H> jwtea::SignatureVerifier<H> for Context {
     async fn verify(
         &self,
@@ -376,10 +521,53 @@ impl<H> jwtea::SignatureVerifier<H> for Context {
         message: &str,
         signature: &[u8],
     ) -> std::result::Result<SignatureValid, jwtea::Error> {
+        self.verify_signature(header, message, signature).await.map(|(proof, _)| proof)
+    }
+}
+
+impl Context {
+    // This implements the logic to verify a JWT signature, by potentially
+    // fetching keys on the fly.
+    pub(super) async fn verify_signature<H>(
+        &self,
+        header: &jwtea::Header<'_, H>,
+        message: &str,
+        signature: &[u8],
+    ) -> std::result::Result<(SignatureValid, VerifiedBy), jwtea::Error> {
+        let span = debug_span!(
+            "verify_signature",
+            alg = %header.alg,
+            kid = header.kid.as_deref(),
+            elapsed_ms = Empty,
+            outcome = Empty,
+        );
+        let future = self.verify_signature_impl(header, message, signature);
+        log::timed!(span, future, |out| match out {
+            Ok(_) => "valid",
+            Err(jwtea::Error::InvalidSignature) => "invalid",
+            Err(_) => "no_key",
+        })
+    }
+
+    async fn verify_signature_impl<H>(
+        &self,
+        header: &jwtea::Header<'_, H>,
+        message: &str,
+        signature: &[u8],
+    ) -> std::result::Result<(SignatureValid, VerifiedBy), jwtea::Error> {
         let alg = &header.alg;
+        let alg_name = alg.to_string();
         let kid = header.kid.as_deref();
-        trace!(%alg, ?kid, message, signature, "Verifying signature...");
+        trace!(%alg, ?kid, message = %log::Token(message), "Verifying signature...");
         // let algo = crypto::Algo::from_str(alg).ok_or(JwtError::UnsupportedAlg)?;
+
+        if let Some(allowed) = &self.config.allowed_algorithms
+            && !allowed.contains(&alg_name)
+        {
+            trace!(%alg, "algorithm not in `jwt.allowed_algorithms` -> rejecting");
+            return Err(jwtea::Error::UnsupportedAlg);
+        }
+
         let mut tried_some_keys = false;
 
         // Tries to verify the given key. Early exits on success. If `kid_match`
@@ -387,11 +575,18 @@ impl<H> jwtea::SignatureVerifier<H> for Context {
         macro_rules! try_verify {
             ($key:expr, $kid_match:expr) => {
                 tried_some_keys = true;
-                let key = $key;
-                match key.verify(header, message, &signature) {
+                let Key { key, source, .. } = $key;
+                let start = std::time::Instant::now();
+                let res = key.verify(header, message, &signature);
+                self.verification_stats.record(&alg_name, start.elapsed(), res.is_ok());
+                match res {
                     Ok(proof) => {
                         trace!(?key, "Key successfully verified signature");
-                        return Ok(proof);
+                        let verified_by = VerifiedBy {
+                            source: source.clone(),
+                            kid_match: $kid_match,
+                        };
+                        return Ok((proof, verified_by));
                     }
                     Err(_) => {
                         trace!(?key, "Key could not verify signature");
@@ -405,14 +600,15 @@ impl<H> jwtea::SignatureVerifier<H> for Context {
 
 
         // First: check all non-stale keys. This is a fast pass to make sure we
-        // don't do any unneeded expensive operation.
+        // don't do any unneeded expensive operation. Expired keys make their
+        // source stale.
         let keys = self.keys().load();
         let mut stale_sources = HashSet::new();
-        for (key, kid_match) in keys.keys_for(kid, alg)? {
-            if key.source.is_stale(&self.config) {
+        for (key, kid_match) in keys.keys_for(kid, alg, self.config.strict)? {
+            if key.source.is_stale(&self.config) || key.meta.is_expired() {
                 stale_sources.insert(&key.source.url);
             } else {
-                try_verify!(&key.key, kid_match);
+                try_verify!(key, kid_match);
             }
         }
 
@@ -426,9 +622,9 @@ impl<H> jwtea::SignatureVerifier<H> for Context {
 
             // Try all keys that were just refreshed
             let keys = self.keys().load();
-            for (key, kid_match) in keys.keys_for(kid, alg)? {
-                if stale_sources.contains(&key.source.url) {
-                    try_verify!(&key.key, kid_match);
+            for (key, kid_match) in keys.keys_for(kid, alg, self.config.strict)? {
+                if stale_sources.contains(&key.source.url) && !key.meta.is_expired() {
+                    try_verify!(key, kid_match);
                 }
             }
         }
@@ -437,28 +633,47 @@ impl<H> jwtea::SignatureVerifier<H> for Context {
         // verify the JWT. It could be that a source we consider fresh rotated a
         // key. To not show failures in this case, we do refetch everything that
         // hasn't been refetched above. This is rate limited however, so that
-        // an attacker cannot force this service to always refetch.
-        if stale_sources.len() == self.config.trusted_keys.len() {
-            trace!("Already just refetched all sources -> no backup refetch");
+        // an attacker cannot force this service to always refetch. If the
+        // `kid` is mapped to a source via `kid_source_hints`, only that source
+        // is considered.
+        let hinted_source = kid.and_then(|kid| self.config.hinted_source(kid));
+        let not_refreshed_yet = self.config.trusted_keys.iter()
+            .map(|src| &src.url)
+            .filter(|url| !stale_sources.contains(url))
+            .filter(|url| hinted_source.is_none_or(|hinted| hinted == *url))
+            .collect::<Vec<_>>();
+        if not_refreshed_yet.is_empty() {
+            trace!("Already just refetched all relevant sources -> no backup refetch");
         } else {
-            let not_refreshed_yet = self
-                .config
-                .trusted_keys
-                .iter()
-                .filter(|url| !stale_sources.contains(url));
-            let try_again = self.key_manager.backup_refresh(not_refreshed_yet).await;
+            let try_again = self.key_manager
+                .backup_refresh(not_refreshed_yet.iter().copied())
+                .await;
 
             if try_again {
                 // Try all keys that were just refreshed
                 let keys = self.keys().load();
-                for (key, kid_match) in keys.keys_for(kid, alg)? {
-                    if !stale_sources.contains(&key.source.url) {
-                        try_verify!(&key.key, kid_match);
+                for (key, kid_match) in keys.keys_for(kid, alg, self.config.strict)? {
+                    if not_refreshed_yet.contains(&&key.source.url) {
+                        try_verify!(key, kid_match);
                     }
                 }
             }
         }
 
+        // As a last resort, try keys that were removed from their JWKS just
+        // recently, see `jwt.removed_key_grace`.
+        let keys = self.keys().load();
+        let grace = self.config.removed_key_grace;
+        for key in keys.removed_keys_for(kid, alg, self.config.strict, grace) {
+            tried_some_keys = true;
+            if let Ok(proof) = key.key.verify(header, message, signature) {
+                warn!(source = %key.source.url, ?kid, "JWT verified with key that was recently \
+                    removed from its JWKS (allowed by `jwt.removed_key_grace`)");
+                let verified_by = VerifiedBy { source: key.source.clone(), kid_match: false };
+                return Ok((proof, verified_by));
+            }
+        }
+
         // After all that, the signature truly cannot be verified
         if tried_some_keys {
             Err(jwtea::Error::InvalidSignature)
@@ -467,3 +682,117 @@ impl<H> jwtea::SignatureVerifier<H> for Context {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, time::Duration};
+
+    use tracing::Instrument as _;
+
+    use crate::{jwt::{Context, JwksUrl, JwtConfig, TrustedKeySource}, log::Capture};
+
+    /// JWT with `alg` EdDSA, payload: `{ "exp": 4012345678, "roles": ["ROLE_ADMIN"] }`
+    const JWT: &str = "eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCJ9.\
+        eyJleHAiOjQwMTIzNDU2NzgsInJvbGVzIjpbIlJPTEVfQURNSU4iXX0.\
+        6Bs6wdvBdWbszV38Lj81OmtW5ibutzUfTc8_X6k3yiwOHNQm5xQrWiILXGRP7eHFiI4Ju1FHu_NxufSUPiELAw";
+
+    // Nothing listens on port 1, so fetching fails quickly.
+    const JWKS_URL: &str = "http://127.0.0.1:1/jwks.json";
+
+    fn config() -> JwtConfig {
+        JwtConfig {
+            trusted_keys: vec![TrustedKeySource {
+                url: JwksUrl(JWKS_URL.parse().unwrap()),
+                key_cache_duration: None,
+                background_key_refresh: Some(false),
+            }],
+            require_https_jwks: false,
+            kid_source_hints: Default::default(),
+            max_concurrent_fetches: NonZeroUsize::new(8).unwrap(),
+            background_key_refresh: false,
+            fail_on_empty_jwks: false,
+            key_cache_duration: Duration::from_secs(600),
+            max_key_cache_duration: Duration::from_secs(7 * 24 * 60 * 60),
+            removed_key_grace: Duration::ZERO,
+            strict: false,
+            require_cnf: false,
+            allow_unencoded_payload: false,
+            max_oc_entries: 10_000,
+            expected_typ: None,
+            allowed_algorithms: None,
+            allowed_clock_skew: Duration::from_secs(3),
+        }
+    }
+
+    /// Captures debug logs for the current thread and sets up a JWT context,
+    /// waiting for its initial fetch so that it does not interfere.
+    async fn setup() -> (Capture, tracing::subscriber::DefaultGuard, Context) {
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let guard = tracing::subscriber::set_default(subscriber);
+
+        let ctx = Context::new(&config()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !capture.output().contains("Fetched 0 trusted keys") {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("initial fetch did not finish");
+
+        (capture, guard, ctx)
+    }
+
+    /// Returns the log line of the `finished` event of span `name` inside the
+    /// span `req{id=7}`. Only in that line, the span has an `outcome`.
+    fn finished_line(logs: &str, name: &str) -> String {
+        let has_outcome = |line: &str| line.split_once(&format!("{name}{{"))
+            .and_then(|(_, rest)| rest.split_once('}'))
+            .is_some_and(|(fields, _)| fields.contains("outcome="));
+        logs.lines()
+            .find(|line| line.contains("req{id=7}") && has_outcome(line))
+            .unwrap_or_else(|| panic!("no finish event of span '{name}' in logs:\n{logs}"))
+            .to_owned()
+    }
+
+    #[tokio::test]
+    async fn refresh_emits_timed_span() {
+        let (capture, _guard, ctx) = setup().await;
+        let sources = [JwksUrl(JWKS_URL.parse().unwrap())];
+        ctx.key_manager.refresh(&sources).instrument(tracing::info_span!("req", id = 7)).await;
+
+        let logs = capture.output();
+        let line = finished_line(&logs, "refresh_keys");
+        assert!(line.contains(&format!("req{{id=7}}:refresh_keys{{source={JWKS_URL}")), "{line}");
+        assert!(line.contains("elapsed_ms="), "{line}");
+        assert!(line.contains("outcome=\"failed\""), "{line}");
+
+        let line = finished_line(&logs, "jwks_fetch");
+        assert!(line.contains("req{id=7}:refresh_keys{"), "{line}");
+        assert!(line.contains("}:jwks_fetch{"), "{line}");
+    }
+
+    #[tokio::test]
+    async fn verify_signature_emits_timed_span() {
+        let (capture, _guard, ctx) = setup().await;
+        let res = ctx.decode_and_verify(JWT).instrument(tracing::info_span!("req", id = 7)).await;
+        assert!(res.is_err());
+
+        let logs = capture.output();
+        let line = finished_line(&logs, "verify_signature");
+        assert!(line.contains("req{id=7}:verify_signature{alg=EdDSA"), "{line}");
+        assert!(line.contains("elapsed_ms="), "{line}");
+        assert!(line.contains("outcome=\"no_key\""), "{line}");
+
+        // No keys are loaded, so the backup refresh fetches the JWKS.
+        let line = finished_line(&logs, "refresh_keys");
+        assert!(line.contains("req{id=7}:verify_signature{alg=EdDSA}:refresh_keys{"), "{line}");
+        let line = finished_line(&logs, "jwks_fetch");
+        assert!(line.contains(":verify_signature{alg=EdDSA}:refresh_keys{"), "{line}");
+        assert!(line.contains("}:jwks_fetch{"), "{line}");
+    }
+}
@@ -1,19 +1,36 @@
-use std::{borrow::{Borrow, Cow}, collections::HashMap, sync::Arc};
+use std::{
+    borrow::{Borrow, Cow},
+    cell::Cell,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
 
 use arc_swap::ArcSwap;
-use jwtea::{Payload, RawJwt};
-use serde::Deserialize;
+use jwtea::{Header, Payload, RawJwt, SignatureValid};
+use serde::{
+    Deserialize,
+    de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, Visitor},
+};
 
 use crate::{
-    jwt::keys::{KeyManager, Keys},
+    jwt::keys::{KeyManager, Keys, VerifiedBy},
     prelude::*,
+    util,
 };
 
 mod config;
+mod dpop;
 mod jwks;
 mod keys;
+mod stats;
+mod unencoded;
 
-pub use self::config::{JwksUrl, JwtConfig};
+pub use self::{
+    config::{JwksUrl, JwtConfig, TrustedKeySource},
+    stats::{AlgStats, LATENCY_BUCKETS_US},
+};
 
 
 
@@ -30,8 +47,18 @@ impl Borrow<str> for Kid {
 /// Claims in the payload we are interested in.
 #[derive(Debug, Deserialize)]
 struct PayloadExtras<'a> {
+    sub: Option<Cow<'a, str>>,
+    name: Option<Cow<'a, str>>,
+    email: Option<Cow<'a, str>>,
     roles: Option<Vec<Cow<'a, str>>>,
     oc: Option<HashMap<Cow<'a, str>, Vec<Cow<'a, str>>>>,
+    cnf: Option<Confirmation>,
+}
+
+/// The `cnf` claim (RFC 7800). Only the DPoP key thumbprint is supported.
+#[derive(Debug, Deserialize)]
+struct Confirmation {
+    jkt: Option<String>,
 }
 
 /// Processed information from a JWT relevant for authorization.
@@ -42,14 +69,56 @@ pub struct TokenInfo {
 
     /// All events that have been granted at least `read` access to in the `oc`
     /// claim.
-    pub readable_events: Vec<String>,
+    pub readable_events: HashSet<String>,
+
+    /// All roles from the `roles` claim.
+    pub roles: Vec<String>,
+
+    /// The `kid` header of the JWT, if any.
+    pub kid: Option<String>,
+
+    /// The `alg` header of the JWT.
+    pub alg: String,
+
+    /// The `jkt` member of the `cnf` claim, i.e. the thumbprint of the key
+    /// the client has to prove possession of.
+    pub cnf_jkt: Option<String>,
+
+    /// The `sub` claim, i.e. the user the JWT was issued for.
+    pub sub: Option<String>,
+
+    /// The `name` claim, i.e. the display name of the user.
+    pub name: Option<String>,
+
+    /// The `email` claim.
+    pub email: Option<String>,
 }
 
 impl TokenInfo {
-    fn from_payload(payload: Payload<PayloadExtras>) -> Self {
-        let mut readable_events = Vec::new();
-        for (item, actions) in payload.extra_fields.oc.unwrap_or_default() {
+    /// Extracts the relevant information from the JWT and checks `typ` and,
+    /// if enabled, the checks described in `jwt.strict`.
+    fn new(
+        header: Header,
+        payload: Payload<PayloadExtras>,
+        config: &JwtConfig,
+    ) -> Result<Self, TokenError> {
+        let strict = config.strict;
+        let expected_typ = config.expected_typ.as_deref().or(strict.then_some("JWT"));
+        if let Some(expected) = expected_typ {
+            let typ = header.typ.as_deref();
+            if !typ.is_some_and(|typ| typ.eq_ignore_ascii_case(expected)) {
+                return Err(TokenError::UnexpectedTyp(typ.map(str::to_owned)));
+            }
+        }
+
+        // The number of entries was already checked by `check_oc_entries`.
+        let oc = payload.extra_fields.oc.unwrap_or_default();
+        let mut readable_events = HashSet::new();
+        for (item, actions) in oc {
             let Some((prefix, id)) = item.split_once(':') else {
+                if strict {
+                    return Err(TokenError::Strict("`oc` claim contains key without colon"));
+                }
                 debug!("`oc` claim contained key without colon -> ignoring");
                 continue;
             };
@@ -57,38 +126,194 @@ impl TokenInfo {
             match prefix {
                 "e" => {
                     if actions.iter().any(|action| action == "read") {
-                        readable_events.push(id.to_owned());
+                        readable_events.insert(id.to_owned());
                     }
                 }
                 "s" | "p" => {} // Ignore
+                _ if strict => {
+                    return Err(TokenError::Strict("`oc` claim contains unknown item kind"));
+                }
                 _ => {
                     debug!("`oc` claim contained unknown item kind: '{prefix}'");
                 }
             }
         }
 
-        Self {
-            is_admin: payload.extra_fields.roles
-                .unwrap_or_default()
-                .iter()
-                .any(|role| role == "ROLE_ADMIN"),
+        let cnf_jkt = payload.extra_fields.cnf.and_then(|cnf| cnf.jkt);
+        if config.require_cnf && cnf_jkt.is_none() {
+            return Err(TokenError::Cnf("missing `cnf.jkt` claim"));
+        }
+
+        let roles: Vec<String> = payload.extra_fields.roles
+            .unwrap_or_default()
+            .into_iter()
+            .map(Cow::into_owned)
+            .collect();
+
+        Ok(Self {
+            is_admin: roles.iter().any(|role| role == "ROLE_ADMIN"),
             readable_events,
+            roles,
+            kid: header.kid.as_deref().map(str::to_owned),
+            alg: header.alg.to_string(),
+            cnf_jkt,
+            sub: payload.extra_fields.sub.map(Cow::into_owned),
+            name: payload.extra_fields.name.map(Cow::into_owned),
+            email: payload.extra_fields.email.map(Cow::into_owned),
+        })
+    }
+
+    /// Whether the `oc` claim grants read access to the given event.
+    pub fn can_read(&self, event_id: &str) -> bool {
+        self.readable_events.contains(event_id)
+    }
+}
+
+/// Reasons why a JWT is rejected.
+#[derive(Debug)]
+pub enum TokenError {
+    /// Error from decoding and verifying, e.g. invalid signature or expired.
+    Jwt(jwtea::Error),
+
+    /// The `typ` header (contained here) does not match `jwt.expected_typ`.
+    UnexpectedTyp(Option<String>),
+
+    /// The JWT violates a check enabled by `jwt.strict`.
+    Strict(&'static str),
+
+    /// The `cnf` claim is missing or the client failed to prove possession of
+    /// the key. See `jwt.require_cnf`.
+    Cnf(&'static str),
+
+    /// The JWT uses an unencoded payload (RFC 7797) incorrectly. See
+    /// `jwt.allow_unencoded_payload`.
+    UnencodedPayload(&'static str),
+
+    /// The `oc` claim has more entries than allowed by `jwt.max_oc_entries`
+    /// (contained here).
+    TooManyOcEntries(usize),
+}
+
+impl From<jwtea::Error> for TokenError {
+    fn from(e: jwtea::Error) -> Self {
+        Self::Jwt(e)
+    }
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Jwt(e) => e.fmt(f),
+            Self::UnexpectedTyp(Some(typ)) => write!(f, "unexpected `typ` header '{typ}'"),
+            Self::UnexpectedTyp(None) => write!(f, "missing `typ` header"),
+            Self::Strict(msg) => write!(f, "strict mode: {msg}"),
+            Self::Cnf(msg) => write!(f, "proof of possession: {msg}"),
+            Self::UnencodedPayload(msg) => write!(f, "unencoded payload: {msg}"),
+            Self::TooManyOcEntries(max) => write!(f, "`oc` claim has more than {max} entries"),
         }
     }
 }
 
+impl std::error::Error for TokenError {}
+
+
+/// Checks that the `oc` claim of the (not yet verified) JWT has at most `max`
+/// entries. The payload is visited without storing anything and the visitor
+/// stops after `max + 1` entries, so huge claims are rejected cheaply, before
+/// the signature is checked and the claim is deserialized into a map.
+/// Malformed payloads pass, as `jwtea` rejects them afterwards anyway.
+fn check_oc_entries(raw: &RawJwt<&str>, max: usize) -> Result<(), TokenError> {
+    let Ok(payload) = raw.dangerously_unchecked_decode_payload() else {
+        return Ok(());
+    };
+
+    let too_many = Cell::new(false);
+    let mut deserializer = serde_json::Deserializer::from_str(&payload);
+    let _ = deserializer.deserialize_map(OcLimit { max, too_many: &too_many });
+    if too_many.get() {
+        return Err(TokenError::TooManyOcEntries(max));
+    }
+    Ok(())
+}
+
+/// Payload visitor for `check_oc_entries`, ignoring everything but `oc`.
+#[derive(Clone, Copy)]
+struct OcLimit<'a> {
+    max: usize,
+
+    /// Set when the limit is exceeded. Visiting is then aborted with an
+    /// error, which could not be told apart from other errors otherwise.
+    too_many: &'a Cell<bool>,
+}
+
+impl<'de> Visitor<'de> for OcLimit<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a JSON object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<Cow<'de, str>>()? {
+            if key == "oc" {
+                map.next_value_seed(OcEntries(self))?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Visitor for the `oc` claim, counting its entries up to the limit.
+struct OcEntries<'a>(OcLimit<'a>);
+
+impl<'de> DeserializeSeed<'de> for OcEntries<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for OcEntries<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a JSON object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut len = 0;
+        while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {
+            len += 1;
+            if len > self.0.max {
+                self.0.too_many.set(true);
+                return Err(de::Error::custom("too many entries in `oc` claim"));
+            }
+        }
+        Ok(())
+    }
+}
+
 
 pub struct Context {
     config: JwtConfig,
     key_manager: Arc<KeyManager>,
+    verification_stats: stats::VerificationStats,
 }
 
 impl Context {
     pub async fn new(config: &JwtConfig) -> Result<Self> {
+        if let Some(warning) = config.key_cache_duration_warning() {
+            warn!("{warning}");
+        }
+
         let key_manager = KeyManager::new(config).await?;
         Ok(Self {
             config: config.clone(),
             key_manager,
+            verification_stats: Default::default(),
         })
     }
 
@@ -96,13 +321,159 @@ impl Context {
         self.key_manager.keys()
     }
 
-    pub async fn decode_and_verify(&self, raw: &str) -> Result<TokenInfo, jwtea::Error> {
-        let raw = RawJwt::new(raw)?;
+    /// Returns information about all currently loaded keys, for debugging.
+    /// Does not include any key material.
+    pub fn loaded_keys(&self) -> Vec<LoadedKey> {
+        self.keys().load().iter()
+            .map(|(kid, key)| LoadedKey {
+                kid: kid.map(|kid| kid.0.clone()),
+                kty: key.meta.kty.clone(),
+                alg: key.meta.alg.clone(),
+                crv: key.meta.crv.clone(),
+                source: key.source.url.clone(),
+                fetched_at: util::to_system_time(key.source.last_fetch),
+                expires_at: util::to_system_time(key.source.expiry(&self.config)),
+            })
+            .collect()
+    }
+
+    /// Returns statistics about all signature checks so far, by algorithm.
+    pub fn verification_stats(&self) -> BTreeMap<String, AlgStats> {
+        self.verification_stats.snapshot()
+    }
+
+    pub async fn decode_and_verify(&self, raw: &str) -> Result<TokenInfo, TokenError> {
+        let converted = self.convert_unencoded_payload(raw)?;
+        let raw = RawJwt::new(converted.as_ref().map_or(raw, |c| &c.jwt))?;
+        check_oc_entries(&raw, self.config.max_oc_entries)?;
+        let validator = jwtea::BasicValidator {
+            allowed_clock_skew: self.config.allowed_clock_skew.as_secs() as u32,
+        };
+        let callback = |header: Header, payload: Payload<PayloadExtras>| {
+            TokenInfo::new(header, payload, &self.config)
+        };
+        match &converted {
+            Some(converted) => {
+                let verifier = unencoded::Verifier { inner: self, converted };
+                raw.decode(&verifier, &validator, callback).await?
+            }
+            None => raw.decode(self, &validator, callback).await?,
+        }
+    }
+
+    /// If `jwt.allow_unencoded_payload` is enabled and `raw` has an unencoded
+    /// payload, returns a form that can be passed to `jwtea`.
+    fn convert_unencoded_payload(
+        &self,
+        raw: &str,
+    ) -> Result<Option<unencoded::Converted>, TokenError> {
+        if !self.config.allow_unencoded_payload {
+            return Ok(None);
+        }
+        unencoded::convert(raw)
+    }
+
+    /// If the token is bound to a key via its `cnf` claim, checks that the
+    /// client proved possession of that key, using the DPoP proof from the
+    /// request. `raw` is the token as sent by the client. Tokens without `cnf`
+    /// are already rejected in `TokenInfo::new` if `jwt.require_cnf` is
+    /// enabled, so nothing is checked for them here.
+    pub async fn verify_proof_of_possession(
+        &self,
+        info: &TokenInfo,
+        raw: &str,
+        dpop_proof: Option<&str>,
+        method: &hyper::Method,
+        path: &str,
+    ) -> Result<(), TokenError> {
+        let Some(jkt) = info.cnf_jkt.as_deref() else {
+            return Ok(());
+        };
+        let proof = dpop_proof.ok_or(TokenError::Cnf("missing DPoP proof"))?;
+        dpop::verify(proof, jkt, raw, method, path, self.config.allowed_clock_skew).await
+    }
+
+    /// Like `decode_and_verify`, but additionally reports which key verified
+    /// the signature. Intended for diagnostics, not the hot path.
+    pub async fn decode_and_verify_detailed(
+        &self,
+        raw: &str,
+    ) -> Result<(TokenInfo, MatchedKey), TokenError> {
+        let converted = self.convert_unencoded_payload(raw)?;
+        let raw = RawJwt::new(converted.as_ref().map_or(raw, |c| &c.jwt))?;
+        check_oc_entries(&raw, self.config.max_oc_entries)?;
         let validator = jwtea::BasicValidator {
             allowed_clock_skew: self.config.allowed_clock_skew.as_secs() as u32,
         };
-        let callback = |_header: jwtea::Header, payload| TokenInfo::from_payload(payload);
-        raw.decode(self, &validator, callback).await
+        let verifier = RecordingVerifier { ctx: self, matched: Mutex::new(None) };
+        let callback = |header: Header, payload: Payload<PayloadExtras>| {
+            TokenInfo::new(header, payload, &self.config)
+        };
+        let info = match &converted {
+            Some(converted) => {
+                let verifier = unencoded::Verifier { inner: &verifier, converted };
+                raw.decode(&verifier, &validator, callback).await??
+            }
+            None => raw.decode(&verifier, &validator, callback).await??,
+        };
+        let matched = verifier.matched.into_inner()
+            .unwrap()
+            .expect("JWT verified, but no matching key was recorded");
+        Ok((info, matched))
+    }
+}
+
+/// The key that verified the signature of a JWT.
+#[derive(Debug)]
+pub struct MatchedKey {
+    /// The JWKS URL the key was fetched from.
+    pub source: JwksUrl,
+
+    /// The key ID, if the key was looked up via the `kid` header of the JWT.
+    pub kid: Option<String>,
+}
+
+/// A currently loaded key, see `Context::loaded_keys`.
+#[derive(Debug)]
+pub struct LoadedKey {
+    pub kid: Option<String>,
+
+    /// The `kty`, `alg` and `crv` members of the JWK.
+    pub kty: String,
+    pub alg: Option<String>,
+    pub crv: Option<String>,
+
+    /// The JWKS URL the key was fetched from.
+    pub source: JwksUrl,
+
+    pub fetched_at: SystemTime,
+
+    /// When the key is considered stale and is refetched.
+    pub expires_at: SystemTime,
+}
+
+/// Signature verifier that delegates to `Context` and remembers which key
+/// verified the signature.
+struct RecordingVerifier<'a> {
+    ctx: &'a Context,
+    matched: Mutex<Option<MatchedKey>>,
+}
+
+impl<H> jwtea::SignatureVerifier<H> for RecordingVerifier<'_> {
+    async fn verify(
+        &self,
+        header: &jwtea::Header<'_, H>,
+        message: &str,
+        signature: &[u8],
+    ) -> std::result::Result<SignatureValid, jwtea::Error> {
+        let (proof, VerifiedBy { source, kid_match }) = self.ctx
+            .verify_signature(header, message, signature)
+            .await?;
+        *self.matched.lock().unwrap() = Some(MatchedKey {
+            source: source.url.clone(),
+            kid: header.kid.as_deref().filter(|_| kid_match).map(str::to_owned),
+        });
+        Ok(proof)
     }
 }
 
@@ -110,9 +481,39 @@ impl Context {
 pub async fn run_check(config: &JwtConfig) -> Vec<(&JwksUrl, Result<()>)> {
     let http_client = crate::util::http_client().expect("failed to create HTTP client");
     let mut out = Vec::new();
-    for url in &config.trusted_keys {
+    for TrustedKeySource { url, .. } in &config.trusted_keys {
         out.push((url, jwks::fetch(url, &http_client).await.map(|_| ())));
     }
 
     out
 }
+
+
+#[cfg(test)]
+mod tests {
+    use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+    use jwtea::RawJwt;
+
+    use super::{TokenError, check_oc_entries};
+
+    #[test]
+    fn oc_entries_limit() {
+        let check = |payload: &str, max| {
+            let jwt = format!("eyJhbGciOiJFZERTQSJ9.{}.sig", URL_SAFE_NO_PAD.encode(payload));
+            check_oc_entries(&RawJwt::new(jwt.as_str()).unwrap(), max)
+        };
+        let two = r#"{"sub":"a","oc":{"e:1":["read"],"e:2":[{"x":[1]}]},"roles":[]}"#;
+        assert!(check(two, 2).is_ok());
+        assert!(matches!(check(two, 1), Err(TokenError::TooManyOcEntries(1))));
+
+        // Aborts at the limit, even if the rest of the payload is malformed.
+        let three = r#"{"oc":{"e:1":[],"e:2":[],"e:3":[]},"#;
+        assert!(matches!(check(three, 2), Err(TokenError::TooManyOcEntries(2))));
+
+        // Anything the limit does not apply to is left to `jwtea`.
+        assert!(check("{}", 0).is_ok());
+        assert!(check(r#"{"oc":null}"#, 0).is_ok());
+        assert!(check(r#"{"oc":{"a":1},"#, 2).is_ok());
+        assert!(check("not json", 0).is_ok());
+    }
+}
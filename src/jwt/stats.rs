@@ -0,0 +1,80 @@
+//! Per-algorithm statistics about signature verifications, reported by the
+//! `/-/verification-stats` admin endpoint.
+
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+
+/// Upper bounds (inclusive) of the latency histogram buckets in microseconds.
+/// Slower verifications are counted in an additional last bucket.
+pub const LATENCY_BUCKETS_US: [u64; 6] = [50, 100, 250, 500, 1_000, 5_000];
+
+#[derive(Default)]
+pub(super) struct VerificationStats {
+    by_alg: Mutex<BTreeMap<String, AlgStats>>,
+}
+
+/// Statistics for one algorithm, see `Context::verification_stats`.
+#[derive(Debug, Clone, Default)]
+pub struct AlgStats {
+    /// Number of signatures checked, i.e. one per key tried.
+    pub count: u64,
+
+    /// Number of checks where the signature did not match the key.
+    pub failures: u64,
+
+    /// Sum of the durations of all checks.
+    pub total_time: Duration,
+
+    /// Number of checks per latency bucket, see `LATENCY_BUCKETS_US`. Has one
+    /// more entry than that for slower checks.
+    pub latency_buckets: [u64; LATENCY_BUCKETS_US.len() + 1],
+}
+
+impl VerificationStats {
+    pub(super) fn record(&self, alg: &str, elapsed: Duration, success: bool) {
+        let mut by_alg = self.by_alg.lock().unwrap();
+        if !by_alg.contains_key(alg) {
+            by_alg.insert(alg.to_owned(), AlgStats::default());
+        }
+        let stats = by_alg.get_mut(alg).unwrap();
+
+        stats.count += 1;
+        stats.failures += u64::from(!success);
+        stats.total_time += elapsed;
+        let us = elapsed.as_micros();
+        let bucket = LATENCY_BUCKETS_US.iter()
+            .position(|bound| us <= u128::from(*bound))
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        stats.latency_buckets[bucket] += 1;
+    }
+
+    pub(super) fn snapshot(&self) -> BTreeMap<String, AlgStats> {
+        self.by_alg.lock().unwrap().clone()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::VerificationStats;
+
+    #[test]
+    fn records_per_algorithm() {
+        let stats = VerificationStats::default();
+        stats.record("EdDSA", Duration::from_micros(30), true);
+        stats.record("EdDSA", Duration::from_micros(300), false);
+        stats.record("ES256", Duration::from_millis(10), true);
+
+        let snapshot = stats.snapshot();
+        let eddsa = &snapshot["EdDSA"];
+        assert_eq!(eddsa.count, 2);
+        assert_eq!(eddsa.failures, 1);
+        assert_eq!(eddsa.total_time, Duration::from_micros(330));
+        assert_eq!(eddsa.latency_buckets, [1, 0, 0, 1, 0, 0, 0]);
+        let es256 = &snapshot["ES256"];
+        assert_eq!(es256.count, 1);
+        assert_eq!(es256.latency_buckets, [0, 0, 0, 0, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,78 @@
+//! Support for JWS with unencoded payload (RFC 7797), i.e. with the header
+//! `"b64": false`. See `JwtConfig::allow_unencoded_payload`.
+
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use jwtea::SignatureValid;
+use serde::Deserialize;
+
+use super::TokenError;
+
+
+#[derive(Deserialize)]
+struct B64Header {
+    b64: Option<bool>,
+    #[serde(default)]
+    crit: Vec<String>,
+}
+
+/// A JWS with unencoded payload, converted to a form `jwtea` can decode.
+pub(super) struct Converted {
+    /// The JWS with the payload base64url encoded, so that `jwtea` can parse
+    /// it. Its signature is NOT valid for that form.
+    pub(super) jwt: String,
+
+    /// The JWS signing input as defined in RFC 7797, i.e. the encoded header,
+    /// a `.` and the unencoded payload.
+    signing_input: String,
+}
+
+/// Checks whether `raw` uses an unencoded payload. If so, returns the
+/// converted form, otherwise `None`.
+pub(super) fn convert(raw: &str) -> Result<Option<Converted>, TokenError> {
+    let err = TokenError::UnencodedPayload;
+    let (Some((header, _)), Some((signing_input, signature))) =
+        (raw.split_once('.'), raw.rsplit_once('.'))
+    else {
+        // Malformed, which is reported by `jwtea`.
+        return Ok(None);
+    };
+    let Some(header_json) = URL_SAFE_NO_PAD.decode(header).ok() else {
+        return Ok(None);
+    };
+    let Ok(parsed) = serde_json::from_slice::<B64Header>(&header_json) else {
+        return Ok(None);
+    };
+    if parsed.b64 != Some(false) {
+        return Ok(None);
+    }
+
+    // RFC 7797, section 6: `b64` has to be understood by the recipient.
+    if !parsed.crit.iter().any(|name| name == "b64") {
+        return Err(err("`b64` header parameter not listed in `crit`"));
+    }
+    let payload = signing_input[header.len()..].strip_prefix('.')
+        .ok_or(err("malformed JWS"))?;
+
+    Ok(Some(Converted {
+        jwt: format!("{header}.{}.{signature}", URL_SAFE_NO_PAD.encode(payload)),
+        signing_input: signing_input.to_owned(),
+    }))
+}
+
+/// Signature verifier that verifies against the signing input of the original
+/// JWS instead of the one of the converted form.
+pub(super) struct Verifier<'a, V> {
+    pub(super) inner: &'a V,
+    pub(super) converted: &'a Converted,
+}
+
+impl<H, V: jwtea::SignatureVerifier<H>> jwtea::SignatureVerifier<H> for Verifier<'_, V> {
+    async fn verify(
+        &self,
+        header: &jwtea::Header<'_, H>,
+        _message: &str,
+        signature: &[u8],
+    ) -> Result<SignatureValid, jwtea::Error> {
+        self.inner.verify(header, &self.converted.signing_input, signature).await
+    }
+}
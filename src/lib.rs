@@ -1,7 +1,6 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 
 use anyhow::Result;
-use tokio::net::TcpListener;
 
 use crate::config::Config;
 
@@ -16,8 +15,12 @@ pub mod opencast;
 pub mod prelude;
 pub mod util;
 
-pub async fn run_http_server(config: Config) -> Result<()> {
+/// Runs the HTTP server. `config_paths` are the files `config` was loaded
+/// from, which are reloaded on `SIGHUP` to update the blocklist.
+pub async fn run_http_server(config: Config, config_paths: Vec<PathBuf>) -> Result<()> {
     let ctx = http::Context::new(config).await?;
+    auth::reload_blocklist_on_sighup(Arc::clone(&ctx.blocklist), config_paths)?;
+    http::listen_for_maintenance_toggle(Arc::clone(&ctx.maintenance))?;
     http::serve(ctx).await?;
     Ok(())
 }
@@ -25,9 +28,29 @@ pub async fn run_http_server(config: Config) -> Result<()> {
 pub async fn test_http_server(
     config: Config,
 ) -> Result<(SocketAddr, impl Future<Output = Result<()>>)> {
+    let (addrs, server) = test_http_server_all(config).await?;
+    Ok((addrs[0], server))
+}
+
+/// Like `test_http_server`, but returns the addresses of all listeners
+/// (`http.address`/`http.port` first, then `http.listeners`).
+pub async fn test_http_server_all(
+    config: Config,
+) -> Result<(Vec<SocketAddr>, impl Future<Output = Result<()>>)> {
+    test_http_server_with_shutdown(config, std::future::pending()).await
+}
+
+/// Like `test_http_server_all`, but the server gracefully shuts down once
+/// `shutdown` resolves.
+pub async fn test_http_server_with_shutdown(
+    config: Config,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(Vec<SocketAddr>, impl Future<Output = Result<()>>)> {
     let ctx = http::Context::new(config).await?;
-    let listener = TcpListener::bind(ctx.config.http.socket_addr()).await?;
-    let addr = listener.local_addr()?;
-    let server = http::serve_on(ctx, listener);
-    Ok((addr, server))
+    let listeners = http::bind_all(&ctx.config.http)?;
+    let addrs = listeners.iter()
+        .map(|listener| listener.local_addr())
+        .collect::<Result<Vec<_>, _>>()?;
+    let server = http::serve_on(ctx, listeners, shutdown);
+    Ok((addrs, server))
 }
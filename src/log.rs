@@ -1,12 +1,24 @@
 use std::{
     collections::HashMap,
-    fs::OpenOptions,
-    path::PathBuf,
+    fmt,
+    fs::{File, OpenOptions},
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+    time::SystemTime,
 };
 use serde::Deserialize;
+use tracing::{Event, Subscriber};
 use tracing_subscriber::{
     filter::{FilterFn, LevelFilter},
+    fmt::{
+        FmtContext, FormatEvent, FormatFields,
+        format::{Compact, Format, Full, Writer},
+        time::{ChronoLocal, ChronoUtc, FormatTime},
+    },
     prelude::*,
+    registry::LookupSpan,
 };
 
 use crate::prelude::*;
@@ -37,12 +49,145 @@ pub struct LogConfig {
     #[config(default = { "octoka": "info" })]
     pub filters: Filters,
 
-    /// If this is set, log messages are also written to this file.
+    /// If this is set, log messages are also written to this file. On Unix,
+    /// the file is reopened when octoka receives `SIGHUP`, which is useful
+    /// for log rotation (e.g. `postrotate` with logrotate).
     pub file: Option<PathBuf>,
 
+    /// If this is set, one line per allowed request for a static file is
+    /// written to this file, recording who accessed which event and why
+    /// access was granted. Each line is a JSON object with the fields `time`
+    /// (RFC 3339, UTC), `event`, `sub` (the `sub` claim of the JWT, if any),
+    /// `basis` ("admin", "event", "role_rule" or "fallback") and `client`
+    /// (IP address of the connecting client, which is your reverse proxy if
+    /// octoka runs behind one). Like `file`, it is reopened on `SIGHUP`.
+    pub audit_file: Option<PathBuf>,
+
+    /// If `true`, client IP addresses in the audit log are anonymized by
+    /// zeroing the last octet of IPv4 and the last 80 bits of IPv6 addresses.
+    #[config(default = false)]
+    pub audit_anonymize_ip: bool,
+
     /// If this is set to `false`, log messages are not written to stdout.
     #[config(default = true)]
     pub stdout: bool,
+
+    /// Format of log messages.
+    /// - "full": all span fields are shown in front of the message.
+    /// - "compact": shorter; span fields are appended to the message.
+    ///
+    /// Both formats have one message per line, starting with an RFC 3339
+    /// timestamp, e.g. `2025-03-14T09:26:53.589793Z`.
+    #[config(default = "full")]
+    pub format: LogFormat,
+
+    /// Timezone of timestamps in log messages: "utc" or "local". With "local",
+    /// timestamps include the UTC offset, e.g. `+01:00`.
+    #[config(default = "utc")]
+    pub timezone: Timezone,
+
+    /// Whether to use ANSI colors for log messages written to stdout.
+    /// - "auto": only if stdout is a terminal and the `NO_COLOR` env var is
+    ///   not set (or empty).
+    /// - "always"
+    /// - "never"
+    ///
+    /// Logs written to `file` never contain colors.
+    #[config(default = "auto")]
+    pub color: ColorMode,
+
+    /// If `true`, JWTs are logged in full (e.g. when rejected). Otherwise,
+    /// only a redacted form showing the first and last few characters is
+    /// logged. Only enable this for debugging, as JWTs are credentials!
+    #[config(default = false)]
+    pub log_full_tokens: bool,
+}
+
+/// Whether full JWTs may be logged. See `LogConfig::log_full_tokens`.
+static LOG_FULL_TOKENS: AtomicBool = AtomicBool::new(false);
+
+/// Wrapper to log a JWT: its `Display` impl only shows a redacted version,
+/// unless `log.log_full_tokens` is enabled.
+pub struct Token<'a>(pub &'a str);
+
+impl fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const SHOWN_CHARS: usize = 4;
+
+        if LOG_FULL_TOKENS.load(Ordering::Relaxed) {
+            return f.write_str(self.0);
+        }
+
+        let len = self.0.chars().count();
+        if len <= 4 * SHOWN_CHARS {
+            return write!(f, "[redacted, {len} chars]");
+        }
+        let start = self.0.chars().take(SHOWN_CHARS).collect::<String>();
+        let end = self.0.chars().skip(len - SHOWN_CHARS).collect::<String>();
+        write!(f, "{start}…{end}")
+    }
+}
+
+/// Awaits `$future` instrumented with `$span`, then records how long that took
+/// (`elapsed_ms`) and the outcome (`$outcome`, with the future's output bound
+/// to `$out`) into the span. The span has to declare both fields as `Empty`.
+/// A final debug event is emitted inside the span so that the recorded values
+/// show up in the log. Evaluates to the future's output.
+macro_rules! timed {
+    ($span:expr, $future:expr, |$out:ident| $outcome:expr $(,)?) => {{
+        let span: tracing::Span = $span;
+        let start = std::time::Instant::now();
+        let $out = tracing::Instrument::instrument($future, span.clone()).await;
+        span.record("elapsed_ms", start.elapsed().as_secs_f64() * 1000.0);
+        span.record("outcome", $outcome);
+        span.in_scope(|| debug!("finished"));
+        $out
+    }};
+}
+pub(crate) use timed;
+
+impl LogConfig {
+    /// Sets the level for all of octoka's logs (`filters.octoka`), e.g. from a
+    /// CLI flag. Filters for more specific targets are kept and still apply.
+    pub fn override_level(&mut self, level: &str) -> Result<()> {
+        let level = parse_level_filter(level).map_err(Error::msg)?;
+        self.filters.0.insert("octoka".into(), level);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Full,
+    Compact,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Timezone {
+    Utc,
+    Local,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    /// Returns whether to use colors, given whether the output is a terminal
+    /// and the value of the `NO_COLOR` env var.
+    fn use_color(self, is_terminal: bool, no_color: Option<&std::ffi::OsStr>) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => is_terminal && no_color.is_none_or(|v| v.is_empty()),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -75,6 +220,8 @@ fn parse_level_filter(s: &str) -> Result<LevelFilter, String> {
 }
 
 pub fn init(config: &LogConfig, test: bool) -> Result<()> {
+    LOG_FULL_TOKENS.store(config.log_full_tokens, Ordering::Relaxed);
+
     let filter = {
         let filters = config.filters.0.clone();
         let max_level = filters.values().max().copied().unwrap_or(LevelFilter::OFF);
@@ -93,34 +240,37 @@ pub fn init(config: &LogConfig, test: bool) -> Result<()> {
         filter.with_max_level_hint(max_level)
     };
 
+    let timer = Rfc3339Time::new(config.timezone);
+    let event_format = || match config.format {
+        LogFormat::Full => EventFormat::Full(Format::default().with_timer(timer.clone())),
+        LogFormat::Compact => {
+            EventFormat::Compact(Format::default().compact().with_timer(timer.clone()))
+        }
+    };
     macro_rules! subscriber {
         ($writer:expr) => {
-            tracing_subscriber::fmt::layer().with_writer($writer)
+            tracing_subscriber::fmt::layer().event_format(event_format()).with_writer($writer)
         };
     }
 
     let stdout_output = if config.stdout {
-        Some(subscriber!(std::io::stdout))
+        use std::io::IsTerminal;
+
+        let no_color = std::env::var_os("NO_COLOR");
+        let ansi = config.color.use_color(io::stdout().is_terminal(), no_color.as_deref());
+        Some(subscriber!(std::io::stdout).with_ansi(ansi))
     } else {
         None
     };
 
-    let file_output = if let Some(path) = &config.file {
+    let log_file = config.file.as_deref().map(LogFile::open).transpose()?.map(Arc::new);
+    if let Some(file) = &log_file {
         use std::io::Write;
 
-        let mut file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(path)
-            .with_context(|| format!("failed to open/create log file '{}'", path.display()))?;
-
         // Add an empty line separator to see process restarts easier.
-        file.write_all(b"\n\n").context("could not write to log file")?;
-
-        Some(subscriber!(file).with_ansi(false))
-    } else {
-        None
-    };
+        (&**file).write_all(b"\n\n").context("could not write to log file")?;
+    }
+    let file_output = log_file.clone().map(|file| subscriber!(file).with_ansi(false));
 
     if test {
         tracing_subscriber::registry()
@@ -133,7 +283,321 @@ pub fn init(config: &LogConfig, test: bool) -> Result<()> {
             .with(file_output)
             .with(stdout_output)
             .try_init()?;
+
+        if let Some(file) = log_file {
+            reopen_on_sighup(file)?;
+        }
     }
 
     Ok(())
 }
+
+/// Event format chosen via `log.format`.
+enum EventFormat {
+    Full(Format<Full, Rfc3339Time>),
+    Compact(Format<Compact, Rfc3339Time>),
+}
+
+impl<S, N> FormatEvent<S, N> for EventFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        match self {
+            Self::Full(format) => format.format_event(ctx, writer, event),
+            Self::Compact(format) => format.format_event(ctx, writer, event),
+        }
+    }
+}
+
+/// RFC 3339 with microsecond precision, e.g. `2025-03-14T09:26:53.589793Z`.
+const UTC_TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6fZ";
+
+/// Like `UTC_TIMESTAMP_FORMAT`, but with offset, e.g. `+01:00`.
+const LOCAL_TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6f%:z";
+
+/// Formats timestamps of log messages in UTC or local time, see
+/// `log.timezone`.
+#[derive(Clone)]
+enum Rfc3339Time {
+    Utc(ChronoUtc),
+    Local(ChronoLocal),
+}
+
+impl Rfc3339Time {
+    fn new(timezone: Timezone) -> Self {
+        match timezone {
+            Timezone::Utc => Self::Utc(ChronoUtc::new(UTC_TIMESTAMP_FORMAT.into())),
+            Timezone::Local => Self::Local(ChronoLocal::new(LOCAL_TIMESTAMP_FORMAT.into())),
+        }
+    }
+}
+
+impl FormatTime for Rfc3339Time {
+    fn format_time(&self, w: &mut Writer<'_>) -> fmt::Result {
+        match self {
+            Self::Utc(timer) => timer.format_time(w),
+            Self::Local(timer) => timer.format_time(w),
+        }
+    }
+}
+
+/// Formats `time` in UTC as RFC 3339 timestamp with microsecond precision,
+/// e.g. `2025-03-14T09:26:53.589793Z`.
+pub fn rfc3339(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time)
+        .to_rfc3339_opts(chrono::SecondsFormat::Micros, true)
+}
+
+/// Log file that can be reopened, e.g. after it was moved by logrotate.
+struct LogFile {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl LogFile {
+    fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.to_owned(),
+            file: Mutex::new(Self::open_file(path)?),
+        })
+    }
+
+    fn open_file(path: &Path) -> Result<File> {
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("failed to open/create log file '{}'", path.display()))
+    }
+
+    /// Opens the file at the configured path again and writes all further
+    /// logs to it.
+    fn reopen(&self) -> Result<()> {
+        let file = Self::open_file(&self.path)?;
+        *self.file.lock().unwrap() = file;
+        Ok(())
+    }
+}
+
+impl io::Write for &LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.lock().unwrap().write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.file.lock().unwrap().write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.lock().unwrap().flush()
+    }
+}
+
+/// Reopens the log file whenever `SIGHUP` is received.
+#[cfg(target_family = "unix")]
+fn reopen_on_sighup(file: Arc<LogFile>) -> Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut signal = signal(SignalKind::hangup())
+        .context("failed to install SIGHUP signal handler")?;
+    tokio::spawn(async move {
+        while signal.recv().await.is_some() {
+            match file.reopen() {
+                Ok(()) => info!(path = ?file.path, "SIGHUP received -> reopened log file"),
+                Err(e) => error!("SIGHUP received, but failed to reopen log file: {e:#}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(target_family = "unix"))]
+fn reopen_on_sighup(_: Arc<LogFile>) -> Result<()> {
+    Ok(())
+}
+
+/// The audit log, see `log.audit_file`.
+pub struct AuditLog {
+    file: Arc<LogFile>,
+    anonymize_ip: bool,
+}
+
+/// A single line in the audit log.
+pub struct AuditEntry<'a> {
+    pub event: &'a str,
+    pub sub: Option<&'a str>,
+    pub basis: &'static str,
+    pub client: Option<IpAddr>,
+}
+
+impl AuditLog {
+    /// Opens `log.audit_file`, if configured.
+    pub fn open(config: &LogConfig) -> Result<Option<Self>> {
+        let Some(path) = &config.audit_file else {
+            return Ok(None);
+        };
+
+        let file = Arc::new(LogFile::open(path)?);
+        reopen_on_sighup(Arc::clone(&file))?;
+        Ok(Some(Self { file, anonymize_ip: config.audit_anonymize_ip }))
+    }
+
+    pub fn record(&self, entry: AuditEntry<'_>) {
+        use std::io::Write;
+
+        let time = rfc3339(SystemTime::now());
+        let client = entry.client
+            .map(|ip| ip.to_canonical())
+            .map(|ip| if self.anonymize_ip { anonymize_ip(ip) } else { ip });
+
+        let mut line = serde_json::json!({
+            "time": time,
+            "event": entry.event,
+            "sub": entry.sub,
+            "basis": entry.basis,
+            "client": client,
+        }).to_string();
+        line.push('\n');
+
+        // `write_all` holds the file lock, so lines of concurrent requests are
+        // not interleaved.
+        if let Err(e) = (&*self.file).write_all(line.as_bytes()) {
+            error!("failed to write to audit log: {e}");
+        }
+    }
+}
+
+/// Zeroes the last octet of IPv4 and the last 80 bits of IPv6 addresses.
+fn anonymize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4((ip.to_bits() & !0xff).into()),
+        IpAddr::V6(ip) => IpAddr::V6((ip.to_bits() & !((1u128 << 80) - 1)).into()),
+    }
+}
+
+/// Log output collected in memory, to be used as writer in tests.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct Capture(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Capture {
+    pub(crate) fn output(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl std::io::Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_redacted_by_default() {
+        let jwt = "eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCJ9.eyJyb2xlcyI6WyJST0xFX0FETUlOIl19.\
+            28ykRHCGRaiC6jQgLOuJU39sg-ZylYAjs3O5pMduR91YloluSF2XBTyZHEO0fgUeMOS-IFYl9ys_9bo2Un3WAA";
+        let logged = Token(jwt).to_string();
+        assert_eq!(logged, "eyJh…3WAA");
+        assert_eq!(Token("short").to_string(), "[redacted, 5 chars]");
+    }
+
+    #[test]
+    fn rfc3339_timestamps() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let format = |secs, micros| {
+            rfc3339(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_micros(micros))
+        };
+        assert_eq!(format(0, 0), "1970-01-01T00:00:00.000000Z");
+        assert_eq!(format(1_700_000_000, 123_456), "2023-11-14T22:13:20.123456Z");
+        assert_eq!(format(1_709_164_800, 7), "2024-02-29T00:00:00.000007Z");
+    }
+
+    #[test]
+    fn log_line_timestamps() {
+        for timezone in [Timezone::Utc, Timezone::Local] {
+            let capture = Capture::default();
+            let writer = capture.clone();
+            let format = Format::default().with_ansi(false).with_timer(Rfc3339Time::new(timezone));
+            let subscriber = tracing_subscriber::fmt()
+                .event_format(EventFormat::Full(format))
+                .with_writer(move || writer.clone())
+                .finish();
+            tracing::subscriber::with_default(subscriber, || info!("hello"));
+
+            let output = capture.output();
+            let (timestamp, rest) = output.split_once(' ').unwrap();
+            assert!(rest.trim_start().starts_with("INFO"), "{output}");
+            let time = chrono::DateTime::parse_from_rfc3339(timestamp).unwrap();
+            assert!((chrono::Utc::now() - time.to_utc()).num_seconds().abs() < 10, "{output}");
+            let (_, fraction) = timestamp.split_once('.').unwrap();
+            assert!(fraction[..6].bytes().all(|b| b.is_ascii_digit()), "{output}");
+
+            // UTC ends with `Z`, local time with the offset (`+00:00` for UTC).
+            let expected_offset = match timezone {
+                Timezone::Utc => "Z".to_owned(),
+                Timezone::Local => chrono::Local::now().format("%:z").to_string(),
+            };
+            assert_eq!(&fraction[6..], expected_offset, "{output}");
+        }
+    }
+
+    #[test]
+    fn color_mode() {
+        use std::ffi::OsStr;
+
+        let set = Some(OsStr::new("1"));
+        assert!(ColorMode::Auto.use_color(true, None));
+        assert!(ColorMode::Auto.use_color(true, Some(OsStr::new(""))));
+        assert!(!ColorMode::Auto.use_color(true, set));
+        assert!(!ColorMode::Auto.use_color(false, None));
+        assert!(ColorMode::Always.use_color(false, set));
+        assert!(!ColorMode::Never.use_color(true, None));
+    }
+
+    #[test]
+    fn anonymized_ips() {
+        let anonymize = |ip: &str| anonymize_ip(ip.parse().unwrap()).to_string();
+        assert_eq!(anonymize("192.168.17.42"), "192.168.17.0");
+        assert_eq!(anonymize("2001:db8:85a3:1234:8a2e:370:7334:1"), "2001:db8:85a3::");
+    }
+
+    #[test]
+    fn log_file_reopen() {
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("octoka-test-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("octoka.log");
+        let rotated = dir.join("octoka.log.1");
+
+        let file = LogFile::open(&path).unwrap();
+        (&file).write_all(b"before\n").unwrap();
+        std::fs::rename(&path, &rotated).unwrap();
+        (&file).write_all(b"still old\n").unwrap();
+        file.reopen().unwrap();
+        (&file).write_all(b"after\n").unwrap();
+
+        assert_eq!(std::fs::read_to_string(&rotated).unwrap(), "before\nstill old\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "after\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,14 +1,20 @@
 use std::{
     fs,
     io::{self, Write},
+    path::PathBuf,
 };
 
 use clap::Parser as _;
 
-use octoka::{cli::{Cli, Command}, config::{self, Config}, jwt, log, prelude::*};
+use octoka::{cli::{Cli, Command}, config::{self, Config}, http, jwt, log, prelude::*};
 
 
-#[cfg(not(target_env = "msvc"))]
+// The global allocator is chosen via cargo features, see `Cargo.toml`.
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+#[cfg(all(feature = "jemalloc", not(feature = "mimalloc"), not(target_env = "msvc")))]
 #[global_allocator]
 static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
@@ -17,8 +23,8 @@ static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 async fn main() -> Result<()> {
     let cli = Cli::parse();
     match cli.cmd {
-        Command::Check => {
-            run_check(&cli).await?;
+        Command::Check { ref validate_token } => {
+            run_check(&cli, validate_token.as_deref()).await?;
         }
 
         Command::GenConfigTemplate { out } => {
@@ -29,20 +35,40 @@ async fn main() -> Result<()> {
             }
         }
 
+        Command::PrintEffectiveConfig => {
+            // JWKS and Opencast URLs must not contain user info, but values
+            // read from files via env vars are likely secrets.
+            let config = load_config(&cli)?;
+            print!("{}", config::redacted_debug(&config));
+        }
+
         Command::Run => {
             let config = load_config_and_init_logger(&cli)?;
-            octoka::run_http_server(config).await?;
+            octoka::run_http_server(config, config_paths(&cli)?).await?;
         }
     }
 
     Ok(())
 }
 
+fn config_paths(cli: &Cli) -> Result<Vec<PathBuf>> {
+    if cli.config.is_empty() {
+        Ok(vec![config::default_path()?])
+    } else {
+        Ok(cli.config.clone())
+    }
+}
+
+fn load_config(cli: &Cli) -> Result<Config> {
+    let mut config = config::load_from(&config_paths(cli)?)?;
+    if let Some(level) = cli.log_level() {
+        config.log.override_level(level)?;
+    }
+    Ok(config)
+}
+
 fn load_config_and_init_logger(cli: &Cli) -> Result<Config> {
-    let config = match &cli.config {
-        None => config::load()?,
-        Some(path) => config::load_from(path)?,
-    };
+    let config = load_config(cli)?;
     log::init(&config.log, false).context("failed to setup logger")?;
     info!("Loaded config");
     info!("Initialized logger");
@@ -51,7 +77,7 @@ fn load_config_and_init_logger(cli: &Cli) -> Result<Config> {
 }
 
 /// Runs the `check` subcommand.
-async fn run_check(cli: &Cli) -> Result<()> {
+async fn run_check(cli: &Cli, token: Option<&str>) -> Result<()> {
     let config = load_config_and_init_logger(cli)
         .context("failed to load config: cannot proceed with `check` command")?;
 
@@ -67,6 +93,16 @@ async fn run_check(cli: &Cli) -> Result<()> {
     }
 
     let jwks_checks = jwt::run_check(&config.jwt).await;
+    let cors = http::check_cors(&config.http);
+    let token_check = match token {
+        // Failing to set up the JWT context is reported like the other
+        // checks instead of aborting them.
+        Some(token) => Some(match jwt::Context::new(&config.jwt).await {
+            Ok(jwt) => Ok(jwt.decode_and_verify(token).await),
+            Err(e) => Err(e.context("failed to set up JWT verification")),
+        }),
+        None => None,
+    };
 
     println!();
     println!();
@@ -75,5 +111,32 @@ async fn run_check(cli: &Cli) -> Result<()> {
         print_outcome(&format!("Fetch '{url}'"), outcome);
     }
 
+    if let Some(res) = token_check {
+        match res {
+            Err(e) => print_outcome::<()>("Token", Err(e)),
+            Ok(Ok(info)) => {
+                println!(" ▸ Token: ✔ valid");
+                let mut events = info.readable_events.iter().collect::<Vec<_>>();
+                events.sort();
+                println!("    admin: {}", info.is_admin);
+                println!("    readable events: {events:?}");
+                println!("    roles: {:?}", info.roles);
+                println!("    sub: {:?}", info.sub);
+            }
+            Ok(Err(e)) => {
+                println!(" ▸ Token: ✘ invalid");
+                println!("    {e}");
+            }
+        }
+    }
+
+    println!(" ▸ CORS policy:");
+    for line in &cors.policy {
+        println!("    {line}");
+    }
+    for warning in &cors.warnings {
+        println!("    ⚠ {warning}");
+    }
+
     Ok(())
 }
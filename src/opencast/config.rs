@@ -1,8 +1,8 @@
-use std::path::PathBuf;
+use std::{collections::HashMap, num::NonZeroU32, path::{Path, PathBuf}, time::Duration};
 
 use serde::Deserialize;
 
-use crate::config::HttpHost;
+use crate::{config::HttpHost, prelude::*};
 
 #[derive(Debug, confique::Config)]
 pub struct OpencastConfig {
@@ -11,6 +11,31 @@ pub struct OpencastConfig {
     /// is enabled).
     pub downloads_path: Option<PathBuf>,
 
+    /// Per-channel overrides of `downloads_path`, e.g. to serve
+    /// `engage-download` files from a different volume:
+    /// `{ "engage-download" = "/mnt/originals/downloads" }`. Each of these
+    /// directories must have the same layout as `downloads_path`, i.e.
+    /// `<org>/<channel>/<event-id>/...`. Channels not listed here use
+    /// `downloads_path`.
+    #[config(default = {})]
+    pub channel_downloads_paths: HashMap<String, PathBuf>,
+
+    /// How request paths are resolved to files when serving them (see
+    /// `http.on_allow = "file"`). In both modes, `.` and `..` are first
+    /// resolved lexically and paths escaping the event directory are
+    /// rejected.
+    ///
+    /// - "canonicalize": the path is canonicalized, resolving all symlinks,
+    ///   and checked again. This catches symlinks pointing outside the event
+    ///   directory, but costs one syscall per path component, which can be
+    ///   slow on network storage.
+    /// - "lexical": no further checks, only a single `stat` to see whether
+    ///   the file exists. Symlinks are followed without being checked, so only
+    ///   use this if nobody but Opencast can create files in the downloads
+    ///   directory.
+    #[config(default = "canonicalize")]
+    pub path_resolution: PathResolution,
+
     /// Host of Opencast. Currently used for `fallback`.
     #[config(default = "http://localhost:8080")]
     pub host: HttpHost,
@@ -20,8 +45,11 @@ pub struct OpencastConfig {
     ///
     /// - "none": no fallback, Opencast is not contacted.
     /// - "head": an HTTP HEAD request is sent to Opencast, with the same URI
-    ///   and headers as the incoming request. Octoka's behavior depends on
-    ///   Opencast's response:
+    ///   and headers as the incoming request, plus `X-Forwarded-For`,
+    ///   `X-Forwarded-Proto` and `X-Forwarded-Host` (existing values are kept;
+    ///   the client address is appended to `X-Forwarded-For`). The same
+    ///   headers are added to all other requests to Opencast, e.g. when
+    ///   proxying. Octoka's behavior depends on Opencast's response:
     ///   - 2xx: treat request as allowed
     ///   - 404: respond with 404
     ///   - 401: respond with 401, forwarding the www-authenticate header
@@ -45,6 +73,80 @@ pub struct OpencastConfig {
         validate = validate_path_prefixes,
     )]
     pub path_prefixes: Vec<String>,
+
+    /// Path to Opencast's `custom.properties`. If set, `path_prefixes` is
+    /// replaced at startup by the path of `org.opencastproject.download.url`
+    /// in that file (`/static` if the key is not set), so that both cannot get
+    /// out of sync. Only that key is read. Relative paths are relative to the
+    /// config file.
+    pub properties_file: Option<PathBuf>,
+
+    /// If set, request paths may omit the organization segment, i.e.
+    /// `/<prefix>/<channel>/<event-id>/...` is treated like
+    /// `/<prefix>/<default_org>/<channel>/<event-id>/...`. A path is
+    /// considered to omit the org if its first segment after the prefix is
+    /// not equal to this value. Only useful for single-tenant systems, e.g.
+    /// with "mh_default_org".
+    #[config(validate = validate_default_org)]
+    pub default_org: Option<String>,
+
+    /// If set, files that do not exist in the downloads directory are fetched
+    /// from `host` and stored in a local cache directory, from which they are
+    /// then served. This is useful if Opencast stores files elsewhere, e.g. in
+    /// S3. Only works with `http.on_allow = "file"`. The request to Opencast
+    /// has the same headers (including credentials) as the incoming request.
+    /// When the cache exceeds `max_size` (in bytes), the least recently used
+    /// files are deleted. Files larger than `max_size` are streamed through
+    /// to the client as a whole instead (ignoring `Range` headers). Concurrent
+    /// requests for the same file wait for a single download.
+    /// Example: `{ path = "/var/cache/octoka", max_size = 10_000_000_000 }`
+    pub read_through_cache: Option<ReadThroughCacheConfig>,
+
+    /// If set, protects against an unavailable Opencast when using `fallback`:
+    /// after `failures` consecutive failed fallback requests (connection
+    /// errors, timeouts or 5xx responses), Opencast is not asked anymore for
+    /// `cooldown` and access is denied right away instead. After that, a
+    /// single request is sent to Opencast as probe. If it succeeds, Opencast
+    /// is asked again as usual, otherwise the cooldown starts again.
+    /// Example: `{ failures = 5, cooldown = "30s" }`
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+}
+
+impl OpencastConfig {
+    /// Returns the downloads directory to use for the given channel.
+    pub fn downloads_path_for(&self, channel: &str) -> Option<&Path> {
+        self.channel_downloads_paths.get(channel)
+            .or(self.downloads_path.as_ref())
+            .map(|p| p.as_path())
+    }
+
+    /// If `properties_file` is set, derives `path_prefixes` from it.
+    pub(crate) fn apply_properties_file(&mut self) -> Result<()> {
+        let Some(path) = &self.properties_file else {
+            return Ok(());
+        };
+        let content = std::fs::read_to_string(path).with_context(|| format!(
+            "failed to read `opencast.properties_file` '{}'",
+            path.display(),
+        ))?;
+        let prefix = super::properties::path_prefix(&content).with_context(|| format!(
+            "failed to derive path prefix from '{}'",
+            path.display(),
+        ))?;
+        crate::config::validate_url_path(&prefix).map_err(|e| anyhow!(
+            "invalid path prefix '{prefix}' derived from '{}': {e}",
+            path.display(),
+        ))?;
+        self.path_prefixes = vec![prefix];
+        Ok(())
+    }
+}
+
+fn validate_default_org(org: &String) -> Result<(), &'static str> {
+    if org.is_empty() || org.contains('/') {
+        return Err("must be a single non-empty path segment");
+    }
+    crate::config::validate_url_path(&format!("/{org}"))
 }
 
 fn validate_path_prefixes(paths: &Vec<String>) -> Result<(), &'static str> {
@@ -58,6 +160,30 @@ fn validate_path_prefixes(paths: &Vec<String>) -> Result<(), &'static str> {
     Ok(())
 }
 
+/// See `opencast.read_through_cache`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReadThroughCacheConfig {
+    pub path: PathBuf,
+    pub max_size: u64,
+}
+
+/// See `opencast.circuit_breaker`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    pub failures: NonZeroU32,
+    #[serde(deserialize_with = "crate::config::deserialize_duration")]
+    pub cooldown: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathResolution {
+    Canonicalize,
+    Lexical,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum FallbackMode {
@@ -1,6 +1,41 @@
+use std::borrow::Cow;
+
 mod config;
+mod properties;
+
+pub use self::config::{
+    CircuitBreakerConfig, FallbackMode, OpencastConfig, PathResolution,
+    ReadThroughCacheConfig,
+};
+
+
+/// Returns the configured prefix (without leading or trailing slashes) that
+/// the given path starts with.
+fn find_prefix<'c>(path: &str, config: &'c OpencastConfig) -> Option<&'c str> {
+    let path = path.strip_prefix('/')?;
+    config.path_prefixes.iter()
+        .map(|prefix| prefix.trim_matches('/'))
+        .find(|prefix| path.starts_with(prefix))
+}
 
-pub use self::config::{FallbackMode, OpencastConfig};
+/// If `opencast.default_org` is set and the given path does not contain the
+/// org segment right after the prefix, returns the path with the default org
+/// inserted. Otherwise, the path is returned unchanged.
+pub fn insert_default_org<'a>(path: &'a str, config: &OpencastConfig) -> Cow<'a, str> {
+    let Some(org) = &config.default_org else {
+        return path.into();
+    };
+    let Some(prefix) = find_prefix(path, config) else {
+        return path.into();
+    };
+
+    // `rest` starts with a slash for well-formed paths.
+    let rest = &path[prefix.len() + 1..];
+    match rest.strip_prefix('/').and_then(|r| r.split('/').next()) {
+        Some(first_segment) if first_segment != org => format!("/{prefix}/{org}{rest}").into(),
+        _ => path.into(),
+    }
+}
 
 
 /// Represents a path to a static Opencast file split into its relevant
@@ -28,15 +63,9 @@ impl<'a> PathParts<'a> {
     /// is not understood, does not start with `/` or does not start with any
     /// prefxies configured in `opencast.path_prefixes`.
     pub fn parse(path: &'a str, config: &OpencastConfig) -> Option<Self> {
-        if !path.starts_with('/') {
-            return None;
-        }
-
         // Check if it starts with any of the configured prefixes and split
         // `path` into prefix part and rest.
-        let prefix = config.path_prefixes.iter()
-            .map(|prefix| prefix.trim_matches('/'))
-            .find(|prefix| path[1..].starts_with(prefix))?;
+        let prefix = find_prefix(path, config)?;
         let start_org = u16::try_from(prefix.len()).unwrap() + 1;
 
         let find_after = |start: u16| path[start as usize + 1..]
@@ -0,0 +1,126 @@
+//! Reading settings from Opencast's `custom.properties`, see
+//! `opencast.properties_file`.
+
+use std::collections::HashMap;
+
+use hyper::Uri;
+
+use crate::prelude::*;
+
+
+/// Key in `custom.properties` defining the URL under which distributed files
+/// are available.
+const DOWNLOAD_URL_KEY: &str = "org.opencastproject.download.url";
+
+/// Path of the download URL Opencast uses if `DOWNLOAD_URL_KEY` is not set.
+const DEFAULT_DOWNLOAD_PATH: &str = "/static";
+
+
+/// Derives the path prefix of static files from the given `custom.properties`
+/// content, e.g. `/static` for `${org.opencastproject.server.url}/static`.
+pub(super) fn path_prefix(content: &str) -> Result<String> {
+    let properties = parse(content);
+    let Some(url) = properties.get(DOWNLOAD_URL_KEY) else {
+        return Ok(DEFAULT_DOWNLOAD_PATH.into());
+    };
+
+    // The value usually starts with a placeholder for the server URL. Only
+    // the path is relevant to us.
+    let rest = match url.strip_prefix("${") {
+        Some(rest) => rest.split_once('}')
+            .ok_or_else(|| anyhow!("unterminated placeholder in `{DOWNLOAD_URL_KEY}`"))?
+            .1,
+        None => url,
+    };
+    let path = if rest.contains("://") {
+        let uri: Uri = rest.parse()
+            .with_context(|| format!("invalid URL in `{DOWNLOAD_URL_KEY}`"))?;
+        uri.path().to_owned()
+    } else {
+        rest.to_owned()
+    };
+
+    let path = path.trim_end_matches('/');
+    if !path.starts_with('/') {
+        bail!("`{DOWNLOAD_URL_KEY}` has no path ('{url}')");
+    }
+    Ok(path.to_owned())
+}
+
+/// Parses Java `.properties` content into key-value pairs. Supports comments,
+/// the separators `=`, `:` and whitespace, and line continuations. Other
+/// escape sequences are not interpreted, as Opencast's URLs and paths do not
+/// need them.
+fn parse(content: &str) -> HashMap<String, String> {
+    let mut out = HashMap::new();
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+        let mut line = line.trim_start().to_owned();
+        if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+            continue;
+        }
+
+        // An odd number of trailing backslashes continues the line.
+        while line.chars().rev().take_while(|c| *c == '\\').count() % 2 == 1 {
+            line.pop();
+            match lines.next() {
+                Some(next) => line.push_str(next.trim_start()),
+                None => break,
+            }
+        }
+
+        let key_end = line.find(['=', ':', ' ', '\t']).unwrap_or(line.len());
+        let (key, rest) = line.split_at(key_end);
+        let rest = rest.trim_start_matches([' ', '\t']);
+        let value = rest.strip_prefix(['=', ':']).unwrap_or(rest).trim_start();
+        out.insert(key.to_owned(), value.to_owned());
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, path_prefix};
+
+    const SAMPLE: &str = r#"
+# The HTTP server URL
+org.opencastproject.server.url=http://localhost:8080
+
+! Alternative comment style
+org.opencastproject.storage.dir : /srv/opencast
+org.opencastproject.download.directory=${org.opencastproject.storage.dir}/downloads
+org.opencastproject.download.url=${org.opencastproject.server.url}/static
+org.opencastproject.security.digest.user    opencast_system_account
+org.opencastproject.admin.ui.url=https://admin.example.com\
+    /admin-ng
+"#;
+
+    #[test]
+    fn parses_properties() {
+        let props = parse(SAMPLE);
+        assert_eq!(props["org.opencastproject.server.url"], "http://localhost:8080");
+        assert_eq!(props["org.opencastproject.storage.dir"], "/srv/opencast");
+        assert_eq!(
+            props["org.opencastproject.security.digest.user"],
+            "opencast_system_account",
+        );
+        assert_eq!(
+            props["org.opencastproject.admin.ui.url"],
+            "https://admin.example.com/admin-ng",
+        );
+        assert!(!props.keys().any(|key| key.starts_with('#') || key.starts_with('!')));
+    }
+
+    #[test]
+    fn derives_path_prefix() {
+        assert_eq!(path_prefix(SAMPLE).unwrap(), "/static");
+        assert_eq!(path_prefix("").unwrap(), "/static");
+
+        let with_url = |url: &str| path_prefix(&format!("org.opencastproject.download.url={url}"));
+        assert_eq!(with_url("https://cdn.example.com/files/").unwrap(), "/files");
+        assert_eq!(with_url("${org.opencastproject.server.url}/oc/static").unwrap(), "/oc/static");
+        assert!(with_url("https://cdn.example.com").is_err());
+        assert!(with_url("${org.opencastproject.server.url").is_err());
+    }
+}
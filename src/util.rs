@@ -1,7 +1,20 @@
+use std::path::{Component, Path, PathBuf};
+
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use hyper_util::client::legacy::{Client as HyperClient, connect::HttpConnector};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
+
+use crate::{opencast::PathResolution, prelude::*};
+
 
-use crate::prelude::*;
+/// Characters that are percent-encoded when building URI paths: everything
+/// except unreserved characters (RFC 3986) and `/`.
+pub const URI_PATH_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'/');
 
 
 pub type EmptyHttpBody = http_body_util::Empty<&'static [u8]>;
@@ -20,3 +33,111 @@ pub fn http_client() -> Result<SimpleHttpClient> {
     let out = HyperClient::builder(hyper_util::rt::TokioExecutor::new()).build(https);
     Ok(out)
 }
+
+/// Simple glob matching: `*` matches any (possibly empty) sequence of
+/// characters, including `/`. All other characters match literally.
+///
+/// This only ever remembers the last `*` for backtracking, so it runs in
+/// `O(pattern.len() * s.len())` in the worst case and is safe to use with
+/// untrusted input.
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let (pattern, s) = (pattern.as_bytes(), s.as_bytes());
+    let (mut pi, mut si) = (0, 0);
+    let mut last_star = None;
+
+    while si < s.len() {
+        if pattern.get(pi) == Some(&b'*') {
+            last_star = Some((pi, si));
+            pi += 1;
+        } else if pattern.get(pi) == Some(&s[si]) {
+            pi += 1;
+            si += 1;
+        } else if let Some((star_pi, star_si)) = last_star {
+            // Let the last star consume one more character and try again.
+            pi = star_pi + 1;
+            si = star_si + 1;
+            last_star = Some((star_pi, star_si + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[pi..].iter().all(|b| *b == b'*')
+}
+
+/// Resolves `.` and `..` components of the relative path without touching
+/// the file system. Returns `None` if the path is absolute or escapes its
+/// root, i.e. if a `..` has no preceding component to remove.
+pub fn normalize_lexically(rel_path: &Path) -> Option<PathBuf> {
+    let mut out = PathBuf::new();
+    for component in rel_path.components() {
+        match component {
+            Component::Normal(c) => out.push(c),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !out.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(out)
+}
+
+/// Joins `base` and the lexically normalized `rel_path` and resolves the
+/// result according to `mode`. Fails with `NotFound` if the file does not
+/// exist.
+pub fn resolve_path(
+    base: &Path,
+    rel_path: &Path,
+    mode: PathResolution,
+) -> std::io::Result<PathBuf> {
+    let path = base.join(rel_path);
+    match mode {
+        PathResolution::Canonicalize => path.canonicalize(),
+        PathResolution::Lexical => std::fs::metadata(&path).map(|_| path),
+    }
+}
+
+/// Converts a tokio `Instant` (which might be in the future) to wall-clock
+/// time, e.g. for displaying it.
+pub fn to_system_time(instant: tokio::time::Instant) -> std::time::SystemTime {
+    let now = tokio::time::Instant::now();
+    let system_now = std::time::SystemTime::now();
+    if instant >= now {
+        system_now + (instant - now)
+    } else {
+        system_now - (now - instant)
+    }
+}
+
+/// Converts wall-clock time to a tokio `Instant`, the inverse of
+/// `to_system_time`. Returns `None` if `time` is too far in the future to be
+/// represented as `Instant`.
+pub fn to_instant(time: std::time::SystemTime) -> Option<tokio::time::Instant> {
+    let now = tokio::time::Instant::now();
+    match time.duration_since(std::time::SystemTime::now()) {
+        Ok(until) => now.checked_add(until),
+        Err(e) => Some(now.checked_sub(e.duration()).unwrap_or(now)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::normalize_lexically;
+
+    #[test]
+    fn lexical_normalization() {
+        let normalize = |p: &str| normalize_lexically(Path::new(p));
+        assert_eq!(normalize("a/b/c"), Some("a/b/c".into()));
+        assert_eq!(normalize("a/./b//c"), Some("a/b/c".into()));
+        assert_eq!(normalize("a/x/../b"), Some("a/b".into()));
+        assert_eq!(normalize("a/../.."), None);
+        assert_eq!(normalize("../a"), None);
+        assert_eq!(normalize("/etc/passwd"), None);
+    }
+}
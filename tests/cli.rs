@@ -0,0 +1,169 @@
+//! Tests running the `octoka` binary.
+
+use std::{net::TcpListener, process::Command};
+
+use anyhow::Result;
+
+use self::common::JwksServer;
+
+mod common;
+
+
+/// Signed with `tests/jwks/ed25519.json`, payload:
+/// `{ "exp": 4012345678, "oc": { "e:ffff": ["read"], "e:abc123": ["read"] } }`
+const JWT_OC_CLAIM: &str = "eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCJ9.\
+    eyJleHAiOjQwMTIzNDU2NzgsIm9jIjp7ImU6ZmZmZiI6WyJyZWFkIl0sImU6YWJjMTIzIjpbInJlYWQiXX19.\
+    OKXJJJPztNijrkLQSJ67isUZo9ktGJkotMlidHe6Foo1yHtcEyA9967XljohpVZKPgtQf9Q7yJ-pbM8Eto2WAQ";
+
+
+#[test]
+fn print_effective_config() -> Result<()> {
+    let config_path = std::env::temp_dir()
+        .join(format!("octoka-test-config-{}.toml", std::process::id()));
+    std::fs::write(&config_path, r#"
+        opencast.fallback = "none"
+        jwt.trusted_keys = ["http://127.0.0.1:4055/ed25519.json"]
+        http.on_allow = "empty"
+        http.port = 4051
+    "#)?;
+    // Short secrets that also appear elsewhere in the output.
+    let secret_path = |name: &str| std::env::temp_dir()
+        .join(format!("octoka-test-config-{name}-{}", std::process::id()));
+    std::fs::write(secret_path("org"), "org\n")?;
+    std::fs::write(secret_path("timeout"), "3s\n")?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_octoka"))
+        .arg("--config")
+        .arg(&config_path)
+        .arg("print-effective-config")
+        .env("OCTOKA_HTTP__ADDRESS", "\"::1\"")
+        .env("OCTOKA_OPENCAST__DEFAULT_ORG_FILE", secret_path("org"))
+        .env("OCTOKA_HTTP__SHUTDOWN_TIMEOUT_FILE", secret_path("timeout"))
+        .output()?;
+    std::fs::remove_file(&config_path)?;
+    std::fs::remove_file(secret_path("org"))?;
+    std::fs::remove_file(secret_path("timeout"))?;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains("address: ::1"), "{stdout}");
+    assert!(stdout.contains("port: 4051"), "{stdout}");
+    assert!(stdout.contains("        default_org: <redacted>,\n"), "{stdout}");
+    assert!(stdout.contains("        shutdown_timeout: <redacted>,\n"), "{stdout}");
+    assert!(stdout.contains("        allowed_clock_skew: 3s,\n"), "{stdout}");
+    assert!(!stdout.contains(r#""org""#), "{stdout}");
+
+    Ok(())
+}
+
+#[test]
+fn multiple_config_files() -> Result<()> {
+    let dir = std::env::temp_dir();
+    let base_path = dir.join(format!("octoka-test-config-base-{}.toml", std::process::id()));
+    let overlay_path = dir.join(format!("octoka-test-config-overlay-{}.toml", std::process::id()));
+    std::fs::write(&base_path, r#"
+        opencast.fallback = "none"
+        jwt.trusted_keys = ["http://127.0.0.1:4055/ed25519.json"]
+        http.on_allow = "empty"
+        http.port = 4051
+        http.address = "::1"
+    "#)?;
+    std::fs::write(&overlay_path, r#"
+        http.port = 4052
+    "#)?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_octoka"))
+        .arg("--config")
+        .arg(&base_path)
+        .arg("--config")
+        .arg(&overlay_path)
+        .arg("print-effective-config")
+        .output()?;
+    std::fs::remove_file(&base_path)?;
+    std::fs::remove_file(&overlay_path)?;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains("port: 4052"), "{stdout}");
+    assert!(stdout.contains("address: ::1"), "{stdout}");
+    assert!(stdout.contains("fallback: None"), "{stdout}");
+
+    Ok(())
+}
+
+#[test]
+fn verbose_flag_overrides_log_level() -> Result<()> {
+    let config_path = std::env::temp_dir()
+        .join(format!("octoka-test-config-verbose-{}.toml", std::process::id()));
+    std::fs::write(&config_path, r#"
+        opencast.fallback = "none"
+        jwt.trusted_keys = ["http://127.0.0.1:4055/ed25519.json"]
+        http.on_allow = "empty"
+        log.filters = { octoka = "warn", "octoka::http::fs" = "off" }
+    "#)?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_octoka"))
+        .arg("--config")
+        .arg(&config_path)
+        .arg("print-effective-config")
+        .arg("-vv")
+        .output()?;
+    std::fs::remove_file(&config_path)?;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains(r#""octoka": LevelFilter::TRACE"#), "{stdout}");
+    assert!(stdout.contains(r#""octoka::http::fs": LevelFilter::OFF"#), "{stdout}");
+    assert!(!stdout.contains("LevelFilter::WARN"), "{stdout}");
+
+    Ok(())
+}
+
+#[test]
+fn check_validate_token() -> Result<()> {
+    let jwks_host = JwksServer::start().url;
+    let config_path = std::env::temp_dir()
+        .join(format!("octoka-test-config-check-{}.toml", std::process::id()));
+    std::fs::write(&config_path, format!(r#"
+        opencast.fallback = "none"
+        jwt.trusted_keys = ["{jwks_host}/ed25519.json"]
+        http.on_allow = "empty"
+    "#))?;
+    let check = |token: &str| Command::new(env!("CARGO_BIN_EXE_octoka"))
+        .arg("--config")
+        .arg(&config_path)
+        .args(["check", "--validate-token", token])
+        .output();
+
+    let output = check(JWT_OC_CLAIM)?;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains("Token: ✔ valid"), "{stdout}");
+    assert!(stdout.contains(r#"readable events: ["abc123", "ffff"]"#), "{stdout}");
+
+    // Same token with a different signature.
+    let (unsigned, _) = JWT_OC_CLAIM.rsplit_once('.').unwrap();
+    let invalid = format!("{unsigned}.{}", "A".repeat(86));
+    let output = check(&invalid)?;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains("Token: ✘ invalid"), "{stdout}");
+    assert!(!stdout.contains("readable events"), "{stdout}");
+
+    // Failing to set up JWT verification is reported as error of the check.
+    let closed_addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    std::fs::write(&config_path, format!(r#"
+        opencast.fallback = "none"
+        jwt.trusted_keys = ["http://{closed_addr}/jwks.json"]
+        jwt.fail_on_empty_jwks = true
+        http.on_allow = "empty"
+    "#))?;
+    let output = check(JWT_OC_CLAIM)?;
+    std::fs::remove_file(&config_path)?;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains("Token: ✘ error"), "{stdout}");
+    assert!(stdout.contains("CORS policy"), "{stdout}");
+
+    Ok(())
+}
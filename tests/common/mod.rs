@@ -0,0 +1,148 @@
+//! Helpers shared by the integration tests and benchmarks.
+
+#![allow(dead_code, reason = "not every test crate uses all helpers")]
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper_util::rt::TokioIo;
+
+
+/// Mock JWKS server serving the files in `tests/jwks`, counting the requests
+/// it receives. It runs on its own thread, so it can be used from sync and
+/// async code alike.
+pub struct JwksServer {
+    /// Base URL, e.g. `http://127.0.0.1:1234`.
+    pub url: String,
+    state: Arc<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// If set, this file is served for every path.
+    file: Mutex<Option<&'static str>>,
+
+    /// Number of requests per path.
+    counts: Mutex<HashMap<String, usize>>,
+
+    /// Number of requests currently being handled.
+    in_flight: AtomicUsize,
+
+    /// Maximum of `in_flight` so far.
+    max_in_flight: AtomicUsize,
+}
+
+impl JwksServer {
+    /// Starts a server serving the file named like the request path, e.g.
+    /// `tests/jwks/ed25519.json` for `/ed25519.json`.
+    pub fn start() -> Self {
+        Self::with_delay(Duration::ZERO)
+    }
+
+    /// Like `start`, but each response is delayed by `delay`.
+    pub fn with_delay(delay: Duration) -> Self {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(State::default());
+
+        let server_state = Arc::clone(&state);
+        std::thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            rt.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                while let Ok((stream, _)) = listener.accept().await {
+                    let state = Arc::clone(&server_state);
+                    let service = hyper::service::service_fn(move |req: http::Request<_>| {
+                        let state = Arc::clone(&state);
+                        async move {
+                            let path = req.uri().path().to_owned();
+                            let response = state.respond(path, delay).await;
+                            Ok::<_, Infallible>(response)
+                        }
+                    });
+                    tokio::spawn(
+                        hyper::server::conn::http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service)
+                    );
+                }
+            });
+        });
+
+        Self { url, state }
+    }
+
+    /// From now on, serves `file` (in `tests/jwks`) for every path, e.g. to
+    /// simulate a key rotation.
+    pub fn serve_for_all(&self, file: &'static str) {
+        *self.state.file.lock().unwrap() = Some(file);
+    }
+
+    /// Returns how often `path` was requested.
+    pub fn count(&self, path: &str) -> usize {
+        self.state.counts.lock().unwrap().get(path).copied().unwrap_or(0)
+    }
+
+    /// Returns the number of answered requests over all paths.
+    pub fn done(&self) -> usize {
+        let requests = self.state.counts.lock().unwrap().values().sum::<usize>();
+        requests - self.state.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Returns the maximum number of requests handled at the same time.
+    pub fn max_in_flight(&self) -> usize {
+        self.state.max_in_flight.load(Ordering::SeqCst)
+    }
+}
+
+impl State {
+    async fn respond(&self, path: String, delay: Duration) -> http::Response<Full<Bytes>> {
+        *self.counts.lock().unwrap().entry(path.clone()).or_default() += 1;
+        let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_flight.fetch_max(now, Ordering::SeqCst);
+        tokio::time::sleep(delay).await;
+
+        let name = self.file.lock().unwrap().map(|file| format!("/{file}")).unwrap_or(path);
+        let file = format!("{}/tests/jwks{name}", env!("CARGO_MANIFEST_DIR"));
+        let response = match std::fs::read(file) {
+            Ok(body) => http::Response::new(Full::new(Bytes::from(body))),
+            Err(_) => http::Response::builder().status(404).body(Full::default()).unwrap(),
+        };
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        response
+    }
+}
+
+
+/// Directory in the system's temp dir that is removed again when dropped,
+/// even if the test fails.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates a fresh, empty directory with a unique name starting with
+    /// `octoka-test-{name}`.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir()
+            .join(format!("octoka-test-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1 @@
+not really an MPEG-TS segment 0
@@ -0,0 +1,2280 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use anyhow::Result;
+use bytes::Bytes;
+use confique::Config as _;
+use http::StatusCode;
+use http_body_util::Full;
+use hyper_util::rt::TokioIo;
+use octoka::config::Config;
+use reqwest::{RequestBuilder, Response};
+use tokio::net::TcpListener;
+
+use self::common::TempDir;
+
+mod common;
+
+
+// ==============================================================================================
+// ===== Test setup stuff and utilities
+// ==============================================================================================
+
+/// JWT signed with `ed25519.json`, payload: `{ "exp": 4012345678, "roles": ["ROLE_ADMIN"] }`
+const JWT_ADMIN: &str = "eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCJ9.\
+    eyJleHAiOjQwMTIzNDU2NzgsInJvbGVzIjpbIlJPTEVfQURNSU4iXX0.\
+    6Bs6wdvBdWbszV38Lj81OmtW5ibutzUfTc8_X6k3yiwOHNQm5xQrWiILXGRP7eHFiI4Ju1FHu_NxufSUPiELAw";
+
+/// JWT signed with `ed25519.json`, payload:
+/// `{ "exp": 4012345678, "roles": ["ROLE_USER", "ROLE_STUDIO_LECTURER"] }`
+const JWT_STUDIO: &str = "eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCJ9.\
+    eyJleHAiOjQwMTIzNDU2NzgsInJvbGVzIjpbIlJPTEVfVVNFUiIsIlJPTEVfU1RVRElPX0xFQ1RVUkVSIl19.\
+    uyaVyIit33ukfEd3ABnpgoaoNsA3mIqMkXS2UuTjy56K6jBvOv2gMRjnrr8WcVEpO485s1q6ymdkpuu22D3aAQ";
+
+/// JWT signed with `ed25519.json`, payload: `{ "exp": 4012345678, "roles": ["ROLE_USER"] }`
+const JWT_USER: &str = "eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCJ9.\
+    eyJleHAiOjQwMTIzNDU2NzgsInJvbGVzIjpbIlJPTEVfVVNFUiJdfQ.\
+    _wHBNPRKhR2xSKRYwyXCRLEUQ0JCrnhUfFn380YwsqSLydxY0qHzxWkUB5CkCsrMLysorkyKxYHx-M9NyyGRCg";
+
+/// JWT signed with `ed25519.json`, payload:
+/// `{ "exp": 4012345678, "roles": ["ROLE_ADMIN"], "oc": { "e:abc123": ["read"] } }`
+const JWT_ADMIN_WITH_GRANT: &str = "eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCJ9.\
+    eyJleHAiOjQwMTIzNDU2NzgsInJvbGVzIjpbIlJPTEVfQURNSU4iXSwib2MiOnsiZTphYmMxMjMiOlsicmVhZCJdfX0.\
+    Kzu5sME-hhWpp4TK6wMfG8mDaq_lVDGBE_s8pLfuB2l-sb0m89gEgNtEUon7s3Y-j9fnwPT-7__pFn7iZsc7Cg";
+
+/// JWT signed with `ed25519.json` without `typ` header, payload:
+/// `{ "exp": 4012345678, "roles": ["ROLE_ADMIN"] }`
+const JWT_ADMIN_NO_TYP: &str = "eyJhbGciOiJFZERTQSJ9.\
+    eyJleHAiOjQwMTIzNDU2NzgsInJvbGVzIjpbIlJPTEVfQURNSU4iXX0.\
+    fWE8w6xJgOWAvaK7oNLZcSICFyKiP9Li_RFIhsVDqH1auilRFnvMmw2_BpCMisWuSayV2wvg8eZEtQ7DF6zHDA";
+
+/// JWT signed with `ed25519.json` with `typ` header "at+jwt", payload:
+/// `{ "exp": 4012345678, "roles": ["ROLE_ADMIN"] }`
+const JWT_ADMIN_AT_TYP: &str = "eyJhbGciOiJFZERTQSIsInR5cCI6ImF0K2p3dCJ9.\
+    eyJleHAiOjQwMTIzNDU2NzgsInJvbGVzIjpbIlJPTEVfQURNSU4iXX0.\
+    8VGUb5XbSiokb-p7jXYg0SdUuujXWs1RBxLMcCKEzl6FkOfT6LiwJxWjtQj2Uy68s50YDRxImyX6XNOWoQKxCQ";
+
+/// JWT signed with `ed25519.json` with `kid` header "unknown-kid", payload:
+/// `{ "exp": 4012345678, "roles": ["ROLE_ADMIN"] }`
+const JWT_ADMIN_UNKNOWN_KID: &str = "eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCIsImtpZCI6InVua25vd24ta2lkIn0.\
+    eyJleHAiOjQwMTIzNDU2NzgsInJvbGVzIjpbIlJPTEVfQURNSU4iXX0.\
+    iqns4DIp4B7fGlj7xBQeAUGzEqTO0dQGz04Yr2n2_l2uXliIvmw_KB2sMHo0pJlMzipwKdXv3I2ZHmG5bBOzDA";
+
+/// JWT signed with `ed25519.json`, payload:
+/// `{ "exp": 4012345678, "oc": { "e:abc123": ["read"], "x:foo": ["read"] } }`
+const JWT_UNKNOWN_OC_KIND: &str = "eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCJ9.\
+    eyJleHAiOjQwMTIzNDU2NzgsIm9jIjp7ImU6YWJjMTIzIjpbInJlYWQiXSwieDpmb28iOlsicmVhZCJdfX0.\
+    25pq6fbMODw4ETp5RT33DjLM4zDxmWdTVcKccKjIqgNBG7bpbWDRqtpHrr_SgOt36CTMNsVPWrsvBr69ERkRAQ";
+
+/// JWT signed with `ed25519.json`, bound to the DPoP key `dpop_key()`, payload:
+/// `{ "exp": 4012345678, "roles": ["ROLE_ADMIN"],
+///    "cnf": { "jkt": "--6IM5l0OosLj9yWskISYhUA3n_3CURQkmrYMSha_ck" } }`
+const JWT_ADMIN_CNF: &str = "eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCJ9.\
+    eyJleHAiOjQwMTIzNDU2NzgsInJvbGVzIjpbIlJPTEVfQURNSU4iXSwiY25mIjp7ImprdCI6Ii0tNklNNWwwT29zTGo5eVd\
+    za0lTWWhVQTNuXzNDVVJRa21yWU1TaGFfY2sifX0.\
+    z9yxLg55h9jXOqe--7FDm3t66UHWyi_VTSRd5726icaCj9k1zjhzkLf2GRe1NNhYnjX6SrZ03s4AiJg4Vn8qCQ";
+
+/// Like `JWT_ADMIN_CNF`, but bound to a different key, payload:
+/// `{ "exp": 4012345678, "roles": ["ROLE_ADMIN"],
+///    "cnf": { "jkt": "E916XTjJCK82vAibEGGhB3lDV7wANvlLfxiCNTqfo_c" } }`
+const JWT_ADMIN_CNF_OTHER: &str = "eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCJ9.\
+    eyJleHAiOjQwMTIzNDU2NzgsInJvbGVzIjpbIlJPTEVfQURNSU4iXSwiY25mIjp7ImprdCI6IkU5MTZYVGpKQ0s4MnZBaWJ\
+    FR0doQjNsRFY3d0FOdmxMZnhpQ05UcWZvX2MifX0.\
+    q9GU-aHAJYh0SD4qYJkgeEfIWTIHF0SLdJ8f2HllKIMAr6uWSxtv50T119Bh8-eOqDiEsWw70PqRjWGYOFKgDw";
+
+/// JWT signed with `ed25519.json`, payload:
+/// `{ "exp": 4012345678, "sub": "alice", "oc": { "e:abc123": ["read"] } }`
+const JWT_SUB: &str = "eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCJ9.\
+    eyJleHAiOjQwMTIzNDU2NzgsInN1YiI6ImFsaWNlIiwib2MiOnsiZTphYmMxMjMiOlsicmVhZCJdfX0.\
+    paVPbl_gqDuWXuITpnQlaL3Pg6h7H9b9AfkSKcW35Nn1If_k0BGMLVBwDri-2uIW6o4Ry4LdKS8zp9dz1YHjBw";
+
+const ORIGIN: &str = "https://tobira.example.com";
+
+struct TestSetup {
+    addr: SocketAddr,
+    client: reqwest::Client,
+}
+
+impl TestSetup {
+    /// Returns a request builder for the given path inside the test event.
+    fn get(&self, path: &str) -> RequestBuilder {
+        self.request(reqwest::Method::GET, path)
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        self.request_full(method, &format!("/static/org/channel/abc123/{path}"))
+    }
+
+    /// Returns a request builder for the given full path (including prefix).
+    fn request_full(&self, method: reqwest::Method, full_path: &str) -> RequestBuilder {
+        let addr = self.addr;
+        self.client.request(method, format!("http://{addr}{full_path}"))
+    }
+
+    /// Like `get`, but with the admin JWT attached.
+    fn get_authed(&self, path: &str) -> RequestBuilder {
+        self.get(path).bearer_auth(JWT_ADMIN)
+    }
+}
+
+/// Starts octoka with a basic test config, serving files from
+/// `tests/downloads`. `extra_config` is TOML and has priority over the basic
+/// config.
+async fn setup(extra_config: &str) -> Result<TestSetup> {
+    let downloads = format!("{}/tests/downloads", env!("CARGO_MANIFEST_DIR"));
+    let base_config = format!(r#"
+        opencast.fallback = "none"
+        opencast.downloads_path = "{downloads}"
+        jwt.trusted_keys = ["http://127.0.0.1:4055/ed25519.json"]
+        http.port = 0
+        log.filters.octoka = "trace"
+    "#);
+
+    let config = Config::builder()
+        .preloaded(toml::from_str(extra_config)?)
+        .preloaded(toml::from_str(&base_config)?)
+        .load()?;
+    let _ = octoka::log::init(&config.log, true);
+
+    let (addr, server) = octoka::test_http_server(config).await?;
+    tokio::spawn(server);
+    Ok(TestSetup {
+        addr,
+        client: reqwest::Client::new(),
+    })
+}
+
+type MockResponse = http::Response<Full<Bytes>>;
+
+/// Starts a mock Opencast server that answers all requests with `handler`.
+/// Returns the value for `opencast.host`.
+async fn mock_opencast<F>(handler: F) -> Result<String>
+where
+    F: Fn(http::Request<hyper::body::Incoming>) -> MockResponse + Clone + Send + Sync + 'static,
+{
+    delayed_mock_opencast(std::time::Duration::ZERO, handler).await
+}
+
+/// Like `mock_opencast`, but each response is delayed by `delay`.
+async fn delayed_mock_opencast<F>(delay: std::time::Duration, handler: F) -> Result<String>
+where
+    F: Fn(http::Request<hyper::body::Incoming>) -> MockResponse + Clone + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let handler = handler.clone();
+            let service = hyper::service::service_fn(move |req| {
+                let response = handler(req);
+                async move {
+                    tokio::time::sleep(delay).await;
+                    Ok::<_, Infallible>(response)
+                }
+            });
+            tokio::spawn(
+                hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+            );
+        }
+    });
+    Ok(format!("http://{addr}"))
+}
+
+async fn send(req: RequestBuilder) -> Result<Response> {
+    req.send().await.map_err(Into::into)
+}
+
+fn header<'a>(resp: &'a Response, name: &str) -> Option<&'a str> {
+    resp.headers().get(name).map(|v| v.to_str().unwrap())
+}
+
+
+
+// ==============================================================================================
+// ===== Tests
+// ==============================================================================================
+
+#[tokio::test]
+async fn timing_allow_origin() -> Result<()> {
+    let config = format!(r#"
+        http.cors_allowed_origins = ["{ORIGIN}"]
+        http.timing_allow_origin = true
+    "#);
+    let setup = setup(&config).await?;
+
+    let resp = send(setup.get_authed("hello.txt").header("Origin", ORIGIN)).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "timing-allow-origin"), Some(ORIGIN));
+
+    let resp = send(setup.get_authed("hello.txt").header("Origin", "https://evil.com")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "timing-allow-origin"), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn cors_preflight_on_deny() -> Result<()> {
+    let preflight = |setup: &TestSetup, origin: &str| setup
+        .request(reqwest::Method::OPTIONS, "hello.txt")
+        .header("Origin", origin)
+        .header("Access-Control-Request-Method", "GET")
+        .header("Access-Control-Request-Headers", "Authorization");
+
+    // Default: 204 without CORS headers
+    let default = setup(&format!(r#"http.cors_allowed_origins = ["{ORIGIN}"]"#)).await?;
+    let resp = send(preflight(&default, "https://evil.com")).await?;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(header(&resp, "access-control-allow-origin"), None);
+    let resp = send(preflight(&default, ORIGIN)).await?;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(header(&resp, "access-control-allow-origin"), Some(ORIGIN));
+
+    // Forbidden mode
+    let forbidden = setup(&format!(r#"
+        http.cors_allowed_origins = ["{ORIGIN}"]
+        http.cors_preflight_on_deny = "forbidden"
+    "#)).await?;
+    let resp = send(preflight(&forbidden, "https://evil.com")).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = send(preflight(&forbidden, ORIGIN)).await?;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(header(&resp, "access-control-allow-origin"), Some(ORIGIN));
+
+    // Non-preflight OPTIONS requests are not affected
+    let resp = send(forbidden.request(reqwest::Method::OPTIONS, "hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn options_matrix() -> Result<()> {
+    let setup = setup(&format!(r#"
+        http.cors_allowed_origins = ["{ORIGIN}"]
+        http.cors_preflight_on_deny = "forbidden"
+        http.admin_endpoints = true
+    "#)).await?;
+    let preflight = |req: RequestBuilder, origin: &str| req
+        .header("Origin", origin)
+        .header("Access-Control-Request-Method", "GET")
+        .header("Access-Control-Request-Headers", "Authorization");
+
+    // Content paths: CORS preflight, whether or not the file exists.
+    for path in ["hello.txt", "does-not-exist.txt"] {
+        let req = setup.request(reqwest::Method::OPTIONS, path);
+        let resp = send(preflight(req, ORIGIN)).await?;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(header(&resp, "allow"), Some("GET, HEAD, OPTIONS"));
+        assert_eq!(header(&resp, "access-control-allow-origin"), Some(ORIGIN));
+
+        let req = setup.request(reqwest::Method::OPTIONS, path);
+        let resp = send(preflight(req, "https://evil.com")).await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = send(setup.request(reqwest::Method::OPTIONS, path)).await?;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(header(&resp, "access-control-allow-origin"), None);
+    }
+
+    // Admin endpoints and unhandled paths: plain 204, never CORS headers.
+    for full_path in ["/-/keys", "/-/verify-token", "/", "/foo/bar.txt"] {
+        for origin in [ORIGIN, "https://evil.com"] {
+            let req = setup.request_full(reqwest::Method::OPTIONS, full_path);
+            let resp = send(preflight(req, origin)).await?;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT, "{full_path}");
+            assert_eq!(header(&resp, "allow"), Some("GET, HEAD, OPTIONS"));
+            assert_eq!(header(&resp, "access-control-allow-origin"), None);
+        }
+        let resp = send(setup.request_full(reqwest::Method::OPTIONS, full_path)).await?;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn cache_rules() -> Result<()> {
+    let setup = setup(r#"
+        http.cache_rules = [
+            { pattern = "*.m3u8", value = "max-age=2" },
+            { pattern = "hls/*.ts", value = "max-age=31536000, immutable" },
+        ]
+    "#).await?;
+
+    let resp = send(setup.get_authed("hls/playlist.m3u8")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "cache-control"), Some("max-age=2"));
+
+    let resp = send(setup.get_authed("hls/segment0.ts")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "cache-control"), Some("max-age=31536000, immutable"));
+
+    let resp = send(setup.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "cache-control"), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn default_org() -> Result<()> {
+    let get = |setup: &TestSetup, path| setup
+        .request_full(reqwest::Method::GET, path)
+        .bearer_auth(JWT_ADMIN);
+
+    // Without `default_org`, the org segment is required.
+    let setup_without = setup("").await?;
+    let resp = send(get(&setup_without, "/static/channel/abc123/hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let setup_with = setup(r#"opencast.default_org = "org""#).await?;
+    for path in ["/static/org/channel/abc123/hello.txt", "/static/channel/abc123/hello.txt"] {
+        let resp = send(get(&setup_with, path)).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.text().await?, "Hello octoka!\n");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn role_rules() -> Result<()> {
+    let matching = setup(r#"
+        auth.role_rules = [
+            { role = "ROLE_STUDIO_*", channel = "channel" },
+            { role = "ROLE_USER", org = "other_org" },
+        ]
+    "#).await?;
+    let resp = send(matching.get("hello.txt").bearer_auth(JWT_STUDIO)).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = send(matching.get("hello.txt").bearer_auth(JWT_USER)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let other_channel = setup(r#"
+        auth.role_rules = [{ role = "ROLE_STUDIO_*", channel = "internal" }]
+    "#).await?;
+    let resp = send(other_channel.get("hello.txt").bearer_auth(JWT_STUDIO)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn allow_admin_override() -> Result<()> {
+    let default = setup("").await?;
+    let resp = send(default.get("hello.txt").bearer_auth(JWT_ADMIN)).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let no_override = setup("auth.allow_admin_override = false").await?;
+    let resp = send(no_override.get("hello.txt").bearer_auth(JWT_ADMIN)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = send(no_override.get("hello.txt").bearer_auth(JWT_ADMIN_WITH_GRANT)).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn bearer_prefix_is_case_insensitive() -> Result<()> {
+    let setup = setup("").await?;
+    for scheme in ["Bearer", "bearer", "BEARER"] {
+        let req = setup.get("hello.txt").header("authorization", format!("{scheme} {JWT_ADMIN}"));
+        let resp = send(req).await?;
+        assert_eq!(resp.status(), StatusCode::OK, "scheme {scheme}");
+    }
+
+    let resp = send(setup.get("hello.txt").header("authorization", format!("Basic {JWT_ADMIN}")))
+        .await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn duplicate_jwt_headers() -> Result<()> {
+    let duplicates = |setup: &TestSetup| setup.get("hello.txt")
+        .header("authorization", format!("Bearer {JWT_USER}"))
+        .header("authorization", format!("Bearer {JWT_ADMIN}"));
+
+    // Default: only the first header is used
+    let first = setup("").await?;
+    assert_eq!(send(duplicates(&first)).await?.status(), StatusCode::FORBIDDEN);
+
+    let reject = setup(r#"http.duplicate_jwt_headers = "reject""#).await?;
+    assert_eq!(send(duplicates(&reject)).await?.status(), StatusCode::BAD_REQUEST);
+    let resp = send(reject.get("hello.txt")
+        .header("authorization", "Basic Zm9vOmJhcg==")
+        .header("authorization", format!("Bearer {JWT_ADMIN}"))).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let try_each = setup(r#"http.duplicate_jwt_headers = "try-each""#).await?;
+    assert_eq!(send(duplicates(&try_each)).await?.status(), StatusCode::OK);
+    let resp = send(try_each.get("hello.txt")
+        .header("authorization", format!("Bearer {JWT_USER}"))
+        .header("authorization", "Bearer not-a-jwt")).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn query_token_encodings() -> Result<()> {
+    let setup = setup("").await?;
+    let encode_all = |s: &str| s.bytes().map(|b| format!("%{b:02x}")).collect::<String>();
+    let cases = [
+        (format!("jwt={JWT_ADMIN}"), StatusCode::OK),
+        (format!("jwt={}", JWT_ADMIN.replace('.', "%2E")), StatusCode::OK),
+        (format!("jwt={}", encode_all(JWT_ADMIN)), StatusCode::OK),
+        (format!("jwt={}", encode_all(JWT_ADMIN).to_uppercase()), StatusCode::OK),
+        (format!("j%77t={JWT_ADMIN}"), StatusCode::OK),
+        (format!("foo=bar&jwt={JWT_ADMIN}&jwt=invalid"), StatusCode::OK),
+        (format!("jwt=invalid&jwt={JWT_ADMIN}"), StatusCode::FORBIDDEN),
+
+        // Double encoded
+        (format!("jwt={}", JWT_ADMIN.replace('.', "%252E")), StatusCode::FORBIDDEN),
+
+        // `+` is decoded as space
+        (format!("jwt=+{JWT_ADMIN}"), StatusCode::FORBIDDEN),
+        (format!("jwt=%2B{JWT_ADMIN}"), StatusCode::FORBIDDEN),
+    ];
+
+    for (query, expected) in cases {
+        let resp = send(setup.get(&format!("hello.txt?{query}"))).await?;
+        assert_eq!(resp.status(), expected, "query: {query}");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn challenge_on_missing() -> Result<()> {
+    // Default: always 403
+    let default = setup("").await?;
+    let resp = send(default.get("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(header(&resp, "www-authenticate"), None);
+
+    let challenge = setup("http.challenge_on_missing = true").await?;
+    let resp = send(challenge.get("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(header(&resp, "www-authenticate"), Some("Bearer"));
+
+    // Token present, but not granting access
+    let resp = send(challenge.get("hello.txt").bearer_auth(JWT_USER)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(header(&resp, "www-authenticate"), None);
+    let resp = send(challenge.get("hello.txt").bearer_auth("not-a-jwt")).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn unmatched_paths() -> Result<()> {
+    let get = |setup: &TestSetup, path| send(setup.request_full(reqwest::Method::GET, path));
+
+    // Default: 400
+    let default = setup("").await?;
+    assert_eq!(get(&default, "/favicon.ico").await?.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(get(&default, "/robots.txt").await?.status(), StatusCode::BAD_REQUEST);
+
+    let robots = format!("{}/tests/static-files/robots.txt", env!("CARGO_MANIFEST_DIR"));
+    let not_found = setup(&format!(r#"
+        http.passthrough_unmatched = "not-found"
+        http.unmatched_files = {{ "/robots.txt" = "{robots}" }}
+    "#)).await?;
+    assert_eq!(get(&not_found, "/favicon.ico").await?.status(), StatusCode::NOT_FOUND);
+    let resp = get(&not_found, "/robots.txt").await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "content-type"), Some("text/plain"));
+    assert_eq!(resp.text().await?, "User-agent: *\nDisallow: /\n");
+
+    let oc_host = mock_opencast(|req| {
+        let body = format!("OC says hi to {}", req.uri());
+        http::Response::builder().status(StatusCode::IM_A_TEAPOT).body(body.into()).unwrap()
+    }).await?;
+    let proxy = setup(&format!(r#"
+        http.passthrough_unmatched = "proxy"
+        opencast.host = "{oc_host}"
+    "#)).await?;
+    let resp = get(&proxy, "/favicon.ico?foo=bar").await?;
+    assert_eq!(resp.status(), StatusCode::IM_A_TEAPOT);
+    assert_eq!(resp.text().await?, "OC says hi to /favicon.ico?foo=bar");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn channel_downloads_paths() -> Result<()> {
+    let path = "/static/org/internal/abc123/secret.txt";
+    let get = |setup: &TestSetup| send(
+        setup.request_full(reqwest::Method::GET, path).bearer_auth(JWT_ADMIN)
+    );
+
+    let default = setup("").await?;
+    assert_eq!(get(&default).await?.status(), StatusCode::NOT_FOUND);
+
+    let internal = format!("{}/tests/downloads-internal", env!("CARGO_MANIFEST_DIR"));
+    let with_channel = setup(&format!(r#"
+        opencast.channel_downloads_paths = {{ internal = "{internal}" }}
+    "#)).await?;
+    let resp = get(&with_channel).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.text().await?, "Internal original\n");
+
+    // Other channels still use the global path.
+    let resp = send(with_channel.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn percent_encoded_paths() -> Result<()> {
+    // The file is called `my file ä.txt`.
+    let paths = [
+        "my%20file%20%C3%A4.txt",
+        "my%20file%20%c3%a4.txt",
+        "%6Dy%20file%20%C3%A4.txt",
+    ];
+
+    let file = setup("").await?;
+    for path in paths {
+        let resp = send(file.get_authed(path)).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.text().await?, "Spaces and umlauts\n");
+    }
+
+    let x_accel = setup(r#"http.on_allow = "x-accel-redirect:/protected""#).await?;
+    for path in paths {
+        let resp = send(x_accel.get_authed(path)).await?;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            header(&resp, "x-accel-redirect"),
+            Some("/protected/org/channel/abc123/my%20file%20%C3%A4.txt"),
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn error_body_content_negotiation() -> Result<()> {
+    let setup = setup("").await?;
+    let get = |accept| send(setup.get("hello.txt").header("Accept", accept));
+
+    let json_accepts = ["application/json", "application/problem+json", "text/plain;q=0.5, application/*"];
+    for accept in json_accepts {
+        let resp = get(accept).await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(header(&resp, "content-type"), Some("application/problem+json"));
+        assert_eq!(header(&resp, "vary"), Some("Accept"));
+        let body: serde_json::Value = serde_json::from_str(&resp.text().await?)?;
+        assert_eq!(body["status"], 403);
+        assert_eq!(body["title"], "Forbidden");
+    }
+
+    let text_accepts = ["text/plain", "text/html,application/xml;q=0.9,*/*;q=0.8", "*/*", "image/png"];
+    for accept in text_accepts {
+        let resp = get(accept).await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(header(&resp, "content-type"), Some("text/plain; charset=utf-8"));
+        assert_eq!(header(&resp, "vary"), Some("Accept"));
+        assert_eq!(resp.text().await?, "403 Forbidden");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn charset_types() -> Result<()> {
+    let default = setup("").await?;
+    let resp = send(default.get_authed("captions.vtt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "content-type"), Some("text/vtt; charset=utf-8"));
+    let resp = send(default.get_authed("hls/playlist.m3u8")).await?;
+    assert_eq!(header(&resp, "content-type"), Some("audio/x-mpegurl"));
+
+    let disabled = setup("http.charset_types = []").await?;
+    let resp = send(disabled.get_authed("captions.vtt")).await?;
+    assert_eq!(header(&resp, "content-type"), Some("text/vtt"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_verify_token() -> Result<()> {
+    let disabled = setup("").await?;
+    let resp = send(disabled.request_full(reqwest::Method::GET, "/-/verify-token")
+        .bearer_auth(JWT_ADMIN)).await?;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let enabled = setup("http.admin_endpoints = true").await?;
+    let verify = |jwt: Option<&str>| {
+        let req = enabled.request_full(reqwest::Method::GET, "/-/verify-token");
+        match jwt {
+            Some(jwt) => req.bearer_auth(jwt),
+            None => req,
+        }
+    };
+
+    let resp = send(verify(Some(JWT_ADMIN))).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "content-type"), Some("application/json"));
+    let body: serde_json::Value = serde_json::from_str(&resp.text().await?)?;
+    assert_eq!(body["valid"], true);
+    assert_eq!(body["source"], "http://127.0.0.1:4055/ed25519.json");
+    assert_eq!(body["kid"], serde_json::Value::Null);
+    assert_eq!(body["alg"], "EdDSA");
+    assert_eq!(body["is_admin"], true);
+
+    // Payload of one token with signature of another
+    let (_, admin_signature) = JWT_ADMIN.rsplit_once('.').unwrap();
+    let (user_message, _) = JWT_USER.rsplit_once('.').unwrap();
+    let tampered = format!("{user_message}.{admin_signature}");
+    let resp = send(verify(Some(&tampered))).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_str(&resp.text().await?)?;
+    assert_eq!(body["valid"], false);
+    assert!(body["error"].is_string());
+
+    let resp = send(verify(None)).await?;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp = send(enabled.request_full(reqwest::Method::GET, "/-/nope")).await?;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_header() -> Result<()> {
+    let default = setup("").await?;
+    let resp = send(default.get_authed("hello.txt")).await?;
+    assert_eq!(header(&resp, "server"), None);
+
+    // Also removed from proxied responses
+    let oc_host = mock_opencast(|_| {
+        http::Response::builder().header("server", "Jetty(9.4)").body("ok".into()).unwrap()
+    }).await?;
+    let proxy = setup(&format!(r#"
+        opencast.host = "{oc_host}"
+        http.passthrough_unmatched = "proxy"
+    "#)).await?;
+    let resp = send(proxy.request_full(reqwest::Method::GET, "/engage/ui/")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "server"), None);
+
+    let custom = setup(r#"http.server_header = "octoka""#).await?;
+    let resp = send(custom.get_authed("hello.txt")).await?;
+    assert_eq!(header(&resp, "server"), Some("octoka"));
+    let resp = send(custom.get("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(header(&resp, "server"), Some("octoka"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn if_range_with_subsecond_mtime() -> Result<()> {
+    // Modified half a second after a full second. HTTP dates only have second
+    // resolution, so `Last-Modified` is the truncated value.
+    let downloads = std::env::temp_dir()
+        .join(format!("octoka-test-subsecond-mtime-{}", std::process::id()));
+    let event_dir = downloads.join("org/channel/abc123");
+    std::fs::create_dir_all(&event_dir)?;
+    let file_path = event_dir.join("ranges.txt");
+    std::fs::write(&file_path, "0123456789\n")?;
+    let second = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+    std::fs::File::options().write(true).open(&file_path)?
+        .set_modified(second + std::time::Duration::from_millis(500))?;
+    let date = httpdate::fmt_http_date(second);
+    let earlier_date = httpdate::fmt_http_date(second - std::time::Duration::from_secs(1));
+
+    let setup = setup(&format!(
+        "opencast.downloads_path = {:?}",
+        downloads.to_str().unwrap(),
+    )).await?;
+    let resp = send(setup.get_authed("ranges.txt")).await?;
+    assert_eq!(header(&resp, "last-modified"), Some(date.as_str()));
+    let etag = header(&resp, "etag").unwrap().to_owned();
+
+    let resp = send(setup.get_authed("ranges.txt").header("if-modified-since", &date)).await?;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    let resp = send(setup.get_authed("ranges.txt").header("if-modified-since", &earlier_date))
+        .await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let range = |if_range: &str| {
+        setup.get_authed("ranges.txt").header("range", "bytes=2-4").header("if-range", if_range)
+    };
+    let resp = send(range(&date)).await?;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(resp.text().await?, "234");
+    let resp = send(range(&earlier_date)).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.text().await?, "0123456789\n");
+    let resp = send(range(&etag)).await?;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    let resp = send(range(&format!("W/{etag}"))).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    std::fs::remove_dir_all(&downloads)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn allowed_referers() -> Result<()> {
+    let default = setup("").await?;
+    let resp = send(default.get_authed("hello.txt").header("referer", "https://evil.example/"))
+        .await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let restricted = setup(&format!(r#"http.allowed_referers = ["{ORIGIN}"]"#)).await?;
+    let get = |referer: &str| restricted.get_authed("hello.txt").header("referer", referer);
+    let resp = send(get(&format!("{ORIGIN}/!v/abc123"))).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = send(get("https://evil.example/tobira.example.com")).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = send(get("http://tobira.example.com/")).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = send(get("not a url")).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Missing referer is allowed
+    let resp = send(restricted.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn proxy_keeps_cache_headers() -> Result<()> {
+    const CACHE_HEADERS: [(&str, &str); 5] = [
+        ("age", "42"),
+        ("cache-control", "public, max-age=600"),
+        ("expires", "Wed, 21 Oct 2037 07:28:00 GMT"),
+        ("etag", "\"oc-etag\""),
+        ("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT"),
+    ];
+    let oc_host = mock_opencast(|_| {
+        let mut builder = http::Response::builder();
+        for (name, value) in CACHE_HEADERS {
+            builder = builder.header(name, value);
+        }
+        builder.body("ok".into()).unwrap()
+    }).await?;
+    let proxy = setup(&format!(r#"
+        opencast.host = "{oc_host}"
+        http.passthrough_unmatched = "proxy"
+    "#)).await?;
+
+    let resp = send(proxy.request_full(reqwest::Method::GET, "/engage/ui/")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    for (name, value) in CACHE_HEADERS {
+        assert_eq!(header(&resp, name), Some(value), "header {name}");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn strip_response_headers() -> Result<()> {
+    let oc_host = mock_opencast(|_| {
+        http::Response::builder()
+            .header("x-powered-by", "Karaf")
+            .header("x-internal-route", "node-3")
+            .header("cache-control", "no-cache")
+            .body("ok".into())
+            .unwrap()
+    }).await?;
+    let proxy = setup(&format!(r#"
+        opencast.host = "{oc_host}"
+        http.passthrough_unmatched = "proxy"
+        http.strip_response_headers = ["X-Powered-By", "x-internal-route"]
+    "#)).await?;
+
+    let resp = send(proxy.request_full(reqwest::Method::GET, "/engage/ui/")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "x-powered-by"), None);
+    assert_eq!(header(&resp, "x-internal-route"), None);
+    assert_eq!(header(&resp, "cache-control"), Some("no-cache"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn unavailable_downloads_dir() -> Result<()> {
+    let downloads = std::env::temp_dir()
+        .join(format!("octoka-test-downloads-{}", std::process::id()));
+    let event_dir = downloads.join("org/channel/abc123");
+    std::fs::create_dir_all(&event_dir)?;
+    std::fs::write(event_dir.join("hello.txt"), "Hello octoka!\n")?;
+
+    let setup = setup(&format!(
+        "opencast.downloads_path = {:?}",
+        downloads.to_str().unwrap(),
+    )).await?;
+    let resp = send(setup.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = send(setup.get_authed("missing.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // Simulate the storage being unmounted
+    std::fs::remove_dir_all(&downloads)?;
+    let resp = send(setup.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let resp = send(setup.get_authed("missing.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn path_resolution_modes() -> Result<()> {
+    let downloads = std::env::temp_dir()
+        .join(format!("octoka-test-path-resolution-{}", std::process::id()));
+    let event_dir = downloads.join("org/channel/abc123");
+    let other_dir = downloads.join("org/channel/other");
+    std::fs::create_dir_all(&event_dir)?;
+    std::fs::create_dir_all(&other_dir)?;
+    std::fs::write(event_dir.join("hello.txt"), "Hello octoka!\n")?;
+    std::fs::write(other_dir.join("secret.txt"), "secret\n")?;
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("../other/secret.txt", event_dir.join("link.txt"))?;
+
+    for mode in ["canonicalize", "lexical"] {
+        let setup = setup(&format!(
+            "opencast.downloads_path = {:?}\nopencast.path_resolution = {mode:?}",
+            downloads.to_str().unwrap(),
+        )).await?;
+        for (path, expected) in [
+            ("hello.txt", StatusCode::OK),
+            ("missing%2F..%2Fhello.txt", StatusCode::OK),
+            ("missing.txt", StatusCode::NOT_FOUND),
+            ("..%2Fother%2Fsecret.txt", StatusCode::BAD_REQUEST),
+            ("..%2F..%2F..%2F..%2Fetc%2Fpasswd", StatusCode::BAD_REQUEST),
+        ] {
+            let resp = send(setup.get_authed(path)).await?;
+            assert_eq!(resp.status(), expected, "mode: {mode}, path: {path}");
+        }
+
+        // Only canonicalization catches symlinks pointing outside the event
+        // directory.
+        #[cfg(unix)]
+        {
+            let expected = match mode {
+                "lexical" => StatusCode::OK,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            let resp = send(setup.get_authed("link.txt")).await?;
+            assert_eq!(resp.status(), expected, "mode: {mode}");
+        }
+    }
+
+    std::fs::remove_dir_all(&downloads)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn growing_file() -> Result<()> {
+    use std::io::Write as _;
+
+    let downloads = std::env::temp_dir()
+        .join(format!("octoka-test-growing-{}", std::process::id()));
+    let event_dir = downloads.join("org/channel/abc123");
+    std::fs::create_dir_all(&event_dir)?;
+    let file_path = event_dir.join("live.ts");
+
+    // Large enough that the body is not completely sent before the file grows.
+    const SIZE: usize = 8 * 1024 * 1024;
+    std::fs::write(&file_path, vec![b'a'; SIZE])?;
+    let append = || -> Result<()> {
+        std::fs::File::options().append(true).open(&file_path)?.write_all(&[b'b'; 4096])?;
+        Ok(())
+    };
+
+    let setup = setup(&format!(
+        "opencast.downloads_path = {:?}",
+        downloads.to_str().unwrap(),
+    )).await?;
+
+    // Full file: the body matches `Content-Length` from when it was opened.
+    let resp = send(setup.get_authed("live.ts")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let expected_len = SIZE.to_string();
+    assert_eq!(header(&resp, "content-length"), Some(expected_len.as_str()));
+    append()?;
+    let body = resp.bytes().await?;
+    assert_eq!(body.len(), SIZE);
+    assert!(body.iter().all(|b| *b == b'a'));
+
+    // Range within the size known when opening the file.
+    let current_size = SIZE + 4096;
+    let range = format!("bytes={}-{}", current_size - 10, current_size - 1);
+    let resp = send(setup.get_authed("live.ts").header("range", range)).await?;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    append()?;
+    assert_eq!(resp.bytes().await?, [b'b'; 10].as_slice());
+
+    std::fs::remove_dir_all(&downloads)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_open_files() -> Result<()> {
+    let downloads = std::env::temp_dir()
+        .join(format!("octoka-test-open-files-{}", std::process::id()));
+    let event_dir = downloads.join("org/channel/abc123");
+    std::fs::create_dir_all(&event_dir)?;
+    // Large enough that the body is not completely sent while not reading it.
+    std::fs::write(event_dir.join("large.mp4"), vec![0u8; 8 * 1024 * 1024])?;
+    std::fs::write(event_dir.join("small.txt"), "small")?;
+
+    let setup = setup(&format!(r#"
+        opencast.downloads_path = {:?}
+        http.max_open_files = 1
+        http.open_file_wait = "500ms"
+    "#, downloads.to_str().unwrap())).await?;
+
+    // The first file stays open while its body is not read, so the second
+    // request times out waiting for a free slot.
+    let large = send(setup.get_authed("large.mp4")).await?;
+    assert_eq!(large.status(), StatusCode::OK);
+    let resp = send(setup.get_authed("small.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // A queued request gets the slot once the first response is done.
+    let queued = tokio::spawn(send(setup.get_authed("small.txt")));
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(large.bytes().await?.len(), 8 * 1024 * 1024);
+    let resp = queued.await??;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.text().await?, "small");
+
+    std::fs::remove_dir_all(&downloads)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn large_file_is_byte_exact() -> Result<()> {
+    let downloads = std::env::temp_dir()
+        .join(format!("octoka-test-large-file-{}", std::process::id()));
+    let event_dir = downloads.join("org/channel/abc123");
+    std::fs::create_dir_all(&event_dir)?;
+    // Spans many read chunks and does not end on a chunk boundary. The
+    // pattern does not repeat at chunk boundaries, so duplicated or skipped
+    // chunks are noticed.
+    let content = (0..5 * 1024 * 1024 + 1234).map(|i: u32| (i % 251) as u8).collect::<Vec<_>>();
+    std::fs::write(event_dir.join("video.mp4"), &content)?;
+
+    let setup = setup(&format!(
+        "opencast.downloads_path = {:?}",
+        downloads.to_str().unwrap(),
+    )).await?;
+
+    let resp = send(setup.get_authed("video.mp4")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.bytes().await? == content);
+
+    let (start, end) = (100_000, 3_000_000);
+    let range = format!("bytes={start}-{end}");
+    let resp = send(setup.get_authed("video.mp4").header("range", range)).await?;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert!(resp.bytes().await? == content[start..=end]);
+
+    std::fs::remove_dir_all(&downloads)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn fallback_files() -> Result<()> {
+    let setup = setup(&format!(r#"
+        http.fallback_files = [
+            {{ pattern = "*.jpg", file = "{}/tests/static-files/placeholder.jpg" }},
+        ]
+    "#, env!("CARGO_MANIFEST_DIR"))).await?;
+
+    let resp = send(setup.get_authed("thumb.jpg")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.text().await?, "real thumbnail\n");
+
+    let resp = send(setup.get_authed("missing-thumb.jpg")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "content-type"), Some("image/jpeg"));
+    assert_eq!(resp.text().await?, "placeholder image\n");
+
+    // Pattern not matching
+    let resp = send(setup.get_authed("missing.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // Auth is still required
+    let resp = send(setup.get("missing-thumb.jpg")).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_oc_entries() -> Result<()> {
+    // `JWT_UNKNOWN_OC_KIND` has two `oc` entries.
+    let default = setup("").await?;
+    let resp = send(default.get("hello.txt").bearer_auth(JWT_UNKNOWN_OC_KIND)).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let limited = setup("jwt.max_oc_entries = 1").await?;
+    let resp = send(limited.get("hello.txt").bearer_auth(JWT_UNKNOWN_OC_KIND)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = send(limited.get("hello.txt").bearer_auth(JWT_SUB)).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn strict_mode() -> Result<()> {
+    let non_utf8 = http::HeaderValue::from_bytes(b"Bearer \xff")?;
+    let lenient = setup("").await?;
+    let strict = setup("jwt.strict = true").await?;
+
+    for (setup, expected) in [(&lenient, StatusCode::OK), (&strict, StatusCode::FORBIDDEN)] {
+        for jwt in [JWT_ADMIN_NO_TYP, JWT_ADMIN_UNKNOWN_KID, JWT_UNKNOWN_OC_KIND] {
+            let resp = send(setup.get("hello.txt").bearer_auth(jwt)).await?;
+            assert_eq!(resp.status(), expected, "JWT: {jwt}");
+        }
+
+        // Non-UTF8 header, but valid JWT in query
+        let req = setup.get(&format!("hello.txt?jwt={JWT_ADMIN}"))
+            .header("authorization", non_utf8.clone());
+        assert_eq!(send(req).await?.status(), expected);
+    }
+
+    // Well-formed tokens work in strict mode
+    let resp = send(strict.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn expected_typ() -> Result<()> {
+    let default = setup("").await?;
+    for jwt in [JWT_ADMIN, JWT_ADMIN_AT_TYP, JWT_ADMIN_NO_TYP] {
+        let resp = send(default.get("hello.txt").bearer_auth(jwt)).await?;
+        assert_eq!(resp.status(), StatusCode::OK, "JWT: {jwt}");
+    }
+
+    let expect_jwt = setup(r#"jwt.expected_typ = "JWT""#).await?;
+    let resp = send(expect_jwt.get("hello.txt").bearer_auth(JWT_ADMIN)).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = send(expect_jwt.get("hello.txt").bearer_auth(JWT_ADMIN_AT_TYP)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = send(expect_jwt.get("hello.txt").bearer_auth(JWT_ADMIN_NO_TYP)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let expect_at = setup(r#"jwt.expected_typ = "AT+JWT""#).await?;
+    let resp = send(expect_at.get("hello.txt").bearer_auth(JWT_ADMIN_AT_TYP)).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = send(expect_at.get("hello.txt").bearer_auth(JWT_ADMIN)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn require_all_sources() -> Result<()> {
+    let setup = setup("http.require_all_sources = true").await?;
+    let get = |query_jwt: Option<&str>, header_jwt: Option<&str>| {
+        let path = match query_jwt {
+            Some(jwt) => format!("hello.txt?jwt={jwt}"),
+            None => "hello.txt".into(),
+        };
+        let req = setup.get(&path);
+        match header_jwt {
+            Some(jwt) => req.bearer_auth(jwt),
+            None => req,
+        }
+    };
+
+    let resp = send(get(Some(JWT_ADMIN), Some(JWT_ADMIN))).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // One source does not provide a token
+    let resp = send(get(Some(JWT_ADMIN), None)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = send(get(None, Some(JWT_ADMIN))).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // One token does not grant access or is invalid
+    let resp = send(get(Some(JWT_ADMIN), Some(JWT_USER))).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = send(get(Some("not-a-jwt"), Some(JWT_ADMIN))).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Opencast is not asked, even if it would allow access.
+    let oc_host = mock_opencast(|_| http::Response::new(Full::default())).await?;
+    let with_fallback = crate::setup(&format!(r#"
+        http.require_all_sources = true
+        opencast.host = "{oc_host}"
+        opencast.fallback = "head"
+    "#)).await?;
+    let resp = send(with_fallback.get("hello.txt").bearer_auth(JWT_ADMIN)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = send(with_fallback.get(&format!("hello.txt?jwt={JWT_ADMIN}"))).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    Ok(())
+}
+
+/// Key used to sign DPoP proofs in tests.
+fn dpop_key() -> ed25519_dalek::SigningKey {
+    ed25519_dalek::SigningKey::from_bytes(&[7; 32])
+}
+
+/// Creates a DPoP proof signed with `key` for the given method and URL, bound
+/// to the access token `jwt`.
+fn dpop_proof(key: &ed25519_dalek::SigningKey, method: &str, url: &str, jwt: &str) -> String {
+    use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+    use ed25519_dalek::Signer as _;
+    use sha2::Digest as _;
+
+    let x = URL_SAFE_NO_PAD.encode(key.verifying_key().as_bytes());
+    let header = serde_json::json!({
+        "typ": "dpop+jwt",
+        "alg": "EdDSA",
+        "jwk": { "kty": "OKP", "crv": "Ed25519", "x": x },
+    });
+    let iat = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+    let claims = serde_json::json!({
+        "jti": "test",
+        "htm": method,
+        "htu": url,
+        "iat": iat.as_secs(),
+        "ath": URL_SAFE_NO_PAD.encode(sha2::Sha256::digest(jwt)),
+    });
+    let message = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(header.to_string()),
+        URL_SAFE_NO_PAD.encode(claims.to_string()),
+    );
+    let signature = URL_SAFE_NO_PAD.encode(key.sign(message.as_bytes()).to_bytes());
+    format!("{message}.{signature}")
+}
+
+#[tokio::test]
+async fn require_cnf() -> Result<()> {
+    let bound = setup("jwt.require_cnf = true").await?;
+    let url = format!("http://{}/static/org/channel/abc123/hello.txt", bound.addr);
+    let proof = dpop_proof(&dpop_key(), "GET", &url, JWT_ADMIN_CNF);
+
+    // Matching confirmation
+    let req = bound.get("hello.txt").bearer_auth(JWT_ADMIN_CNF).header("dpop", &proof);
+    assert_eq!(send(req).await?.status(), StatusCode::OK);
+
+    // Token bound to another key
+    let other_proof = dpop_proof(&dpop_key(), "GET", &url, JWT_ADMIN_CNF_OTHER);
+    let req = bound.get("hello.txt").bearer_auth(JWT_ADMIN_CNF_OTHER).header("dpop", other_proof);
+    assert_eq!(send(req).await?.status(), StatusCode::FORBIDDEN);
+
+    // Proof signed by another key
+    let other_key = ed25519_dalek::SigningKey::from_bytes(&[8; 32]);
+    let other_proof = dpop_proof(&other_key, "GET", &url, JWT_ADMIN_CNF);
+    let req = bound.get("hello.txt").bearer_auth(JWT_ADMIN_CNF).header("dpop", other_proof);
+    assert_eq!(send(req).await?.status(), StatusCode::FORBIDDEN);
+
+    // Proof for another path, method or access token, or no proof at all
+    let other_url = url.replace("hello.txt", "other.txt");
+    let wrong_path = dpop_proof(&dpop_key(), "GET", &other_url, JWT_ADMIN_CNF);
+    let req = bound.get("hello.txt").bearer_auth(JWT_ADMIN_CNF).header("dpop", wrong_path);
+    assert_eq!(send(req).await?.status(), StatusCode::FORBIDDEN);
+    let wrong_method = dpop_proof(&dpop_key(), "POST", &url, JWT_ADMIN_CNF);
+    let req = bound.get("hello.txt").bearer_auth(JWT_ADMIN_CNF).header("dpop", wrong_method);
+    assert_eq!(send(req).await?.status(), StatusCode::FORBIDDEN);
+    let wrong_token = dpop_proof(&dpop_key(), "GET", &url, JWT_ADMIN_CNF_OTHER);
+    let req = bound.get("hello.txt").bearer_auth(JWT_ADMIN_CNF).header("dpop", wrong_token);
+    assert_eq!(send(req).await?.status(), StatusCode::FORBIDDEN);
+    let resp = send(bound.get("hello.txt").bearer_auth(JWT_ADMIN_CNF)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Tokens without `cnf` claim are rejected
+    let resp = send(bound.get_authed("hello.txt").header("dpop", &proof)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Without `require_cnf`, tokens without `cnf` claim are accepted, but
+    // bound tokens still need a proof.
+    let lenient = setup("").await?;
+    let resp = send(lenient.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = send(lenient.get("hello.txt").bearer_auth(JWT_ADMIN_CNF)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let req = lenient.get("hello.txt").bearer_auth(JWT_ADMIN_CNF).header("dpop", &proof);
+    assert_eq!(send(req).await?.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn method_not_allowed() -> Result<()> {
+    let setup = setup("").await?;
+    let req = setup.request(reqwest::Method::POST, "hello.txt").bearer_auth(JWT_ADMIN);
+    let resp = send(req).await?;
+    assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(header(&resp, "allow"), Some("GET, HEAD, OPTIONS"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn trace_and_connect_rejected() -> Result<()> {
+    let setup = setup("").await?;
+    for method in [reqwest::Method::TRACE, reqwest::Method::CONNECT] {
+        let req = setup.request(method.clone(), "hello.txt")
+            .bearer_auth(JWT_ADMIN)
+            .header("x-reflect-me", "canary");
+        let resp = send(req).await?;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED, "{method}");
+        assert_eq!(header(&resp, "allow"), Some("GET, HEAD, OPTIONS"));
+        assert_eq!(header(&resp, "x-reflect-me"), None);
+        let body = resp.text().await?;
+        assert!(!body.contains("canary") && !body.contains(JWT_ADMIN), "{method}: {body}");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn ipv6_dual_stack() -> Result<()> {
+    // Not every test environment has IPv6, in which case there is nothing
+    // to test.
+    if std::net::TcpListener::bind("[::1]:0").is_err() {
+        return Ok(());
+    }
+
+    let get = |host: String, port: u16| async move {
+        let url = format!("http://{host}:{port}/static/org/channel/abc123/hello.txt");
+        reqwest::Client::new().get(url).bearer_auth(JWT_ADMIN).send().await
+    };
+
+    let dual = setup(r#"http.address = "::""#).await?;
+    let port = dual.addr.port();
+    assert_eq!(get("[::1]".into(), port).await?.status(), StatusCode::OK);
+    assert_eq!(get("127.0.0.1".into(), port).await?.status(), StatusCode::OK);
+
+    let v6_only = setup(r#"
+        http.address = "::"
+        http.ipv6_only = true
+    "#).await?;
+    let port = v6_only.addr.port();
+    assert_eq!(get("[::1]".into(), port).await?.status(), StatusCode::OK);
+
+    // The IPv4 port is still free. Connecting to it instead could reach
+    // another test's listener using the same port number.
+    assert!(std::net::TcpListener::bind(("127.0.0.1", port)).is_ok());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn multiple_listeners() -> Result<()> {
+    let downloads = format!("{}/tests/downloads", env!("CARGO_MANIFEST_DIR"));
+    let config = format!(r#"
+        opencast.fallback = "none"
+        opencast.downloads_path = "{downloads}"
+        jwt.trusted_keys = ["http://127.0.0.1:4055/ed25519.json"]
+        http.port = 0
+        http.listeners = [{{ address = "127.0.0.1", port = 0 }}]
+    "#);
+    let config = Config::builder().preloaded(toml::from_str(&config)?).load()?;
+    let (addrs, server) = octoka::test_http_server_all(config).await?;
+    tokio::spawn(server);
+
+    assert_eq!(addrs.len(), 2);
+    assert_ne!(addrs[0], addrs[1]);
+    for addr in addrs {
+        let url = format!("http://{addr}/static/org/channel/abc123/hello.txt");
+        let resp = send(reqwest::Client::new().get(url).bearer_auth(JWT_ADMIN)).await?;
+        assert_eq!(resp.status(), StatusCode::OK, "{addr}");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn connection_close() -> Result<()> {
+    let setup = setup("").await?;
+    let addr = setup.addr;
+
+    // Two requests on one connection: the first keeps it alive, after the
+    // second one, the server has to close it. `read_to_string` only returns
+    // once the connection is closed (or fails on timeout).
+    let response = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+        use std::io::{Read, Write};
+
+        let mut stream = std::net::TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+        let request = |connection: &str| format!(
+            "GET /static/org/channel/abc123/hello.txt HTTP/1.1\r\n\
+                Host: localhost\r\n\
+                Authorization: Bearer {JWT_ADMIN}\r\n\
+                Connection: {connection}\r\n\r\n",
+        );
+        stream.write_all(request("keep-alive").as_bytes())?;
+        stream.write_all(request("close").as_bytes())?;
+        let mut out = String::new();
+        stream.read_to_string(&mut out)?;
+        Ok(out)
+    }).await??;
+
+    assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2, "{response}");
+    assert!(response.to_ascii_lowercase().contains("connection: close"), "{response}");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn early_hints() -> Result<()> {
+    const LINKS: [&str; 2] = [
+        "<segment0.ts>; rel=preload; as=fetch; crossorigin",
+        "<segment1.ts>; rel=preload; as=fetch; crossorigin",
+    ];
+    let links = |resp: &Response| resp.headers()
+        .get_all("link")
+        .iter()
+        .map(|v| v.to_str().unwrap().to_owned())
+        .collect::<Vec<_>>();
+
+    let default = setup("").await?;
+    let resp = send(default.get_authed("hls/playlist.m3u8")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(links(&resp).is_empty());
+
+    let hints = setup("http.early_hints = true").await?;
+    let resp = send(hints.get_authed("hls/playlist.m3u8")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(links(&resp), LINKS);
+
+    // Only for manifests
+    let resp = send(hints.get_authed("hls/segment0.ts")).await?;
+    assert!(links(&resp).is_empty());
+
+    // The 103 has to precede the 200, also for pipelined requests. For those,
+    // it might be skipped, but must never end up inside another response.
+    let addr = hints.addr;
+    let response = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+        use std::io::{Read, Write};
+
+        let mut stream = std::net::TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+        let request = |path: &str, connection: &str| format!(
+            "GET /static/org/channel/abc123/{path} HTTP/1.1\r\n\
+                Host: localhost\r\n\
+                Authorization: Bearer {JWT_ADMIN}\r\n\
+                Connection: {connection}\r\n\r\n",
+        );
+        stream.write_all(request("hls/playlist.m3u8", "keep-alive").as_bytes())?;
+        stream.write_all(request("hls/segment0.ts", "keep-alive").as_bytes())?;
+        stream.write_all(request("hls/playlist.m3u8", "close").as_bytes())?;
+        let mut out = Vec::new();
+        stream.read_to_end(&mut out)?;
+        Ok(String::from_utf8_lossy(&out).into_owned())
+    }).await??;
+
+    let expected_hints = format!(
+        "HTTP/1.1 103 Early Hints\r\nlink: {}\r\nlink: {}\r\n\r\nHTTP/1.1 200 OK\r\n",
+        LINKS[0],
+        LINKS[1],
+    );
+    assert!(response.starts_with(&expected_hints), "{response}");
+    assert_eq!(response.matches("HTTP/1.1 200 OK\r\n").count(), 3, "{response}");
+    let all_followed_by_200 = response.split("HTTP/1.1 103 Early Hints\r\n")
+        .skip(1)
+        .all(|rest| rest.split_once("\r\n\r\n").unwrap().1.starts_with("HTTP/1.1 200 OK"));
+    assert!(all_followed_by_200, "{response}");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn fallback_circuit_breaker() -> Result<()> {
+    use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+
+    // Opencast is "down", answering all requests with 503.
+    let requests = Arc::new(AtomicUsize::new(0));
+    let oc_host = mock_opencast({
+        let requests = Arc::clone(&requests);
+        move |_| {
+            requests.fetch_add(1, Ordering::SeqCst);
+            http::Response::builder().status(503).body(Full::default()).unwrap()
+        }
+    }).await?;
+    let setup = setup(&format!(r#"
+        opencast.host = "{oc_host}"
+        opencast.fallback = "head"
+        opencast.circuit_breaker = {{ failures = 3, cooldown = "1s" }}
+    "#)).await?;
+
+    // After three failures, Opencast is not asked anymore.
+    for _ in 0..5 {
+        let resp = send(setup.get("hello.txt")).await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+    // After the cooldown, a single probe is sent, which fails again.
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    for _ in 0..3 {
+        let resp = send(setup.get("hello.txt")).await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+    // Requests with valid JWT are not affected.
+    let resp = send(setup.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_through_cache() -> Result<()> {
+    use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let oc_host = mock_opencast({
+        let requests = Arc::clone(&requests);
+        move |req| {
+            requests.fetch_add(1, Ordering::SeqCst);
+            let (status, body) = match req.uri().path() {
+                "/static/org/channel/abc123/first.txt" => (StatusCode::OK, "first remote file"),
+                "/static/org/channel/abc123/second.txt" => (StatusCode::OK, "second one"),
+                "/static/org/channel/abc123/large.txt" => {
+                    (StatusCode::OK, "too large for the cache")
+                }
+                _ => (StatusCode::NOT_FOUND, ""),
+            };
+            http::Response::builder().status(status).body(body.into()).unwrap()
+        }
+    }).await?;
+    let cache_dir = std::env::temp_dir()
+        .join(format!("octoka-test-cache-{}", std::process::id()));
+    let setup = setup(&format!(r#"
+        opencast.host = "{oc_host}"
+        opencast.read_through_cache = {{ path = "{}", max_size = 20 }}
+    "#, cache_dir.display())).await?;
+    let cached_file = |name: &str| cache_dir.join("files/org/channel/abc123").join(name);
+
+    // Fetched once, then served from the cache.
+    for _ in 0..2 {
+        let resp = send(setup.get_authed("first.txt")).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.text().await?, "first remote file");
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    assert!(cached_file("first.txt").is_file());
+
+    // Local files are not fetched and OC's 404 is passed on.
+    let resp = send(setup.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = send(setup.get_authed("missing.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    // Both files don't fit into the cache, so the first one is evicted.
+    let resp = send(setup.get_authed("second.txt")).await?;
+    assert_eq!(resp.text().await?, "second one");
+    assert!(!cached_file("first.txt").exists());
+    assert!(cached_file("second.txt").is_file());
+
+    let resp = send(setup.get_authed("first.txt")).await?;
+    assert_eq!(resp.text().await?, "first remote file");
+    assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+    // Concurrent requests for the same file only fetch it once.
+    let responses = futures::future::join_all(
+        (0..8).map(|_| send(setup.get_authed("second.txt"))),
+    ).await;
+    for resp in responses {
+        assert_eq!(resp?.text().await?, "second one");
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 5);
+
+    // Files too large for the cache are streamed through without asking OC again.
+    let resp = send(setup.get_authed("large.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.text().await?, "too large for the cache");
+    assert_eq!(requests.load(Ordering::SeqCst), 6);
+    assert!(!cached_file("large.txt").exists());
+
+    std::fs::remove_dir_all(&cache_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_through_cache_large_chunked_file() -> Result<()> {
+    use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    // Without `Content-Length`, octoka only notices that the file is too
+    // large while downloading it.
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let oc_addr = listener.local_addr()?;
+    let requests = Arc::new(AtomicUsize::new(0));
+    tokio::spawn({
+        let requests = Arc::clone(&requests);
+        async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                requests.fetch_add(1, Ordering::SeqCst);
+                let _ = stream.read(&mut [0; 4096]).await;
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    8\r\ntoo larg\r\n8\r\ne for th\r\n7\r\ne cache\r\n0\r\n\r\n").await;
+            }
+        }
+    });
+    let cache_dir = std::env::temp_dir()
+        .join(format!("octoka-test-chunked-cache-{}", std::process::id()));
+    let setup = setup(&format!(r#"
+        opencast.host = "http://{oc_addr}"
+        opencast.read_through_cache = {{ path = "{}", max_size = 10 }}
+    "#, cache_dir.display())).await?;
+
+    let resp = send(setup.get_authed("large.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.text().await?, "too large for the cache");
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    assert!(!cache_dir.join("files/org/channel/abc123/large.txt").exists());
+    assert_eq!(std::fs::read_dir(cache_dir.join("tmp"))?.count(), 0);
+
+    std::fs::remove_dir_all(&cache_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn verify_upstream_digests() -> Result<()> {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    // OC sends "hello" with its correct MD5 for `good.txt`, but a wrong one
+    // for `bad.txt`, e.g. as if the body got corrupted.
+    let oc_host = mock_opencast(|req| {
+        let md5 = match req.uri().path().rsplit('/').next() {
+            Some("good.txt") => "XUFAKrxLKna5cZ2REBfFkg==",
+            _ => "AAAAAAAAAAAAAAAAAAAAAA==",
+        };
+        http::Response::builder().header("content-md5", md5).body("hello".into()).unwrap()
+    }).await?;
+    let cache_dir = std::env::temp_dir()
+        .join(format!("octoka-test-digest-cache-{}", std::process::id()));
+    let config = |verify: bool| format!(r#"
+        opencast.host = "{oc_host}"
+        opencast.read_through_cache = {{ path = "{}", max_size = 1000 }}
+        http.passthrough_unmatched = "proxy"
+        http.verify_upstream_digests = {verify}
+    "#, cache_dir.display());
+    let checked = setup(&config(true)).await?;
+    let proxied = |path| send(checked.request_full(reqwest::Method::GET, path));
+
+    // Read-through cache: corrupt files are not stored.
+    let resp = send(checked.get_authed("good.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.text().await?, "hello");
+    let resp = send(checked.get_authed("bad.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+    assert!(!cache_dir.join("files/org/channel/abc123/bad.txt").exists());
+
+    // Proxying: the response is aborted before the last frame. Here, the
+    // body has only one frame, so the headers might not be flushed yet either.
+    let resp = proxied("/good.txt").await?;
+    assert_eq!(resp.text().await?, "hello");
+    let aborted = match proxied("/bad.txt").await {
+        Ok(resp) => resp.bytes().await.is_err(),
+        Err(_) => true,
+    };
+    assert!(aborted);
+
+    // With a body in multiple frames, the client receives the headers and the
+    // first frame before the response is aborted.
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let chunked_oc_addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0; 4096];
+                let len = stream.read(&mut buf).await.unwrap_or(0);
+                let md5 = match String::from_utf8_lossy(&buf[..len]).contains("/good.txt") {
+                    true => "XUFAKrxLKna5cZ2REBfFkg==",
+                    false => "AAAAAAAAAAAAAAAAAAAAAA==",
+                };
+                let head = format!("HTTP/1.1 200 OK\r\nContent-MD5: {md5}\r\n\
+                    Transfer-Encoding: chunked\r\n\r\n3\r\nhel\r\n");
+                let _ = stream.write_all(head.as_bytes()).await;
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                let _ = stream.write_all(b"2\r\nlo\r\n0\r\n\r\n").await;
+            });
+        }
+    });
+    let chunked = setup(&format!(r#"
+        opencast.host = "http://{chunked_oc_addr}"
+        http.passthrough_unmatched = "proxy"
+        http.verify_upstream_digests = true
+    "#)).await?;
+    let resp = send(chunked.request_full(reqwest::Method::GET, "/good.txt")).await?;
+    assert_eq!(resp.text().await?, "hello");
+    let resp = send(chunked.request_full(reqwest::Method::GET, "/bad.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.bytes().await.is_err());
+
+    // Not checked unless enabled.
+    let unchecked = setup(&config(false)).await?;
+    let resp = send(unchecked.get_authed("bad.txt")).await?;
+    assert_eq!(resp.text().await?, "hello");
+
+    std::fs::remove_dir_all(&cache_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn etag_format() -> Result<()> {
+    let setup = setup("").await?;
+
+    let resp = send(setup.get_authed("hello.txt")).await?;
+    let etag = header(&resp, "etag").unwrap().to_owned();
+    let inner = etag.strip_prefix('"').and_then(|s| s.strip_suffix('"')).unwrap();
+    assert!(!inner.is_empty());
+    assert!(inner.chars().all(|c| c.is_ascii_digit() || c == ':' || c == '-'), "{etag}");
+
+    // A reverse proxy compressing the response weakens the ETag. Conditional
+    // requests with that still work.
+    let req = setup.get_authed("hello.txt").header("if-none-match", format!("W/{etag}"));
+    assert_eq!(send(req).await?.status(), StatusCode::NOT_MODIFIED);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn not_modified_skips_content() -> Result<()> {
+    let setup = setup(r#"
+        http.early_hints = true
+        http.repr_digest_max_size = 1000
+    "#).await?;
+    let resp = send(setup.get_authed("hls/playlist.m3u8")).await?;
+    assert!(header(&resp, "link").is_some());
+    assert!(header(&resp, "repr-digest").is_some());
+    let etag = header(&resp, "etag").unwrap().to_owned();
+
+    // Nothing that requires reading the file is done for 304.
+    let req = setup.get_authed("hls/playlist.m3u8").header("if-none-match", &etag);
+    let resp = send(req).await?;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(header(&resp, "etag"), Some(etag.as_str()));
+    assert!(header(&resp, "last-modified").is_some());
+    assert_eq!(header(&resp, "link"), None);
+    assert_eq!(header(&resp, "repr-digest"), None);
+    assert_eq!(header(&resp, "content-type"), None);
+    assert!(resp.bytes().await?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn range_with_unknown_unit() -> Result<()> {
+    let setup = setup("").await?;
+
+    let resp = send(setup.get_authed("ranges.txt").header("range", "items=0-3")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "content-range"), None);
+    assert_eq!(resp.text().await?, "0123456789\n");
+
+    // Units are case-insensitive.
+    let resp = send(setup.get_authed("ranges.txt").header("range", "Bytes=2-4")).await?;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(resp.text().await?, "234");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn range_of_compressed_file() -> Result<()> {
+    let setup = setup("").await?;
+
+    // `ranges.txt.gz` is `ranges.txt` gzipped, 31 bytes.
+    let resp = send(setup.get_authed("ranges.txt.gz").header("range", "bytes=0-1")).await?;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(header(&resp, "content-range"), Some("bytes 0-1/31"));
+    assert_eq!(header(&resp, "content-encoding"), None);
+    assert_eq!(header(&resp, "content-type"), Some("application/gzip"));
+    assert_eq!(resp.bytes().await?.as_ref(), [0x1f, 0x8b]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn range_not_satisfiable() -> Result<()> {
+    let setup = setup("").await?;
+
+    let resp = send(setup.get_authed("ranges.txt").header("range", "bytes=20-30")).await?;
+    assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(header(&resp, "content-range"), Some("bytes */11"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn head_with_range() -> Result<()> {
+    let setup = setup("").await?;
+
+    let req = setup.request(reqwest::Method::HEAD, "ranges.txt")
+        .bearer_auth(JWT_ADMIN)
+        .header("range", "bytes=2-4");
+    let resp = send(req).await?;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(header(&resp, "content-range"), Some("bytes 2-4/11"));
+    assert_eq!(header(&resp, "content-length"), Some("3"));
+    assert_eq!(resp.bytes().await?.len(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn head_error_statuses() -> Result<()> {
+    let downloads = TempDir::new("head-errors");
+    let event_dir = downloads.path().join("org/channel/abc123");
+    std::fs::create_dir_all(event_dir.join("subdir"))?;
+    let unreadable = event_dir.join("unreadable.txt");
+    std::fs::write(&unreadable, "secret")?;
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        std::fs::set_permissions(&unreadable, std::fs::Permissions::from_mode(0o000))?;
+    }
+
+    let setup = setup(&format!(
+        "opencast.downloads_path = {:?}",
+        downloads.path().to_str().unwrap(),
+    )).await?;
+
+    // Root (e.g. in containers) can read the file regardless of permissions.
+    let mut cases = vec![("subdir", StatusCode::NOT_FOUND), ("missing.txt", StatusCode::NOT_FOUND)];
+    if std::fs::File::open(&unreadable).is_err() {
+        cases.push(("unreadable.txt", StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    // `HEAD` has the same status and headers as `GET`, just no body.
+    for (path, status) in cases {
+        let get = send(setup.get_authed(path)).await?;
+        let head = send(setup.request(reqwest::Method::HEAD, path).bearer_auth(JWT_ADMIN)).await?;
+        assert_eq!(get.status(), status, "{path}");
+        assert_eq!(head.status(), status, "{path}");
+        assert_eq!(header(&head, "content-length"), header(&get, "content-length"), "{path}");
+        assert!(!get.bytes().await?.is_empty(), "{path}");
+        assert!(head.bytes().await?.is_empty(), "{path}");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn head_without_content_length() -> Result<()> {
+    let head = |setup: &TestSetup| setup.request(reqwest::Method::HEAD, "hello.txt")
+        .bearer_auth(JWT_ADMIN);
+
+    // 204 from `on_allow = "empty"`
+    let empty = setup(r#"http.on_allow = "empty""#).await?;
+    let resp = send(head(&empty)).await?;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(header(&resp, "content-length"), None);
+
+    // 304 for a conditional request
+    let file = setup("").await?;
+    let resp = send(head(&file)).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(header(&resp, "content-length").is_some());
+    let etag = header(&resp, "etag").unwrap().to_owned();
+    let resp = send(head(&file).header("if-none-match", etag)).await?;
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(header(&resp, "content-length"), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn repr_digest() -> Result<()> {
+    // SHA-256 of "0123456789\n", the content of `ranges.txt` (11 bytes).
+    const DIGEST: &str = "sha-256=:xnwZlZViLfvcnkFcSgrWFm60nL90xqrHuz6VhgTV7Lg=:";
+
+    let enabled = setup("http.repr_digest_max_size = 11").await?;
+    let resp = send(enabled.get_authed("ranges.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "repr-digest"), Some(DIGEST));
+
+    // The digest is about the whole file, also for range requests.
+    let resp = send(enabled.get_authed("ranges.txt").header("range", "bytes=2-4")).await?;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(header(&resp, "repr-digest"), Some(DIGEST));
+
+    let too_large = setup("http.repr_digest_max_size = 10").await?;
+    let resp = send(too_large.get_authed("ranges.txt")).await?;
+    assert_eq!(header(&resp, "repr-digest"), None);
+
+    let disabled = setup("").await?;
+    let resp = send(disabled.get_authed("ranges.txt")).await?;
+    assert_eq!(header(&resp, "repr-digest"), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn unencoded_payload() -> Result<()> {
+    // Header: `{ "alg": "EdDSA", "b64": false, "crit": ["b64"] }`, signed with
+    // `ed25519.json` over the unencoded payload (RFC 7797).
+    const JWT: &str = r#"eyJhbGciOiJFZERTQSIsImI2NCI6ZmFsc2UsImNyaXQiOlsiYjY0Il19.{"exp":4012345678,"roles":["ROLE_ADMIN"]}.zjc5ZdGkt-s9mnSW_qLpMqLfI2jvdmWKpLP6fgl_ebvEPgV1qQOYg6EUKiNHIYfj25cjdQT1ysQyDnhCbyO6DA"#;
+
+    // Like above, but without `crit`, which is required for `b64`.
+    const JWT_NO_CRIT: &str = r#"eyJhbGciOiJFZERTQSIsImI2NCI6ZmFsc2V9.{"exp":4012345678,"roles":["ROLE_ADMIN"]}.Ws2WKYNupvl75jE5EzdOjbAEycDLi_J1eTtC-m8c2l8icPRkbsVsRTwKvjHMuuUI3CP2AZkNv8rGF73DIQ31AQ"#;
+
+    let enabled = setup("jwt.allow_unencoded_payload = true").await?;
+    let resp = send(enabled.get("hello.txt").bearer_auth(JWT)).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = send(enabled.get("hello.txt").bearer_auth(JWT_NO_CRIT)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = send(enabled.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let disabled = setup("").await?;
+    let resp = send(disabled.get("hello.txt").bearer_auth(JWT)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn media_headers() -> Result<()> {
+    // Proxied from Opencast
+    let oc_host = mock_opencast(|_| {
+        http::Response::builder()
+            .header("x-content-duration", "12.5")
+            .body("ok".into())
+            .unwrap()
+    }).await?;
+    let proxy = setup(&format!(r#"
+        opencast.host = "{oc_host}"
+        http.passthrough_unmatched = "proxy"
+    "#)).await?;
+    let resp = send(proxy.request_full(reqwest::Method::GET, "/engage/ui/")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "x-content-duration"), Some("12.5"));
+
+    // Served files
+    let files = setup(r#"
+        http.type_headers = [
+            { content_type = "video/*", name = "X-Content-Type-Options", value = "nosniff" },
+        ]
+    "#).await?;
+    let resp = send(files.get_authed("hls/segment0.ts")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "x-content-type-options"), Some("nosniff"));
+    let resp = send(files.get_authed("hello.txt")).await?;
+    assert_eq!(header(&resp, "x-content-type-options"), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn connection_close_during_shutdown() -> Result<()> {
+    // Opencast mock that allows all requests, but answers slowly, so that the
+    // request is still in flight when the shutdown begins.
+    let oc_host = delayed_mock_opencast(std::time::Duration::from_millis(300), |_| {
+        http::Response::new(Full::default())
+    }).await?;
+
+    let downloads = format!("{}/tests/downloads", env!("CARGO_MANIFEST_DIR"));
+    let config = format!(r#"
+        opencast.host = "{oc_host}"
+        opencast.downloads_path = "{downloads}"
+        jwt.trusted_keys = ["http://127.0.0.1:4055/ed25519.json"]
+        http.port = 0
+    "#);
+    let config = Config::builder().preloaded(toml::from_str(&config)?).load()?;
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+    let shutdown = async { let _ = shutdown_rx.await; };
+    let (addrs, server) = octoka::test_http_server_with_shutdown(config, shutdown).await?;
+    let server = tokio::spawn(server);
+
+    let url = format!("http://{}/static/org/channel/abc123/hello.txt", addrs[0]);
+    let request = tokio::spawn(reqwest::get(url));
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    shutdown_tx.send(()).unwrap();
+
+    let resp = request.await??;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "connection"), Some("close"));
+    assert_eq!(resp.text().await?, "Hello octoka!\n");
+    server.await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn audit_log() -> Result<()> {
+    let path = std::env::temp_dir()
+        .join(format!("octoka-test-audit-{}.log", std::process::id()));
+    let setup = setup(&format!(r#"
+        log.audit_file = "{}"
+        log.audit_anonymize_ip = true
+    "#, path.display())).await?;
+
+    let resp = send(setup.get("hello.txt").bearer_auth(JWT_SUB)).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = send(setup.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Denied requests are not recorded.
+    let resp = send(setup.get("hello.txt").bearer_auth(JWT_USER)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let content = std::fs::read_to_string(&path)?;
+    let lines = content.lines()
+        .map(serde_json::from_str::<serde_json::Value>)
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(lines.len(), 2);
+    for (line, sub, basis) in [(&lines[0], Some("alice"), "event"), (&lines[1], None, "admin")] {
+        assert_eq!(line["event"], "abc123");
+        assert_eq!(line["sub"].as_str(), sub);
+        assert_eq!(line["basis"], basis);
+        assert_eq!(line["client"], "127.0.0.0");
+        assert!(line["time"].as_str().is_some_and(|t| t.ends_with('Z')));
+    }
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn user_headers() -> Result<()> {
+    let enabled = setup(r#"
+        http.on_allow = "empty"
+        http.user_headers = true
+    "#).await?;
+    let resp = send(enabled.get("hello.txt").bearer_auth(JWT_SUB)).await?;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(header(&resp, "x-authenticated-user"), Some("alice"));
+    assert_eq!(header(&resp, "x-authenticated-name"), None);
+
+    let disabled = setup(r#"http.on_allow = "empty""#).await?;
+    let resp = send(disabled.get("hello.txt").bearer_auth(JWT_SUB)).await?;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(header(&resp, "x-authenticated-user"), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn path_headers() -> Result<()> {
+    let enabled = setup(r#"
+        http.on_allow = "empty"
+        http.path_headers = true
+    "#).await?;
+    let resp = send(enabled.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(header(&resp, "x-octoka-org"), Some("org"));
+    assert_eq!(header(&resp, "x-octoka-channel"), Some("channel"));
+    assert_eq!(header(&resp, "x-octoka-event"), Some("abc123"));
+
+    // Not sent for denied requests or when disabled.
+    let resp = send(enabled.get("hello.txt").bearer_auth(JWT_USER)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(header(&resp, "x-octoka-event"), None);
+    let disabled = setup(r#"http.on_allow = "empty""#).await?;
+    let resp = send(disabled.get_authed("hello.txt")).await?;
+    assert_eq!(header(&resp, "x-octoka-event"), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn traversal_to_nonexistent_path() -> Result<()> {
+    let setup = setup("").await?;
+
+    // Encoded slashes, as clients normalize literal `..` segments.
+    for path in [
+        "missing/..%2F..%2F..%2Fother%2Ffile.txt",
+        "..%2Fother%2Fmissing%2Ffile.txt",
+        "..%2F..%2F..%2F..%2F..%2Fetc%2Fpasswd",
+    ] {
+        let resp = send(setup.get_authed(path)).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "path: {path}");
+    }
+
+    // Staying inside the event directory is fine.
+    let resp = send(setup.get_authed("missing/..%2Fhello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = send(setup.get_authed("missing/..%2Fmissing.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn lexical_path_normalization() -> Result<()> {
+    let setup = setup("").await?;
+
+    // Encoded slashes, as clients normalize literal `.` and `..` segments.
+    for (path, expected) in [
+        // In bounds, even though `missing` does not exist
+        ("missing%2F..%2Fhello.txt", StatusCode::OK),
+        ("missing/sub/..%2F..%2Fhello.txt", StatusCode::OK),
+        ("..%2Fabc123%2Fhello.txt", StatusCode::OK),
+
+        // `.` segments
+        (".%2Fhello.txt", StatusCode::OK),
+        ("missing%2F.%2F..%2F.%2Fhello.txt", StatusCode::OK),
+
+        // Escaping the event directory
+        ("..%2Fhello.txt", StatusCode::BAD_REQUEST),
+        ("missing%2F..%2F..%2Fabc123-other%2Fhello.txt", StatusCode::BAD_REQUEST),
+        ("..%2F..%2F..%2Forg%2Fchannel%2Fabc123%2F..%2Fhello.txt", StatusCode::BAD_REQUEST),
+    ] {
+        let resp = send(setup.get_authed(path)).await?;
+        assert_eq!(resp.status(), expected, "path: {path}");
+        if expected == StatusCode::OK {
+            assert_eq!(resp.text().await?, "Hello octoka!\n");
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_hints() -> Result<()> {
+    let config = r#"http.client_hints = ["Downlink", "ECT", "Save-Data"]"#;
+    let file = setup(config).await?;
+    let resp = send(file.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "accept-ch"), Some("Downlink, ECT, Save-Data"));
+    assert_eq!(header(&resp, "vary"), None);
+
+    let empty = setup(&format!("{config}\nhttp.on_allow = \"empty\"")).await?;
+    let resp = send(empty.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(header(&resp, "accept-ch"), Some("Downlink, ECT, Save-Data"));
+
+    // Not sent for denied requests or when disabled.
+    let resp = send(file.get("hello.txt").bearer_auth(JWT_USER)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(header(&resp, "accept-ch"), None);
+    let disabled = setup("").await?;
+    let resp = send(disabled.get_authed("hello.txt")).await?;
+    assert_eq!(header(&resp, "accept-ch"), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn allow_and_deny_headers() -> Result<()> {
+    let setup = setup(r#"
+        http.on_allow = "empty"
+        http.allow_headers = [
+            { name = "X-Octoka-Result", value = "allow" },
+            { name = "X-Octoka-Version", value = "1" },
+        ]
+        http.deny_headers = [{ name = "X-Octoka-Result", value = "deny" }]
+    "#).await?;
+
+    let resp = send(setup.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(header(&resp, "x-octoka-result"), Some("allow"));
+    assert_eq!(header(&resp, "x-octoka-version"), Some("1"));
+
+    let resp = send(setup.get("hello.txt").bearer_auth(JWT_USER)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(header(&resp, "x-octoka-result"), Some("deny"));
+    assert_eq!(header(&resp, "x-octoka-version"), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn forwarded_headers() -> Result<()> {
+    use std::sync::{Arc, Mutex};
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let oc_host = mock_opencast({
+        let received = Arc::clone(&received);
+        move |req| {
+            let forwarded = ["x-forwarded-for", "x-forwarded-proto", "x-forwarded-host"]
+                .map(|name| req.headers().get(name).map_or("", |v| v.to_str().unwrap()))
+                .map(str::to_owned);
+            received.lock().unwrap().push(forwarded);
+            http::Response::new("ok".into())
+        }
+    }).await?;
+    let setup = setup(&format!(r#"
+        opencast.host = "{oc_host}"
+        opencast.fallback = "head"
+        http.on_allow = "empty"
+        http.passthrough_unmatched = "proxy"
+    "#)).await?;
+    let host = setup.addr.to_string();
+
+    // Fallback request without JWT
+    let resp = send(setup.get("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    // Proxied request with headers from a reverse proxy in front of octoka
+    let resp = send(setup.request_full(reqwest::Method::GET, "/engage/ui/")
+        .header("x-forwarded-for", "203.0.113.7")
+        .header("x-forwarded-proto", "https")
+        .header("x-forwarded-host", "tobira.example.com")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let received = received.lock().unwrap();
+    assert_eq!(received[0], ["127.0.0.1", "http", host.as_str()]);
+    assert_eq!(received[1], ["203.0.113.7, 127.0.0.1", "https", "tobira.example.com"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn proxy_body_limits() -> Result<()> {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    // Mock OC that sends the response headers and part of a chunked body, and
+    // then stalls.
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let oc_addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\n\
+                    Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n").await;
+                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            });
+        }
+    });
+    let slow = setup(&format!(r#"
+        opencast.host = "http://{oc_addr}"
+        http.passthrough_unmatched = "proxy"
+        http.proxy_read_timeout = "200ms"
+    "#)).await?;
+    let resp = send(slow.request_full(reqwest::Method::GET, "/engage/ui/")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let start = std::time::Instant::now();
+    assert!(resp.bytes().await.is_err());
+    assert!(start.elapsed() < std::time::Duration::from_secs(2));
+
+    // Too large response with `Content-Length`
+    let oc_host = mock_opencast(|_| http::Response::new("0123456789".into())).await?;
+    let limited = setup(&format!(r#"
+        opencast.host = "{oc_host}"
+        http.passthrough_unmatched = "proxy"
+        http.proxy_max_body_size = 5
+    "#)).await?;
+    let resp = send(limited.request_full(reqwest::Method::GET, "/engage/ui/")).await?;
+    assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn deny_delay() -> Result<()> {
+    let setup = setup(r#"http.deny_delay = "400ms""#).await?;
+    let timed = |req: RequestBuilder| async move {
+        let start = std::time::Instant::now();
+        let status = send(req).await?.status();
+        Ok::<_, anyhow::Error>((status, start.elapsed()))
+    };
+
+    let (status, elapsed) = timed(setup.get("hello.txt").bearer_auth(JWT_USER)).await?;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(elapsed >= std::time::Duration::from_millis(200), "{elapsed:?}");
+
+    let (status, elapsed) = timed(setup.get_authed("missing.txt")).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(elapsed >= std::time::Duration::from_millis(200), "{elapsed:?}");
+
+    // Allowed requests are not delayed.
+    let (status, elapsed) = timed(setup.get_authed("hello.txt")).await?;
+    assert_eq!(status, StatusCode::OK);
+    assert!(elapsed < std::time::Duration::from_millis(200), "{elapsed:?}");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn blocklist() -> Result<()> {
+    let blocked_event = setup(r#"auth.blocked_events = ["abc123"]"#).await?;
+    let resp = send(blocked_event.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = send(blocked_event.request_full(
+        reqwest::Method::GET,
+        "/static/org/channel/abc%3123/hello.txt",
+    ).bearer_auth(JWT_ADMIN)).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let blocked_path = setup(r#"auth.blocked_path_patterns = ["*/hello.*"]"#).await?;
+    let resp = send(blocked_path.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = send(blocked_path.get_authed("hello%2Etxt")).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = send(blocked_path.get_authed("hls/segment0.ts")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_keys() -> Result<()> {
+    let setup = setup("http.admin_endpoints = true").await?;
+    // Keys are fetched in the background, a successful verification makes
+    // sure they are loaded.
+    let resp = send(setup.get_authed("hello.txt")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = send(setup.request_full(reqwest::Method::GET, "/-/keys")).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header(&resp, "content-type"), Some("application/json"));
+
+    let body: serde_json::Value = serde_json::from_str(&resp.text().await?)?;
+    let keys = body["keys"].as_array().unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0]["kid"], serde_json::Value::Null);
+    assert_eq!(keys[0]["kty"], "OKP");
+    assert_eq!(keys[0]["alg"], "EdDSA");
+    assert_eq!(keys[0]["crv"], "Ed25519");
+    assert_eq!(keys[0]["source"], "http://127.0.0.1:4055/ed25519.json");
+    assert!(keys[0]["fetched_at"].as_str().unwrap() < keys[0]["expires_at"].as_str().unwrap());
+    assert!(keys[0].get("x").is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_verification_stats() -> Result<()> {
+    let setup = setup("http.admin_endpoints = true").await?;
+    let stats = || async {
+        let resp = send(setup.request_full(reqwest::Method::GET, "/-/verification-stats")).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        Ok::<_, anyhow::Error>(serde_json::from_str::<serde_json::Value>(&resp.text().await?)?)
+    };
+    assert_eq!(stats().await?["algorithms"], serde_json::json!({}));
+
+    send(setup.get_authed("hello.txt")).await?;
+    send(setup.get_authed("hello.txt")).await?;
+    send(setup.get("hello.txt").bearer_auth(JWT_USER)).await?;
+    send(setup.get("hello.txt").bearer_auth("not-a-jwt")).await?;
+
+    let body = stats().await?;
+    let algorithms = body["algorithms"].as_object().unwrap();
+    assert_eq!(algorithms.keys().collect::<Vec<_>>(), ["EdDSA"]);
+    let eddsa = &algorithms["EdDSA"];
+    assert_eq!(eddsa["count"], 3);
+    assert_eq!(eddsa["failures"], 0);
+    assert!(eddsa["total_ms"].as_f64().unwrap() > 0.0);
+    let buckets = eddsa["latency_buckets_us"].as_object().unwrap();
+    assert_eq!(buckets.len(), 7);
+    assert!(buckets.contains_key("+Inf"));
+    assert_eq!(buckets.values().map(|v| v.as_u64().unwrap()).sum::<u64>(), 3);
+
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
 use anyhow::Result;
 use confique::Config as _;
@@ -6,6 +6,9 @@ use http::StatusCode;
 use octoka::config::Config;
 use reqwest::Response;
 
+use self::common::JwksServer;
+
+mod common;
 
 // ==============================================================================================
 // ===== Test setup stuff and utilities
@@ -42,6 +45,11 @@ impl TestSetup {
 }
 
 async fn setup(trusted_keys: &[&str]) -> Result<TestSetup> {
+    setup_with_config(trusted_keys, "").await
+}
+
+/// Like `setup`, but with additional config (TOML) that has priority.
+async fn setup_with_config(trusted_keys: &[&str], extra_config: &str) -> Result<TestSetup> {
     // Load trusted keys
     let keys = trusted_keys.iter().flat_map(|key_file| {
         let path = format!("{}/tests/jwks/{key_file}", env!("CARGO_MANIFEST_DIR"));
@@ -53,19 +61,27 @@ async fn setup(trusted_keys: &[&str]) -> Result<TestSetup> {
             .collect::<Vec<_>>()
     }).collect::<Vec<_>>();
 
-    let trusted_keys = trusted_keys.iter()
-        .map(|file| format!("\"http://127.0.0.1:4055/{file}\""))
-        .collect::<Vec<_>>()
-        .join(", ");
+    // `jwt.trusted_keys` must not be empty, so tests passing no key files
+    // set it in `extra_config`.
+    let trusted_keys = match trusted_keys {
+        [] => String::new(),
+        files => {
+            let urls = files.iter()
+                .map(|file| format!("\"http://127.0.0.1:4055/{file}\""))
+                .collect::<Vec<_>>();
+            format!("jwt.trusted_keys = [{}]", urls.join(", "))
+        }
+    };
     let config = format!(r#"
         opencast.fallback = "none"
-        jwt.trusted_keys = [{trusted_keys}]
+        {trusted_keys}
         http.on_allow = "empty"
         http.port = 0
         log.filters.octoka = "trace"
     "#);
 
     let config = Config::builder()
+        .preloaded(toml::from_str(extra_config)?)
         .preloaded(toml::from_str(&config)?)
         .load()?;
     let _ = octoka::log::init(&config.log, true);
@@ -235,6 +251,27 @@ async fn ps256() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn allowed_algorithms() -> Result<()> {
+    let eddsa_jwt = format!("{HEADER_EDDSA}.{PAYLOAD_ADMIN}.\
+        6Bs6wdvBdWbszV38Lj81OmtW5ibutzUfTc8_X6k3yiwOHNQm5xQrWiILXGRP7eHFiI4Ju1FHu_NxufSUPiELAw");
+    let es256_jwt = format!("{HEADER_ES256}.{PAYLOAD_ADMIN}.\
+        t12mLMa67e_XagL0SnLC87sT853ksnQ1UkWIaIlZCl1gYlvyDvvH5UL1IA1TZ2S0XzISUGMeCIqAexhx0-gm5w");
+
+    let setup = setup(&["ed25519.json", "es256.json"]).await?;
+    assert_status!(setup.fetch(&eddsa_jwt), StatusCode::NO_CONTENT);
+    assert_status!(setup.fetch(&es256_jwt), StatusCode::NO_CONTENT);
+
+    let restricted = setup_with_config(
+        &["ed25519.json", "es256.json"],
+        r#"jwt.allowed_algorithms = ["EdDSA"]"#,
+    ).await?;
+    assert_status!(restricted.fetch(&eddsa_jwt), StatusCode::NO_CONTENT);
+    assert_jwt_ok_but_forbidden!(restricted, &es256_jwt);
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn alg_none() -> Result<()> {
     let setup = setup(&["ed25519.json"]).await?;
@@ -321,6 +358,205 @@ async fn oc_claims() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn kid_source_hints() -> Result<()> {
+    let jwks = JwksServer::start();
+    let jwks_host = &jwks.url;
+    // `fail_on_empty_jwks` makes the setup wait for the initial fetch.
+    let setup = setup_with_config(&[], &format!(r#"
+        jwt.trusted_keys = ["{jwks_host}/ed25519.json", "{jwks_host}/es256.json"]
+        jwt.kid_source_hints = {{ "tobira-" = "{jwks_host}/ed25519.json" }}
+        jwt.fail_on_empty_jwks = true
+    "#)).await?;
+    assert_eq!(jwks.count("/ed25519.json"), 1);
+    assert_eq!(jwks.count("/es256.json"), 1);
+
+    // Header: { "alg": "EdDSA", "typ": "JWT", "kid": "tobira-1" }, invalid
+    // signature, which triggers a backup refresh.
+    let jwt = format!("eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCIsImtpZCI6InRvYmlyYS0xIn0.{PAYLOAD_ADMIN}.\
+        6Bs6wdvBdWbszV38Lj81OmtW5ibutzUfTc8_X6k3yiwOHNQm5xQrWiILXGRP7eHFiI4Ju1FHu_NxufSUPiELAw");
+    assert_status!(setup.fetch(&jwt), StatusCode::FORBIDDEN);
+    assert_eq!(jwks.count("/ed25519.json"), 2);
+    assert_eq!(jwks.count("/es256.json"), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn per_source_key_cache_duration() -> Result<()> {
+    let jwks = JwksServer::start();
+    let jwks_host = &jwks.url;
+    // `fail_on_empty_jwks` makes the setup wait for the initial fetch.
+    let setup = setup_with_config(&[], &format!(r#"
+        jwt.background_key_refresh = false
+        jwt.fail_on_empty_jwks = true
+        jwt.trusted_keys = [
+            {{ url = "{jwks_host}/ed25519.json", key_cache_duration = "1s" }},
+            "{jwks_host}/es256.json",
+        ]
+    "#)).await?;
+
+    let eddsa_jwt = format!("{HEADER_EDDSA}.{PAYLOAD_ADMIN}.\
+        6Bs6wdvBdWbszV38Lj81OmtW5ibutzUfTc8_X6k3yiwOHNQm5xQrWiILXGRP7eHFiI4Ju1FHu_NxufSUPiELAw");
+    let es256_jwt = format!("{HEADER_ES256}.{PAYLOAD_ADMIN}.\
+        t12mLMa67e_XagL0SnLC87sT853ksnQ1UkWIaIlZCl1gYlvyDvvH5UL1IA1TZ2S0XzISUGMeCIqAexhx0-gm5w");
+    assert_status!(setup.fetch(&eddsa_jwt), StatusCode::NO_CONTENT);
+    assert_status!(setup.fetch(&es256_jwt), StatusCode::NO_CONTENT);
+    assert_eq!(jwks.count("/ed25519.json"), 1);
+    assert_eq!(jwks.count("/es256.json"), 1);
+
+    // Only the source with the short duration is stale now.
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_status!(setup.fetch(&eddsa_jwt), StatusCode::NO_CONTENT);
+    assert_status!(setup.fetch(&es256_jwt), StatusCode::NO_CONTENT);
+    assert_eq!(jwks.count("/ed25519.json"), 2);
+    assert_eq!(jwks.count("/es256.json"), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_concurrent_fetches() -> Result<()> {
+    const SOURCES: usize = 12;
+
+    // Different URLs for the same file, each being a separate source.
+    let jwks = JwksServer::with_delay(Duration::from_millis(100));
+    let jwks_host = &jwks.url;
+    let trusted_keys = (0..SOURCES)
+        .map(|i| format!("\"{jwks_host}/ed25519.json?{i}\""))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let _setup = setup_with_config(&[], &format!(r#"
+        jwt.trusted_keys = [{trusted_keys}]
+        jwt.max_concurrent_fetches = 3
+    "#)).await?;
+
+    // Wait for the initial fetch of all sources.
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while jwks.done() < SOURCES {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }).await?;
+    assert_eq!(jwks.count("/ed25519.json"), SOURCES);
+    assert_eq!(jwks.max_in_flight(), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn key_ops() -> Result<()> {
+    let jwks = JwksServer::start();
+    let jwks_host = &jwks.url;
+    let jwt = format!("{HEADER_EDDSA}.{PAYLOAD_ADMIN}.\
+        6Bs6wdvBdWbszV38Lj81OmtW5ibutzUfTc8_X6k3yiwOHNQm5xQrWiILXGRP7eHFiI4Ju1FHu_NxufSUPiELAw");
+
+    let verify = setup_with_config(&[], &format!(r#"
+        jwt.trusted_keys = ["{jwks_host}/ed25519-key-ops-verify.json"]
+    "#)).await?;
+    assert_status!(verify.fetch(&jwt), StatusCode::NO_CONTENT);
+
+    let encrypt = setup_with_config(&[], &format!(r#"
+        jwt.trusted_keys = ["{jwks_host}/ed25519-key-ops-encrypt.json"]
+    "#)).await?;
+    assert_status!(encrypt.fetch(&jwt), StatusCode::FORBIDDEN);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn key_expiry() -> Result<()> {
+    let jwks = JwksServer::start();
+    let jwks_host = &jwks.url;
+    let jwt = format!("{HEADER_EDDSA}.{PAYLOAD_ADMIN}.\
+        6Bs6wdvBdWbszV38Lj81OmtW5ibutzUfTc8_X6k3yiwOHNQm5xQrWiILXGRP7eHFiI4Ju1FHu_NxufSUPiELAw");
+
+    let future = setup_with_config(&[], &format!(r#"
+        jwt.trusted_keys = ["{jwks_host}/ed25519-exp-future.json"]
+    "#)).await?;
+    assert_status!(future.fetch(&jwt), StatusCode::NO_CONTENT);
+
+    let past = setup_with_config(&[], &format!(r#"
+        jwt.trusted_keys = ["{jwks_host}/ed25519-exp-past.json"]
+    "#)).await?;
+    assert_status!(past.fetch(&jwt), StatusCode::FORBIDDEN);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn duplicate_kid_across_algorithms() -> Result<()> {
+    let jwks = JwksServer::start();
+    let jwks_host = &jwks.url;
+    let setup = setup_with_config(&[], &format!(r#"
+        jwt.trusted_keys = ["{jwks_host}/duplicate-kid.json"]
+    "#)).await?;
+
+    // Header: { "alg": "EdDSA", "typ": "JWT", "kid": "rotating" }
+    let jwt = format!("eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCIsImtpZCI6InJvdGF0aW5nIn0.{PAYLOAD_ADMIN}.\
+        _uoS4jKAjMxRjMuVEJvzhHKtIJuwkZT_OR2BOFEMGofiiROWO6ynYQUvnVyR3AyYu1xMiwjQqO6mYxiuCZGPDQ");
+    assert_status!(setup.fetch(&jwt), StatusCode::NO_CONTENT);
+
+    // Header: { "alg": "ES256", "typ": "JWT", "kid": "rotating" }
+    let jwt = format!("eyJhbGciOiJFUzI1NiIsInR5cCI6IkpXVCIsImtpZCI6InJvdGF0aW5nIn0.{PAYLOAD_ADMIN}.\
+        F0wsja8YfkKEqxaE2MLQkTHut6ZmOlCEtHuymYWtdUvtr_qdquoT0XE6LDqpW_zbITHw_PYUS4iLWB2eF-aGCA");
+    assert_status!(setup.fetch(&jwt), StatusCode::NO_CONTENT);
+
+    // Header: { "alg": "RS256", "typ": "JWT", "kid": "rotating" }, no key
+    // with that `kid` supports RS256.
+    let jwt = format!("eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCIsImtpZCI6InJvdGF0aW5nIn0.{PAYLOAD_ADMIN}.\
+        _uoS4jKAjMxRjMuVEJvzhHKtIJuwkZT_OR2BOFEMGofiiROWO6ynYQUvnVyR3AyYu1xMiwjQqO6mYxiuCZGPDQ");
+    assert_status!(setup.fetch(&jwt), StatusCode::FORBIDDEN);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn fail_on_empty_jwks() -> Result<()> {
+    let jwks = JwksServer::start();
+    let jwks_host = &jwks.url;
+    let config = |fail: bool| format!(r#"
+        jwt.trusted_keys = ["{jwks_host}/ed25519.json", "{jwks_host}/empty.json"]
+        jwt.fail_on_empty_jwks = {fail}
+    "#);
+
+    let lenient = setup_with_config(&[], &config(false)).await?;
+    let jwt = format!("{HEADER_EDDSA}.{PAYLOAD_ADMIN}.\
+        6Bs6wdvBdWbszV38Lj81OmtW5ibutzUfTc8_X6k3yiwOHNQm5xQrWiILXGRP7eHFiI4Ju1FHu_NxufSUPiELAw");
+    assert_status!(lenient.fetch(&jwt), StatusCode::NO_CONTENT);
+
+    let err = setup_with_config(&[], &config(true)).await.err().expect("startup should fail");
+    assert!(format!("{err:#}").contains("/empty.json"), "{err:#}");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn removed_key_grace() -> Result<()> {
+    let jwt = format!("{HEADER_EDDSA}.{PAYLOAD_ADMIN}.\
+        6Bs6wdvBdWbszV38Lj81OmtW5ibutzUfTc8_X6k3yiwOHNQm5xQrWiILXGRP7eHFiI4Ju1FHu_NxufSUPiELAw");
+
+    for (grace, expected) in [("1min", StatusCode::NO_CONTENT), ("0s", StatusCode::FORBIDDEN)] {
+        let jwks = JwksServer::start();
+        jwks.serve_for_all("ed25519.json");
+        let jwks_host = &jwks.url;
+        let setup = setup_with_config(&[], &format!(r#"
+            jwt.trusted_keys = ["{jwks_host}/jwks.json"]
+            jwt.background_key_refresh = false
+            jwt.key_cache_duration = "1s"
+            jwt.removed_key_grace = "{grace}"
+        "#)).await?;
+        assert_status!(setup.fetch(&jwt), StatusCode::NO_CONTENT);
+
+        // Rotate the key out and wait until the source is stale, so that the
+        // next request refetches it.
+        jwks.serve_for_all("es256.json");
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_status!(setup.fetch(&jwt), expected);
+    }
+
+    Ok(())
+}
+
 // TODO:
 // - kid: key has it, jwt has it, combinations
 // - incorrect alg in JWK
@@ -0,0 +1,65 @@
+//! This test installs a global log subscriber, which is why it lives in its
+//! own test binary.
+
+use std::{io, sync::{Arc, Mutex}};
+
+use anyhow::Result;
+use confique::Config as _;
+use http::StatusCode;
+use octoka::config::Config;
+
+
+/// Writer appending all log output to a shared buffer.
+#[derive(Clone)]
+struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn connection_and_request_ids_in_spans() -> Result<()> {
+    let capture = Capture(Arc::new(Mutex::new(Vec::new())));
+    let writer = capture.clone();
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .init();
+
+    let config = format!(r#"
+        opencast.fallback = "none"
+        opencast.downloads_path = "{}/tests/downloads"
+        jwt.trusted_keys = ["http://127.0.0.1:4055/ed25519.json"]
+        http.port = 0
+    "#, env!("CARGO_MANIFEST_DIR"));
+    let config = Config::builder().preloaded(toml::from_str(&config)?).load()?;
+    let (addr, server) = octoka::test_http_server(config).await?;
+    tokio::spawn(server);
+
+    // Two requests over the same connection
+    let client = reqwest::Client::new();
+    for _ in 0..2 {
+        let resp = client.get(format!("http://{addr}/static/org/channel/abc123/hello.txt"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    let logs = String::from_utf8(capture.0.lock().unwrap().clone())?;
+    let incoming = logs.lines()
+        .filter(|line| line.contains("incoming req"))
+        .collect::<Vec<_>>();
+    assert_eq!(incoming.len(), 2, "logs: {logs}");
+    assert!(incoming[0].contains("conn{id=1}:req{id=1}"), "line: {}", incoming[0]);
+    assert!(incoming[1].contains("conn{id=1}:req{id=2}"), "line: {}", incoming[1]);
+
+    Ok(())
+}
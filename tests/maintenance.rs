@@ -0,0 +1,64 @@
+//! Maintenance mode is toggled via `SIGUSR2`, which affects the whole process.
+//! That's why this test lives in its own test binary.
+#![cfg(target_family = "unix")]
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use confique::Config as _;
+use http::StatusCode;
+use octoka::config::Config;
+
+
+/// JWT signed with `ed25519.json`, payload: `{ "exp": 4012345678, "roles": ["ROLE_ADMIN"] }`
+const JWT_ADMIN: &str = "eyJhbGciOiJFZERTQSIsInR5cCI6IkpXVCJ9.\
+    eyJleHAiOjQwMTIzNDU2NzgsInJvbGVzIjpbIlJPTEVfQURNSU4iXX0.\
+    6Bs6wdvBdWbszV38Lj81OmtW5ibutzUfTc8_X6k3yiwOHNQm5xQrWiILXGRP7eHFiI4Ju1FHu_NxufSUPiELAw";
+
+#[tokio::test]
+async fn maintenance_mode_toggle() -> Result<()> {
+    let downloads = format!("{}/tests/downloads", env!("CARGO_MANIFEST_DIR"));
+    let config = format!(r#"
+        opencast.fallback = "none"
+        opencast.downloads_path = "{downloads}"
+        jwt.trusted_keys = ["http://127.0.0.1:4055/ed25519.json"]
+        http.port = 0
+        http.maintenance_mode = true
+        http.unmatched_files = {{ "/robots.txt" = "{}/tests/static-files/robots.txt" }}
+    "#, env!("CARGO_MANIFEST_DIR"));
+    let config = Config::builder().preloaded(toml::from_str(&config)?).load()?;
+    let ctx = octoka::http::Context::new(config).await?;
+    octoka::http::listen_for_maintenance_toggle(Arc::clone(&ctx.maintenance))?;
+    let listeners = octoka::http::bind_all(&ctx.config.http)?;
+    let addr = listeners[0].local_addr()?;
+    tokio::spawn(octoka::http::serve_on(ctx, listeners, std::future::pending()));
+
+    let client = reqwest::Client::new();
+    let get = |path: &str| client.get(format!("http://{addr}{path}")).bearer_auth(JWT_ADMIN);
+    let file = "/static/org/channel/abc123/hello.txt";
+
+    let resp = get(file).send().await?;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(resp.headers().contains_key("retry-after"));
+    let resp = get("/robots.txt").send().await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let toggle = || async {
+        let status = std::process::Command::new("kill")
+            .args(["-USR2", &std::process::id().to_string()])
+            .status()?;
+        assert!(status.success());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        anyhow::Ok(())
+    };
+
+    toggle().await?;
+    let resp = get(file).send().await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    toggle().await?;
+    let resp = get(file).send().await?;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    Ok(())
+}